@@ -1,81 +1,290 @@
 use crate::{Error, FBErrorKind, LogicalNr, PhysicalNr};
 use std::collections::BTreeMap;
 use std::fmt::Debug;
-use std::fs::File;
 use std::io;
-use std::io::{Read, Write};
+use std::io::{Cursor, Read, Write};
+use std::mem;
+use std::mem::size_of;
 
 mod block;
 pub(crate) mod block_io;
 mod blocktype;
 mod header;
 pub(crate) mod physical;
+mod storage;
 mod stream;
 pub(crate) mod types;
 
+use block_io::{WriteBuffer, DEFAULT_WRITE_BUFFER};
+use header::HEADER_LEN;
 use physical::Physical;
 use types::Types;
 
-pub use block::{alloc_box_buffer, Block, HeaderArray, HeaderArrayMut, UserBlock};
+pub use block::{alloc_box_buffer, Block, BlockMut, HeaderArray, HeaderArrayMut, UserBlock};
 pub use blocktype::BlockType;
 pub use header::{HeaderBlock, State};
 pub use physical::PhysicalBlock;
+pub use storage::BlockStorage;
+#[cfg(feature = "mmap")]
+pub use storage::MmapStorage;
 pub use stream::{StreamsBlock, UserStreamsBlock};
 pub use types::{TypesBlock, UserTypesBlock};
 
+/// Minimum number of entries in a type-map or physical-map block.
+const MIN_MAP_LEN: usize = 4;
+
 pub const _INIT_HEADER_NR: LogicalNr = LogicalNr(0);
 pub const _INIT_TYPES_NR: LogicalNr = LogicalNr(1);
 pub const _INIT_PHYSICAL_NR: LogicalNr = LogicalNr(2);
 pub const _INIT_STREAM_NR: LogicalNr = LogicalNr(3);
 
+/// Generation of one of the internal map blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MapGeneration {
+    /// Block-nr of the map block.
+    pub block_nr: LogicalNr,
+    /// Generation of the last store of this block.
+    pub generation: u32,
+    /// Was written with the last store.
+    pub current: bool,
+}
+
+/// Generations of all the internal map blocks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapGenerations {
+    /// Current store generation.
+    pub generation: u32,
+    /// Type-map blocks.
+    pub types: Vec<MapGeneration>,
+    /// Physical-map blocks.
+    pub physical: Vec<MapGeneration>,
+}
+
+/// Position of the end of a stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamPosition {
+    /// Block-type of the stream.
+    pub block_type: BlockType,
+    /// Tail block. 0 if the stream has no blocks.
+    pub block_nr: LogicalNr,
+    /// Head-idx in the tail block.
+    pub head_idx: usize,
+}
+
+/// Block counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockStats {
+    /// Allocated user blocks. Internal blocks are not counted.
+    pub allocated: usize,
+    /// Free block-nrs in the type map.
+    pub free: usize,
+    /// Blocks in the file as of the last store.
+    pub physical_file_blocks: usize,
+    /// Unused physical blocks within the file.
+    pub wasted: usize,
+}
+
 /// Manages allocations and block-buffers.
 #[derive(Debug)]
 pub struct Alloc {
-    file: File,
+    file: Box<dyn BlockStorage>,
     block_size: usize,
+    // block_size without the checksum trailer.
+    data_size: usize,
+    checksum: bool,
 
     header: HeaderBlock,
     types: Types,
     physical: Physical,
     streams: StreamsBlock,
 
+    // coalesces writes of consecutive physical blocks.
+    write_buffer: WriteBuffer,
+    // physical blocks reserved for the next store.
+    reserved: BTreeMap<LogicalNr, PhysicalNr>,
+
     // block cache
     user: BTreeMap<LogicalNr, Block>,
+    // max cached user blocks, and the last use for each of them.
+    // cache_order is the same as cache_lru, ordered by the last use.
+    cache_limit: Option<usize>,
+    cache_tick: u64,
+    cache_lru: BTreeMap<LogicalNr, u64>,
+    cache_order: BTreeMap<u64, LogicalNr>,
+    // never stored
+    scratch: Vec<Block>,
 
     generation: u32,
+    // sequence counter, stored with the next header state.
+    sequence: u64,
+    // prepare() has run, commit() is next.
+    prepared: bool,
     #[cfg(debug_assertions)]
     store_panic: u32,
+    #[cfg(debug_assertions)]
+    store_hook: Option<fn(u32)>,
 }
 
 impl Alloc {
+    /// Checks that the block-size can hold the header and at least 4 entries
+    /// in each type-map and physical-map block.
+    ///
+    /// Fails with InvalidBlockSize.
+    pub fn check_block_size(block_size: usize, checksum: bool) -> Result<(), Error> {
+        if block_size >= HEADER_LEN && block_size <= u32::MAX as usize {
+            let data_size = block_io::data_size(block_size, checksum);
+            if TypesBlock::len_types_g(data_size) >= MIN_MAP_LEN
+                && PhysicalBlock::len_physical_g(data_size) >= MIN_MAP_LEN
+            {
+                return Ok(());
+            }
+        }
+        Err(Error::err(FBErrorKind::InvalidBlockSize(block_size)))
+    }
+
     /// Init a new Allocator.
-    pub fn init(file: File, block_size: usize) -> Self {
-        let header = HeaderBlock::init(block_size);
-        let types = Types::init(block_size);
-        let physical = Physical::init(block_size);
-        let streams = StreamsBlock::init(block_size);
+    ///
+    /// Panics if the block-size fails check_block_size().
+    pub fn init<S: BlockStorage + 'static>(file: S, block_size: usize) -> Self {
+        Self::init_opt(Box::new(file), block_size, false)
+    }
 
-        let s = Self {
+    /// Init a new Allocator. Every block except the header is written with a
+    /// crc32 checksum trailer, so the usable block data is 4 bytes smaller.
+    pub fn init_with_checksums<S: BlockStorage + 'static>(file: S, block_size: usize) -> Self {
+        Self::init_opt(Box::new(file), block_size, true)
+    }
+
+    /// Init a new Allocator with the given starting state of the header.
+    /// The first store writes the other state and switches to it.
+    /// The default is High.
+    pub fn init_with_state<S: BlockStorage + 'static>(
+        file: S,
+        block_size: usize,
+        state: State,
+    ) -> Self {
+        let mut s = Self::init_opt(Box::new(file), block_size, false);
+        s.header.set_state(state);
+        s
+    }
+
+    fn init_opt(file: Box<dyn BlockStorage>, block_size: usize, checksum: bool) -> Self {
+        Self::check_block_size(block_size, checksum).expect("block-size");
+        let data_size = block_io::data_size(block_size, checksum);
+
+        let mut header = HeaderBlock::init(block_size);
+        header.set_checksummed(checksum);
+        let types = Types::init(data_size);
+        let physical = Physical::init(data_size, checksum);
+        let streams = StreamsBlock::init(data_size);
+
+        let mut s = Self {
             file,
             block_size,
+            data_size,
+            checksum,
             header,
             types,
             physical,
             streams,
+            write_buffer: WriteBuffer::new(DEFAULT_WRITE_BUFFER),
+            reserved: Default::default(),
             user: Default::default(),
+            cache_limit: None,
+            cache_tick: 0,
+            cache_lru: Default::default(),
+            cache_order: Default::default(),
+            scratch: Default::default(),
             generation: 0,
+            sequence: 0,
+            prepared: false,
             #[cfg(debug_assertions)]
             store_panic: 0,
+            #[cfg(debug_assertions)]
+            store_hook: None,
         };
-        s.verify(block_size).expect("init-ok");
+        s.append_physical_blockmap().expect("init-ok");
+        s.verify().expect("init-ok");
 
         s
     }
 
-    /// Load from file.
-    pub fn load(mut file: File, block_size: usize) -> Result<Self, Error> {
+    /// Truncates the storage and starts over with an empty one, as if
+    /// it was just created with the same block-size and checksum setting.
+    /// The empty state is stored immediately.
+    ///
+    /// The user meta-data, the sequence counter and the settings for
+    /// the write-buffer, the cache-limit and the debug hooks are kept.
+    pub fn reset(&mut self) -> Result<(), Error> {
+        let file = mem::replace(&mut self.file, Box::new(Cursor::new(Vec::new())));
+        let old = mem::replace(self, Self::init_opt(file, self.block_size, self.checksum));
+        self.write_buffer = old.write_buffer;
+        self.write_buffer.clear();
+        self.cache_limit = old.cache_limit;
+        self.sequence = old.sequence;
+        self.header.set_sequence(old.sequence);
+        self.header.set_user_meta(old.header.user_meta())?;
+        #[cfg(debug_assertions)]
+        {
+            self.store_panic = old.store_panic;
+            self.store_hook = old.store_hook;
+            self.physical
+                .set_check_double_assign(old.physical.is_check_double_assign());
+        }
+
+        block_io::set_len(&mut *self.file, 0)?;
+        self.set_open_marker(old.header.is_open())?;
+        self.store()
+    }
+
+    /// Load from storage.
+    pub fn load<S: BlockStorage + 'static>(file: S, block_size: usize) -> Result<Self, Error> {
+        let mut file: Box<dyn BlockStorage> = Box::new(file);
+        let mut s = Self::load_from(&mut *file, block_size)?;
+        s.file = file;
+        Ok(s)
+    }
+
+    /// Discards all state and loads it again from the storage. Use this
+    /// if the file was changed by someone else.
+    ///
+    /// Fails with UnsavedChanges if there is anything not stored yet.
+    pub fn reload(&mut self) -> Result<(), Error> {
+        if self.prepared || self.is_dirty() {
+            return Err(Error::err(FBErrorKind::UnsavedChanges));
+        }
+        self.reload_force()
+    }
+
+    /// Discards all state and loads it again from the storage.
+    /// Unsaved changes are lost.
+    pub fn reload_force(&mut self) -> Result<(), Error> {
+        let mut s = Self::load_from(&mut *self.file, self.block_size)?;
+        mem::swap(&mut s.file, &mut self.file);
+        mem::swap(&mut s.write_buffer, &mut self.write_buffer);
+        s.cache_limit = self.cache_limit;
+        s.generation = self.generation;
+        #[cfg(debug_assertions)]
+        {
+            s.store_panic = self.store_panic;
+            s.store_hook = self.store_hook;
+        }
+        *self = s;
+        Ok(())
+    }
+
+    /// Loads the state from storage. The result has an empty placeholder
+    /// instead of the storage.
+    fn load_from(file: &mut dyn BlockStorage, block_size: usize) -> Result<Self, Error> {
+        Self::check_block_size(block_size, false)?;
+
         let mut header = HeaderBlock::new(block_size);
-        block_io::load_raw_0(&mut file, &mut header.0)?;
+        block_io::load_raw_0(&mut *file, &mut header.0)?;
+        header.verify_magic()?;
+
+        let checksum = header.is_checksummed();
+        Self::check_block_size(block_size, checksum)?;
+        let data_size = block_io::data_size(block_size, checksum);
 
         // load physical map
         let physical_pnr = match header.state() {
@@ -85,7 +294,7 @@ impl Alloc {
         if physical_pnr == 0 {
             return Err(Error::err(FBErrorKind::HeaderCorrupted));
         }
-        let physical = Physical::load(&mut file, block_size, physical_pnr)?;
+        let physical = Physical::load(&mut *file, data_size, checksum, physical_pnr)?;
 
         // load type map
         let types_pnr = match header.state() {
@@ -95,7 +304,7 @@ impl Alloc {
         if types_pnr == 0 {
             return Err(Error::err(FBErrorKind::HeaderCorrupted));
         }
-        let types = Types::load(&mut file, &physical, block_size, types_pnr)?;
+        let types = Types::load(&mut *file, &physical, data_size, checksum, types_pnr)?;
 
         // load streams
         let streams_pnr = match header.state() {
@@ -103,27 +312,46 @@ impl Alloc {
             State::High => header.high_streams(),
         };
         let streams = if streams_pnr != 0 {
-            let mut streams = StreamsBlock::new(block_size);
-            block_io::load_raw(&mut file, streams_pnr, &mut streams.0)?;
-            streams
+            StreamsBlock::load(
+                &mut *file,
+                &physical,
+                &types,
+                data_size,
+                checksum,
+                streams_pnr,
+            )?
         } else {
-            StreamsBlock::init(block_size)
+            StreamsBlock::init(data_size)
         };
 
+        let sequence = header.sequence();
         let s = Self {
-            file,
+            file: Box::new(Cursor::new(Vec::new())),
             block_size,
+            data_size,
+            checksum,
             header,
             types,
             physical,
             streams,
+            write_buffer: WriteBuffer::new(DEFAULT_WRITE_BUFFER),
+            reserved: Default::default(),
             user: Default::default(),
+            cache_limit: None,
+            cache_tick: 0,
+            cache_lru: Default::default(),
+            cache_order: Default::default(),
+            scratch: Default::default(),
             generation: 0,
+            sequence,
+            prepared: false,
             #[cfg(debug_assertions)]
             store_panic: 0,
+            #[cfg(debug_assertions)]
+            store_hook: None,
         };
 
-        s.verify(block_size)?;
+        s.verify()?;
 
         Ok(s)
     }
@@ -135,52 +363,207 @@ impl Alloc {
         self.store_panic = step;
     }
 
+    /// For testing only. Disarms the panic set with set_store_panic.
+    #[cfg(debug_assertions)]
+    pub fn clear_store_panic(&mut self) {
+        self.store_panic = 0;
+    }
+
+    /// For testing only. The step set with set_store_panic. 0 if disarmed.
+    #[cfg(debug_assertions)]
+    pub fn store_panic_step(&self) -> u32 {
+        self.store_panic
+    }
+
+    /// For testing only. Calls the hook with each step of store(), with the
+    /// numbering of set_store_panic. The hook runs before the panic.
+    #[cfg(debug_assertions)]
+    pub fn set_store_hook(&mut self, hook: Option<fn(u32)>) {
+        self.store_hook = hook;
+    }
+
+    /// Which state is recovered if a store is interrupted at this step of
+    /// store(). Steps are numbered as with set_store_panic. Up to step 7 the
+    /// header still points to the previous store, after that to the new one.
+    pub fn step_recovers_new_generation(step: u32) -> bool {
+        step > 7
+    }
+
+    #[cfg(debug_assertions)]
+    fn store_step(&self, step: u32) {
+        if let Some(hook) = self.store_hook {
+            hook(step);
+        }
+        if self.store_panic == step {
+            panic!("invoke store_panic {}", step);
+        }
+    }
+
+    /// For testing only. Switches the check for double assigned physical blocks
+    /// that runs with every block written. This check scans the whole physical map
+    /// each time, which gets slow for big stores. Default is on.
+    #[cfg(debug_assertions)]
+    pub fn set_check_double_assign(&mut self, check: bool) {
+        self.physical.set_check_double_assign(check);
+    }
+
+    /// For testing only. Sets the physical block of a block-nr without
+    /// any checks. Allows to simulate a broken map.
+    #[cfg(debug_assertions)]
+    pub fn set_physical_nr_unchecked(
+        &mut self,
+        block_nr: LogicalNr,
+        block_pnr: PhysicalNr,
+    ) -> Result<(), Error> {
+        self.physical.set_physical_nr_unchecked(block_nr, block_pnr)
+    }
+
+    /// Checks the physical map for physical blocks assigned to more than one
+    /// block-nr. This is the same check as with load.
+    pub fn verify_no_double_mapping(&self) -> Result<(), Error> {
+        self.physical.verify()
+    }
+
+    /// Checks that both header states reference different root blocks,
+    /// otherwise a rollback would use the new blocks. Only roots that were
+    /// rewritten by a store in this session can be checked, and only after
+    /// the file has seen at least two stores.
+    pub fn verify_header_states(&self) -> Result<(), Error> {
+        if self.generation == 0 || self.header.total_stores() < 2 {
+            return Ok(());
+        }
+
+        let types = self.types.iter().find(|v| v.block_nr() == _INIT_TYPES_NR);
+        let physical = self
+            .physical
+            .iter()
+            .find(|v| v.block_nr() == _INIT_PHYSICAL_NR);
+        let roots = [
+            (
+                _INIT_TYPES_NR,
+                types.map(|v| v.generation()),
+                self.header.low_types(),
+                self.header.high_types(),
+            ),
+            (
+                _INIT_PHYSICAL_NR,
+                physical.map(|v| v.generation()),
+                self.header.low_physical(),
+                self.header.high_physical(),
+            ),
+            (
+                _INIT_STREAM_NR,
+                Some(self.streams.root().generation()),
+                self.header.low_streams(),
+                self.header.high_streams(),
+            ),
+        ];
+        for (block_nr, generation, low, high) in roots {
+            if generation == Some(self.generation) && low == high {
+                return Err(Error::err(FBErrorKind::SharedHeaderState(block_nr, low)));
+            }
+        }
+        Ok(())
+    }
+
     /// Store to file.
     ///
     pub fn store(&mut self) -> Result<(), Error> {
-        self.generation += 1;
+        self.prepare()?;
+        self.commit()
+    }
+
+    /// Store to file without syncing.
+    ///
+    /// The writes may still be reordered by the os, so this is not
+    /// crash-safe until the next sync(). A crash before that can leave
+    /// a broken file.
+    pub fn store_no_sync(&mut self) -> Result<(), Error> {
+        self.prepare_opt(false)?;
+        self.commit_opt(false)
+    }
+
+    /// Sync the storage. Makes any store_no_sync() durable.
+    pub fn sync(&mut self) -> Result<(), Error> {
+        block_io::sync(&mut *self.file)
+    }
+
+    /// First half of store(). Writes all dirty blocks and the maps and
+    /// records them as the inactive state of the header, but doesn't flip
+    /// the state. Until commit() the file still contains the previous store.
+    pub fn prepare(&mut self) -> Result<(), Error> {
+        self.prepare_opt(true)
+    }
 
-        // is a new file?
-        if block_io::metadata(&mut self.file)?.len() == 0 {
-            // Write default header.
-            let default = HeaderBlock::init(self.block_size);
-            block_io::store_raw_0(&mut self.file, &default.0)?;
+    fn prepare_opt(&mut self, sync: bool) -> Result<(), Error> {
+        let r = self.prepare_blocks(sync);
+        if r.is_err() {
+            self.reset_failed_store();
         }
+        r
+    }
 
-        #[cfg(debug_assertions)]
-        if self.store_panic == 1 {
-            panic!("invoke store_panic 1");
+    // A failed store may have discarded buffered blocks that are not
+    // dirty any longer. Everything is written again with the next store.
+    fn reset_failed_store(&mut self) {
+        self.write_buffer.clear();
+        for block in self.user.values_mut() {
+            if block.generation() == self.generation {
+                block.set_dirty(true);
+            }
         }
+        self.streams.set_dirty(true);
+        let types_nrs: Vec<_> = self.types.iter().map(|v| v.block_nr()).collect();
+        for block_nr in types_nrs {
+            if let Ok(block) = self.types.blockmap_mut(block_nr) {
+                block.set_dirty(true);
+            }
+        }
+        let physical_nrs: Vec<_> = self.physical.iter().map(|v| v.block_nr()).collect();
+        for block_nr in physical_nrs {
+            if let Ok(block) = self.physical.blockmap_mut(block_nr) {
+                block.set_dirty(true);
+            }
+        }
+    }
+
+    fn prepare_blocks(&mut self, sync: bool) -> Result<(), Error> {
+        self.generation += 1;
+
+        self.store_new_header()?;
+
+        #[cfg(debug_assertions)]
+        self.store_step(1);
 
         // write user blocks.
         for (block_nr, block) in self.user.iter_mut().filter(|(_k, v)| v.is_dirty()) {
-            let new_pnr = self.physical.pop_free();
+            let new_pnr = match self.reserved.remove(block_nr) {
+                Some(pnr) => pnr,
+                None => self.physical.pop_free(),
+            };
             self.physical.set_physical_nr(*block_nr, new_pnr)?;
 
-            block_io::store_raw(&mut self.file, new_pnr, block)?;
+            self.write_buffer
+                .store(&mut *self.file, new_pnr, block, self.checksum)?;
             block.set_dirty(false);
             block.set_generation(self.generation);
         }
 
         #[cfg(debug_assertions)]
-        if self.store_panic == 2 {
-            panic!("invoke store_panic 2");
-        }
+        self.store_step(2);
 
-        if self.streams.is_dirty() {
+        for block in self.streams.iter_dirty_mut() {
             let new_pnr = self.physical.pop_free();
-            self.physical
-                .set_physical_nr(self.streams.block_nr(), new_pnr)?;
+            self.physical.set_physical_nr(block.block_nr(), new_pnr)?;
 
-            block_io::store_raw(&mut self.file, new_pnr, &self.streams.0)?;
-            self.streams.set_dirty(false);
-            self.streams.0.set_generation(self.generation);
+            self.write_buffer
+                .store(&mut *self.file, new_pnr, block, self.checksum)?;
+            block.set_dirty(false);
+            block.set_generation(self.generation);
         }
 
         #[cfg(debug_assertions)]
-        if self.store_panic == 3 {
-            panic!("invoke store_panic 3");
-        }
+        self.store_step(3);
 
         // write block-types.
         for block_nr in self.types.iter_dirty() {
@@ -188,15 +571,14 @@ impl Alloc {
             self.physical.set_physical_nr(block_nr, new_pnr)?;
 
             let map_block = self.types.blockmap_mut(block_nr)?;
-            block_io::store_raw(&mut self.file, new_pnr, &map_block.0)?;
+            self.write_buffer
+                .store(&mut *self.file, new_pnr, &map_block.0, self.checksum)?;
             map_block.set_dirty(false);
             map_block.0.set_generation(self.generation);
         }
 
         #[cfg(debug_assertions)]
-        if self.store_panic == 4 {
-            panic!("invoke store_panic 4");
-        }
+        self.store_step(4);
 
         // Assign physical block to physical block-maps before writing any of them.
         for block_nr in self.physical.iter_dirty() {
@@ -205,70 +587,106 @@ impl Alloc {
         }
 
         #[cfg(debug_assertions)]
-        if self.store_panic == 5 {
-            panic!("invoke store_panic 5");
-        }
+        self.store_step(5);
 
         // writing the physical maps is the last thing. now every block
         // including the physical maps should have a physical-block assigned.
         for block_nr in self.physical.iter_dirty() {
             let block_pnr = self.physical.physical_nr(block_nr)?;
-            debug_assert_ne!(block_pnr.as_u32(), 0);
+            debug_assert_ne!(block_pnr, PhysicalNr(0));
 
             let map_block = self.physical.blockmap_mut(block_nr)?;
-            block_io::store_raw(&mut self.file, block_pnr, &map_block.0)?;
+            self.write_buffer
+                .store(&mut *self.file, block_pnr, &map_block.0, self.checksum)?;
             map_block.set_dirty(false);
 
             map_block.0.set_generation(self.generation);
         }
 
+        // all blocks must be on disk before the header changes.
+        self.write_buffer.flush(&mut *self.file)?;
+
         #[cfg(debug_assertions)]
-        if self.store_panic == 6 {
-            panic!("invoke store_panic 6");
-        }
+        self.store_step(6);
 
         // write root blocks
         let ty_pnr = self.physical.physical_nr(_INIT_TYPES_NR)?;
         let phy_pnr = self.physical.physical_nr(_INIT_PHYSICAL_NR)?;
         let st_pnr = self.physical.physical_nr(_INIT_STREAM_NR)?;
 
-        // flip state.
+        // counted before the flip, a store that fails now has written most of its data.
+        let total_stores = self.header.total_stores() + 1;
+        self.header
+            .store_total_stores(&mut *self.file, total_stores)?;
+        self.header.store_user_meta(&mut *self.file)?;
+
+        // write the inactive state.
         match self.header.state() {
             State::Low => {
                 self.header
-                    .store_high(&mut self.file, ty_pnr, phy_pnr, st_pnr)?;
-                block_io::sync(&mut self.file)?;
-
-                #[cfg(debug_assertions)]
-                if self.store_panic == 7 {
-                    panic!("invoke store_panic 7");
-                }
-
-                self.header.store_state(&mut self.file, State::High)?;
-                block_io::sync(&mut self.file)?;
+                    .store_high(&mut *self.file, ty_pnr, phy_pnr, st_pnr, self.sequence)?;
             }
             State::High => {
                 self.header
-                    .store_low(&mut self.file, ty_pnr, phy_pnr, st_pnr)?;
-                block_io::sync(&mut self.file)?;
+                    .store_low(&mut *self.file, ty_pnr, phy_pnr, st_pnr, self.sequence)?;
+            }
+        }
+        if sync {
+            block_io::sync(&mut *self.file)?;
+        }
 
-                #[cfg(debug_assertions)]
-                if self.store_panic == 7 {
-                    panic!("invoke store_panic 7");
-                }
+        self.prepared = true;
+
+        Ok(())
+    }
 
-                self.header.store_state(&mut self.file, State::Low)?;
-                block_io::sync(&mut self.file)?;
+    // Writes the default header if this is a new file.
+    fn store_new_header(&mut self) -> Result<(), Error> {
+        if block_io::len(&mut *self.file)? == 0 {
+            let mut default = HeaderBlock::init(self.block_size);
+            default.set_checksummed(self.checksum);
+            default.set_state(self.header.state());
+            block_io::store_raw_0(&mut *self.file, &default.0)?;
+            if self.header.is_open() {
+                self.header.store_open(&mut *self.file, true)?;
             }
         }
+        Ok(())
+    }
+
+    /// Second half of store(). Flips the header state to the data written
+    /// with prepare().
+    ///
+    /// Fails with NotPrepared if there was no prepare().
+    pub fn commit(&mut self) -> Result<(), Error> {
+        self.commit_opt(true)
+    }
+
+    fn commit_opt(&mut self, sync: bool) -> Result<(), Error> {
+        if !self.prepared {
+            return Err(Error::err(FBErrorKind::NotPrepared));
+        }
 
         #[cfg(debug_assertions)]
-        if self.store_panic == 100 {
-            panic!("invoke store_panic 100");
+        self.store_step(7);
+
+        // flip state.
+        let state = match self.header.state() {
+            State::Low => State::High,
+            State::High => State::Low,
+        };
+        self.header.store_state(&mut *self.file, state)?;
+        if sync {
+            block_io::sync(&mut *self.file)?;
         }
+        self.prepared = false;
 
-        // Rebuild the list of free physical pages.
-        let file_size = block_io::metadata(&mut self.file)?.len();
+        #[cfg(debug_assertions)]
+        self.store_step(100);
+
+        // Rebuild the list of free physical pages. This ends any reservation.
+        self.reserved.clear();
+        let file_size = block_io::len(&mut *self.file)?;
         self.physical.init_free_list(file_size);
 
         // Clean cache.
@@ -277,15 +695,289 @@ impl Alloc {
         Ok(())
     }
 
+    /// Allocates a block for each slice and writes it directly to a new
+    /// physical block without going through the cache. Finishes with a
+    /// store(), so the header is flipped only once.
+    ///
+    /// Fails with DataTooLarge if a slice exceeds the data_size().
+    pub fn bulk_write<'a>(
+        &mut self,
+        block_type: BlockType,
+        align: usize,
+        blocks: impl Iterator<Item = &'a [u8]>,
+    ) -> Result<Vec<LogicalNr>, Error> {
+        // must be first, store() can't tell a new file later.
+        self.store_new_header()?;
+
+        let mut block_nrs = Vec::new();
+        let mut block = Block::new(LogicalNr(0), self.data_size, align, block_type);
+        for data in blocks {
+            if data.len() > self.data_size {
+                // the blocks so far are allocated and must be written.
+                self.write_buffer.flush(&mut *self.file)?;
+                return Err(Error::err(FBErrorKind::DataTooLarge(data.len())));
+            }
+
+            if self.types.free_len() == 2 {
+                self.append_blockmap()?;
+            }
+            let Some(block_nr) = self.types.pop_free() else {
+                return Err(Error::err(FBErrorKind::NoFreeBlocks));
+            };
+            self.types.set_block_type(block_nr, block_type)?;
+
+            block.set_block_nr(block_nr);
+            block.data[..data.len()].copy_from_slice(data);
+            block.data[data.len()..].fill(0);
+
+            let new_pnr = self.physical.pop_free();
+            self.physical.set_physical_nr(block_nr, new_pnr)?;
+            self.write_buffer
+                .store(&mut *self.file, new_pnr, &block, self.checksum)?;
+
+            block_nrs.push(block_nr);
+        }
+
+        self.store()?;
+
+        Ok(block_nrs)
+    }
+
+    /// Store to file and report the physical block for every block that
+    /// was written. This includes the streams block and the map-blocks.
+    pub fn store_and_report(&mut self) -> Result<Vec<(LogicalNr, PhysicalNr)>, Error> {
+        let mut block_nrs: Vec<_> = self
+            .user
+            .iter()
+            .filter(|(_k, v)| v.is_dirty())
+            .map(|(k, _v)| *k)
+            .collect();
+
+        self.store()?;
+
+        // the map-blocks get dirty while storing, use the generation.
+        let generation = self.generation;
+        block_nrs.extend(
+            self.streams
+                .iter_blocks()
+                .filter(|v| v.generation() == generation)
+                .map(|v| v.block_nr()),
+        );
+        block_nrs.extend(
+            self.types
+                .iter()
+                .filter(|v| v.generation() == generation)
+                .map(|v| v.block_nr()),
+        );
+        block_nrs.extend(
+            self.physical
+                .iter()
+                .filter(|v| v.generation() == generation)
+                .map(|v| v.block_nr()),
+        );
+
+        block_nrs
+            .into_iter()
+            .map(|nr| Ok((nr, self.physical.physical_nr(nr)?)))
+            .collect()
+    }
+
+    /// Can the inactive state of the header still be used to go back one store?
+    ///
+    /// After a store the blocks of the previous state are on the free-list,
+    /// but they are not overwritten before the next store. So a block of the
+    /// inactive state counts as intact if it is still free, or if the active
+    /// state uses it for the same logical block. It is lost once it has been
+    /// handed out from the free-list again, which leaves it writable by the
+    /// next store. This walks the complete block-map of the inactive state.
+    ///
+    /// Between prepare() and commit() the inactive state is the prepared one,
+    /// and this returns false. An interrupted store from an earlier session
+    /// is only detected if it left the inactive block-map unreadable.
+    pub fn can_rollback(&mut self) -> bool {
+        let (active_pnr, types_pnr, physical_pnr, streams_pnr) = match self.header.state() {
+            State::Low => (
+                self.header.low_physical(),
+                self.header.high_types(),
+                self.header.high_physical(),
+                self.header.high_streams(),
+            ),
+            State::High => (
+                self.header.high_physical(),
+                self.header.low_types(),
+                self.header.low_physical(),
+                self.header.low_streams(),
+            ),
+        };
+        if types_pnr == 0 || physical_pnr == 0 {
+            return false;
+        }
+
+        // owners of the physical blocks in the stored active state.
+        let Ok(active) = Physical::load(&mut *self.file, self.data_size, self.checksum, active_pnr)
+        else {
+            return false;
+        };
+        let mut owner = BTreeMap::new();
+        for block in active.iter() {
+            for (nr, pnr) in block.iter_nr() {
+                if pnr != 0 {
+                    owner.insert(pnr, nr);
+                }
+            }
+        }
+
+        let intact = |block_nr: LogicalNr, pnr: PhysicalNr| match owner.get(&pnr) {
+            Some(owner_nr) => *owner_nr == block_nr,
+            None => self.physical.is_free(pnr),
+        };
+        if !intact(_INIT_PHYSICAL_NR, physical_pnr)
+            || !intact(_INIT_TYPES_NR, types_pnr)
+            || (streams_pnr != 0 && !intact(_INIT_STREAM_NR, streams_pnr))
+        {
+            return false;
+        }
+
+        let Ok(inactive) =
+            Physical::load(&mut *self.file, self.data_size, self.checksum, physical_pnr)
+        else {
+            return false;
+        };
+        let mapped = inactive
+            .iter()
+            .flat_map(|block| block.iter_nr())
+            .filter(|(_, pnr)| *pnr != 0)
+            .all(|(nr, pnr)| intact(nr, pnr));
+        mapped
+    }
+
+    /// Reads the process-open marker directly from the storage.
+    pub(crate) fn stored_open_marker(
+        file: &mut dyn BlockStorage,
+        block_size: usize,
+    ) -> Result<bool, Error> {
+        let mut header = HeaderBlock::new(block_size);
+        block_io::load_raw_0(file, &mut header.0)?;
+        Ok(header.is_open())
+    }
+
+    /// Replaces the file handle. It must refer to the same file.
+    pub(crate) fn replace_file<S: BlockStorage + 'static>(&mut self, file: S) {
+        self.file = Box::new(file);
+    }
+
+    /// Copy of the raw storage contents.
+    pub fn storage_bytes(&mut self) -> Result<Vec<u8>, Error> {
+        block_io::load_all_raw(&mut *self.file)
+    }
+
+    /// SHA-256 of the raw storage contents. Reads block by block.
+    #[cfg(feature = "sha2")]
+    pub fn storage_digest(&mut self) -> Result<[u8; 32], Error> {
+        use sha2::{Digest, Sha256};
+
+        let len = block_io::len(&mut *self.file)?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0u8; self.block_size];
+        let mut pos = 0u64;
+        while pos < len {
+            let n = (len - pos).min(buf.len() as u64) as usize;
+            block_io::load_at(&mut *self.file, pos, &mut buf[..n])?;
+            hasher.update(&buf[..n]);
+            pos += n as u64;
+        }
+        Ok(hasher.finalize().into())
+    }
+
+    /// Sets the process-open marker in the header. This is written to the file
+    /// immediately if the file already has a header, otherwise with the first store.
+    pub fn set_open_marker(&mut self, open: bool) -> Result<(), Error> {
+        if block_io::len(&mut *self.file)? == 0 {
+            self.header.set_open(open);
+        } else {
+            self.header.store_open(&mut *self.file, open)?;
+            block_io::sync(&mut *self.file)?;
+        }
+        Ok(())
+    }
+
     /// Stores a compact copy. The copy contains no unused blocks.
-    #[allow(dead_code)]
-    pub fn compact_to(&mut self, _file: &mut File) -> Result<(), Error> {
-        unimplemented!()
+    ///
+    /// The logical block-nrs stay the same, the physical blocks are
+    /// renumbered densely. This copies the current state, including any
+    /// changes that have not been stored yet. The file should be empty.
+    pub fn compact_to<S: BlockStorage + 'static>(&mut self, file: S) -> Result<(), Error> {
+        let mut header = HeaderBlock::init(self.block_size);
+        header.set_checksummed(self.checksum);
+        header.set_user_meta(self.header.user_meta())?;
+        let mut compact = Self {
+            file: Box::new(file),
+            block_size: self.block_size,
+            data_size: self.data_size,
+            checksum: self.checksum,
+            header,
+            types: self.types.copy(),
+            physical: self.physical.copy_empty(),
+            streams: self.streams.copy(),
+            write_buffer: WriteBuffer::new(DEFAULT_WRITE_BUFFER),
+            reserved: Default::default(),
+            user: Default::default(),
+            cache_limit: None,
+            cache_tick: 0,
+            cache_lru: Default::default(),
+            cache_order: Default::default(),
+            scratch: Default::default(),
+            generation: 0,
+            sequence: self.sequence,
+            prepared: false,
+            #[cfg(debug_assertions)]
+            store_panic: 0,
+            #[cfg(debug_assertions)]
+            store_hook: None,
+        };
+
+        // header first, store() only writes it for an empty file.
+        block_io::store_raw_0(&mut *compact.file, &compact.header.0)?;
+
+        // copy the user blocks one at a time. store() does the rest.
+        let block_nrs: Vec<_> = self
+            .iter_metadata(&|_nr, ty| ty != BlockType::Free && !ty.is_internal())
+            .collect();
+        for (block_nr, block_type) in block_nrs {
+            let block_pnr = self.physical.physical_nr(block_nr)?;
+
+            let mut tmp;
+            let block = match self.user.get(&block_nr) {
+                Some(block) if block.is_dirty() || block_pnr != 0 => block,
+                Some(_) => continue,
+                None if block_pnr != 0 => {
+                    tmp = Block::new(block_nr, self.data_size, 1, block_type);
+                    block_io::load_raw(&mut *self.file, block_pnr, &mut tmp, self.checksum)?;
+                    &tmp
+                }
+                // never written.
+                None => continue,
+            };
+
+            let new_pnr = compact.physical.pop_free();
+            compact.physical.set_physical_nr(block_nr, new_pnr)?;
+            compact
+                .write_buffer
+                .store(&mut *compact.file, new_pnr, block, compact.checksum)?;
+        }
+
+        compact.store()
     }
 
-    // post load validation.
-    fn verify(&self, block_size: usize) -> Result<(), Error> {
-        if self.header.stored_block_size() != block_size {
+    /// Runs the checks from load against the current state.
+    /// Checks the block-size in the header, the sequence of the map blocks,
+    /// the block-types of all internal blocks and that no physical block is
+    /// assigned twice.
+    pub fn verify(&self) -> Result<(), Error> {
+        self.types.verify()?;
+        self.physical.verify()?;
+
+        if self.header.stored_block_size() != self.block_size {
             return Err(Error::err(FBErrorKind::InvalidBlockSize(
                 self.header.stored_block_size(),
             )));
@@ -324,6 +1016,17 @@ impl Alloc {
                 )));
             }
         }
+
+        for block_nr in self.streams.iter_block_nrs() {
+            let Ok(block_type) = self.block_type(block_nr) else {
+                return Err(Error::err(FBErrorKind::NoBlockType(block_nr)));
+            };
+            if block_type != BlockType::Streams {
+                return Err(Error::err(FBErrorKind::InvalidBlockType(
+                    block_nr, block_type,
+                )));
+            }
+        }
         Ok(())
     }
 
@@ -337,13 +1040,22 @@ impl Alloc {
         self.types.set_block_type(types_nr, BlockType::Types)?;
         self.types.append_blockmap(types_nr);
 
-        // new physical-block
-        let Some(physical_nr) = self.types.pop_free() else {
-            return Err(Error::err(FBErrorKind::NoFreeBlocks));
-        };
-        self.types
-            .set_block_type(physical_nr, BlockType::Physical)?;
-        self.physical.append_blockmap(physical_nr)?;
+        self.append_physical_blockmap()
+    }
+
+    /// Append blocks for the physical map until it covers all block-nrs
+    /// of the type map.
+    ///
+    /// With the feature physical64 one physical block covers fewer block-nrs.
+    fn append_physical_blockmap(&mut self) -> Result<(), Error> {
+        while self.physical.end_nr() < self.types.end_nr() {
+            let Some(physical_nr) = self.types.pop_free() else {
+                return Err(Error::err(FBErrorKind::NoFreeBlocks));
+            };
+            self.types
+                .set_block_type(physical_nr, BlockType::Physical)?;
+            self.physical.append_blockmap(physical_nr)?;
+        }
 
         Ok(())
     }
@@ -353,11 +1065,28 @@ impl Alloc {
         self.block_size
     }
 
+    /// Size of the block data. This is the block-size without the
+    /// checksum trailer.
+    pub fn data_size(&self) -> usize {
+        self.data_size
+    }
+
+    /// Blocks are written with a checksum.
+    pub fn is_checksummed(&self) -> bool {
+        self.checksum
+    }
+
     /// Header data.
     pub fn header(&self) -> &HeaderBlock {
         &self.header
     }
 
+    /// Set the application data in the header block.
+    /// Fails with DataTooLarge if it doesn't fit.
+    pub fn set_user_meta(&mut self, meta: &[u8]) -> Result<(), Error> {
+        self.header.set_user_meta(meta)
+    }
+
     /// Streams data.
     pub fn streams(&self) -> &StreamsBlock {
         &self.streams
@@ -383,11 +1112,17 @@ impl Alloc {
         self.physical.iter()
     }
 
+    /// Runs of consecutive free physical blocks as (start, len).
+    /// The free list is rebuilt after each store.
+    pub fn free_extents(&self) -> Vec<(PhysicalNr, u32)> {
+        self.physical.free_extents()
+    }
+
     /// Metadata. As this copies the metadata there is a front-line filter available.
     pub fn iter_metadata<F>(
         &self,
         filter: &F,
-    ) -> impl Iterator<Item = (LogicalNr, BlockType)> + DoubleEndedIterator
+    ) -> impl DoubleEndedIterator<Item = (LogicalNr, BlockType)>
     where
         F: Fn(LogicalNr, BlockType) -> bool,
     {
@@ -400,11 +1135,148 @@ impl Alloc {
         self.generation
     }
 
+    /// Generation of the store that last wrote the block. This is only
+    /// tracked for cached blocks, a block that was loaded or discarded
+    /// since has generation 0.
+    pub fn block_generation(&self, block_nr: LogicalNr) -> Result<u32, Error> {
+        if self.types.block_type(block_nr)? == BlockType::Free {
+            return Err(Error::err(FBErrorKind::NotAllocated(block_nr)));
+        }
+        Ok(self
+            .user
+            .get(&block_nr)
+            .map(|v| v.generation())
+            .unwrap_or(0))
+    }
+
+    /// Was the block written by a store after the given generation.
+    pub fn stored_since(&self, block_nr: LogicalNr, generation: u32) -> Result<bool, Error> {
+        Ok(self.block_generation(block_nr)? > generation)
+    }
+
+    /// Any unsaved changes?
+    pub fn is_dirty(&self) -> bool {
+        self.user.values().any(|v| v.is_dirty())
+            || self.header.0.is_dirty()
+            || self.sequence != self.header.sequence()
+            || self.streams.is_dirty()
+            || self.types.iter().any(|v| v.is_dirty())
+            || self.physical.iter().any(|v| v.is_dirty())
+    }
+
+    /// Free block-nrs of the type map in ascending order. These are
+    /// handed out by alloc_block() before the map chain grows.
+    pub fn iter_free(&self) -> impl Iterator<Item = LogicalNr> {
+        self.types.iter_free()
+    }
+
+    /// Increments the sequence counter and returns the new value.
+    ///
+    /// This marks the header dirty, the counter is written to the new
+    /// header state with the next store. Until then a crash or reload goes
+    /// back to the last stored value, together with anything else that
+    /// used the new values.
+    pub fn next_sequence(&mut self) -> u64 {
+        self.sequence += 1;
+        self.sequence
+    }
+
+    /// Block counts.
+    pub fn block_stats(&self) -> BlockStats {
+        BlockStats {
+            allocated: self
+                .iter_metadata(&|_nr, ty| ty != BlockType::Free && !ty.is_internal())
+                .count(),
+            free: self.types.free_len(),
+            physical_file_blocks: self.physical.file_blocks(),
+            wasted: self.physical.free_len(),
+        }
+    }
+
+    /// Capacity of the write buffer in bytes. Consecutive physical blocks
+    /// are collected up to this size and written with one call.
+    /// 0 writes every block on its own. Defaults to 64k.
+    pub fn set_write_buffer_size(&mut self, bytes: usize) {
+        self.write_buffer.set_capacity(bytes);
+    }
+
+    /// Size of the storage in blocks.
+    pub fn file_block_count(&mut self) -> Result<u64, Error> {
+        Ok(block_io::len(&mut *self.file)? / self.block_size as u64)
+    }
+
+    /// Cuts off the free physical blocks at the end of the file.
+    /// Returns the number of blocks removed. The blocks of the inactive
+    /// state are kept as long as can_rollback() is true.
+    ///
+    /// Fails with UnsavedChanges if there is anything not stored yet.
+    pub fn truncate_file(&mut self) -> Result<u64, Error> {
+        if self.prepared || self.is_dirty() {
+            return Err(Error::err(FBErrorKind::UnsavedChanges));
+        }
+
+        let mut max_used = self.physical.max_used();
+        if self.can_rollback() {
+            let physical_pnr = match self.header.state() {
+                State::Low => self.header.high_physical(),
+                State::High => self.header.low_physical(),
+            };
+            let inactive =
+                Physical::load(&mut *self.file, self.data_size, self.checksum, physical_pnr)?;
+            max_used = max_used.max(inactive.max_used());
+        }
+
+        let file_size = block_io::len(&mut *self.file)?;
+        let len = (max_used.as_u64() + 1) * self.block_size as u64;
+        if len >= file_size {
+            return Ok(0);
+        }
+
+        block_io::set_len(&mut *self.file, len)?;
+        block_io::sync(&mut *self.file)?;
+        self.physical.init_free_list(len);
+
+        Ok((file_size - len) / self.block_size as u64)
+    }
+
+    /// Generations of the type-map and physical-map blocks.
+    /// Shows which of them were rewritten with the last store.
+    pub fn map_generations(&self) -> MapGenerations {
+        let generation = self.generation;
+        MapGenerations {
+            generation,
+            types: self
+                .types
+                .iter()
+                .map(|v| MapGeneration {
+                    block_nr: v.block_nr(),
+                    generation: v.generation(),
+                    current: v.generation() == generation,
+                })
+                .collect(),
+            physical: self
+                .physical
+                .iter()
+                .map(|v| MapGeneration {
+                    block_nr: v.block_nr(),
+                    generation: v.generation(),
+                    current: v.generation() == generation,
+                })
+                .collect(),
+        }
+    }
+
     /// Iterate all blocks in memory.
     pub fn iter_blocks(&self) -> impl Iterator<Item = &Block> {
         self.user.values()
     }
 
+    /// Number of free block-nrs in the type map. alloc_block() appends
+    /// to the map chain when only 2 are left.
+    pub fn immediate_free_capacity(&self) -> usize {
+        self.types.free_len()
+    }
+
     /// Allocate a block.
     pub fn alloc_block(&mut self, block_type: BlockType, align: usize) -> Result<LogicalNr, Error> {
         if self.types.free_len() == 2 {
@@ -416,14 +1288,81 @@ impl Alloc {
         };
         self.types.set_block_type(alloc_nr, block_type)?;
 
-        let block = Block::new(alloc_nr, self.block_size, align, block_type);
-        self.user.insert(alloc_nr, block);
-        Ok(alloc_nr)
+        let block = Block::new(alloc_nr, self.data_size, align, block_type);
+        self.user.insert(alloc_nr, block);
+        self.touch_cached(alloc_nr);
+        Ok(alloc_nr)
+    }
+
+    /// Grows the maps so that additional blocks can be allocated without
+    /// appending further map blocks.
+    ///
+    /// Fails with NoFreeBlocks if additional is too large.
+    pub fn reserve(&mut self, additional: usize) -> Result<(), Error> {
+        let Some(needed) = additional.checked_add(2) else {
+            return Err(Error::err(FBErrorKind::NoFreeBlocks));
+        };
+        while self.types.free_len() < needed {
+            self.append_blockmap()?;
+        }
+        Ok(())
+    }
+
+    /// Allocate count blocks. The maps are grown for all of them first,
+    /// so this fails before any block is allocated.
+    pub fn alloc_blocks(
+        &mut self,
+        block_type: BlockType,
+        align: usize,
+        count: usize,
+    ) -> Result<Vec<LogicalNr>, Error> {
+        self.reserve(count)?;
+
+        let mut block_nrs = Vec::with_capacity(count);
+        for _ in 0..count {
+            block_nrs.push(self.alloc_block(block_type, align)?);
+        }
+        Ok(block_nrs)
+    }
+
+    /// Allocate count blocks and reserve consecutive physical blocks for
+    /// them, so the next store writes them in one run. The reserved blocks
+    /// are taken from the free-list or appended to the file. The blocks are
+    /// marked dirty. A reservation that is unused by the next store ends
+    /// with it. The block-nrs are returned in ascending order.
+    pub fn alloc_contiguous(
+        &mut self,
+        block_type: BlockType,
+        align: usize,
+        count: usize,
+    ) -> Result<Vec<LogicalNr>, Error> {
+        let mut block_nrs = self.alloc_blocks(block_type, align, count)?;
+        // store() writes in block-nr order.
+        block_nrs.sort();
+
+        let start = self.physical.pop_free_extent(count as u32);
+        for (i, block_nr) in block_nrs.iter().enumerate() {
+            self.reserved.insert(*block_nr, start + i as u32);
+            self.block_mut(*block_nr, align)?.set_dirty(true);
+        }
+        Ok(block_nrs)
     }
 
     /// Free a block.
+    ///
+    /// Fails with NotAllocated if the block is already free and with
+    /// AccessDenied for an internal block.
     pub fn free_block(&mut self, block_nr: LogicalNr) -> Result<(), Error> {
-        self.user.remove(&block_nr);
+        let block_type = self.types.block_type(block_nr)?;
+        if block_type == BlockType::Free {
+            return Err(Error::err(FBErrorKind::NotAllocated(block_nr)));
+        }
+        if block_type.is_internal() {
+            return Err(Error::err(FBErrorKind::AccessDenied(block_nr)));
+        }
+
+        self.uncache(block_nr);
+        self.reserved.remove(&block_nr);
 
         self.types.set_block_type(block_nr, BlockType::Free)?;
         self.types.push_free(block_nr);
@@ -433,6 +1372,75 @@ impl Alloc {
         Ok(())
     }
 
+    /// Changes the block-type of an allocated block. The data and the
+    /// physical block stay as they are.
+    ///
+    /// Fails with NotAllocated for a free block and with AccessDenied if
+    /// either the old or the new block-type is internal. Use free_block()
+    /// instead of changing to Free.
+    pub fn retype_block(&mut self, block_nr: LogicalNr, new_type: BlockType) -> Result<(), Error> {
+        let block_type = self.types.block_type(block_nr)?;
+        if block_type == BlockType::Free {
+            return Err(Error::err(FBErrorKind::NotAllocated(block_nr)));
+        }
+        if block_type.is_internal() || new_type.is_internal() || new_type == BlockType::Free {
+            return Err(Error::err(FBErrorKind::AccessDenied(block_nr)));
+        }
+
+        self.types.set_block_type(block_nr, new_type)?;
+        if let Some(block) = self.user.get_mut(&block_nr) {
+            block.set_block_type(new_type);
+        }
+
+        Ok(())
+    }
+
+    /// Changes the block-type of all blocks of block-type from to block-type
+    /// to. A stream head-idx is moved too. Returns the number of blocks.
+    ///
+    /// Fails with NoUserBlockType if one of them is Free or an internal type.
+    /// The block-type to must be unused. Fails with MixedStreamUsage if
+    /// to is a stream or if from is a stream and there are already blocks
+    /// of type to. Fails with TypeInUse if there are blocks of type to
+    /// otherwise.
+    pub fn rename_type(&mut self, from: BlockType, to: BlockType) -> Result<usize, Error> {
+        for ty in [from, to] {
+            if ty == BlockType::Free || ty.is_internal() {
+                return Err(Error::err(FBErrorKind::NoUserBlockType(ty)));
+            }
+        }
+        if from == to {
+            return Ok(self.iter_metadata(&|_nr, ty| ty == from).count());
+        }
+
+        let is_stream = self.streams.is_stream(from);
+        if is_stream || self.streams.is_stream(to) {
+            if self.streams.is_stream(to)
+                || self.iter_metadata(&|_nr, ty| ty == to).next().is_some()
+            {
+                return Err(Error::err(FBErrorKind::MixedStreamUsage(to)));
+            }
+        } else if self.iter_metadata(&|_nr, ty| ty == to).next().is_some() {
+            return Err(Error::err(FBErrorKind::TypeInUse(to)));
+        }
+
+        let block_nrs: Vec<_> = self
+            .iter_metadata(&|_nr, ty| ty == from)
+            .map(|(nr, _ty)| nr)
+            .collect();
+        for block_nr in &block_nrs {
+            self.retype_block(*block_nr, to)?;
+        }
+
+        if is_stream {
+            let head_idx = self.streams.head_idx(from);
+            self.streams.remove_stream(from);
+            self.set_stream_head_idx(to, head_idx)?;
+        }
+
+        Ok(block_nrs.len())
+    }
+
     /// Discard a block. Remove from memory cache but do nothing otherwise.
     /// If the block was modified, the discard flag is set and the block is removed
     /// after store.
@@ -441,16 +1449,42 @@ impl Alloc {
             if block.is_dirty() {
                 block.set_discard(true);
             } else {
-                self.user.remove(&block_nr);
+                self.uncache(block_nr);
             }
         }
     }
 
-    /// Free user-block cache.
+    /// Estimated memory used by the block cache and the scratch blocks.
+    /// This is the block data plus the Block struct and the map key.
+    pub fn cache_memory_bytes(&self) -> usize {
+        const OVERHEAD: usize = size_of::<Block>() + size_of::<LogicalNr>();
+        self.user
+            .values()
+            .chain(self.scratch.iter())
+            .map(|v| v.data.len() + OVERHEAD)
+            .sum()
+    }
+
+    /// Allocate a scratch block. This block has no block-nr and is never stored.
+    /// It is dropped with the next store or retain_blocks().
+    pub fn alloc_scratch(&mut self, align: usize) -> &mut Block {
+        let mut block = Block::new(LogicalNr(0), self.data_size, align, BlockType::Free);
+        block.set_scratch(true);
+        self.scratch.push(block);
+        self.scratch.last_mut().expect("scratch-block")
+    }
+
+    /// Number of scratch blocks.
+    pub fn scratch_len(&self) -> usize {
+        self.scratch.len()
+    }
+
+    /// Free user-block cache. Drops all scratch blocks too.
     pub fn retain_blocks<F>(&mut self, mut f: F)
     where
         F: FnMut(&LogicalNr, &mut Block) -> bool,
     {
+        self.scratch.clear();
         self.user.retain(move |k, v| match v.block_type() {
             BlockType::Free
             | BlockType::Header
@@ -459,6 +1493,81 @@ impl Alloc {
             | BlockType::Streams => unreachable!(), // stored elsewhere
             _ => f(k, v),
         });
+        self.retain_cached();
+    }
+
+    /// Removes all clean user blocks from the cache. Dirty blocks and
+    /// scratch blocks stay.
+    pub fn release_clean(&mut self) {
+        self.user.retain(|_k, v| v.is_dirty());
+        self.retain_cached();
+    }
+
+    /// Limits the number of cached user blocks. If a block()/block_mut()
+    /// exceeds the limit, the least recently used clean blocks are removed
+    /// from the cache. Dirty blocks are never removed, so the cache can
+    /// still grow beyond the limit with unsaved changes.
+    pub fn set_cache_limit(&mut self, limit: Option<usize>) {
+        self.cache_limit = limit;
+        self.evict_clean(None);
+    }
+
+    /// Limit for cached user blocks.
+    pub fn cache_limit(&self) -> Option<usize> {
+        self.cache_limit
+    }
+
+    // Record the use of a cached block.
+    fn touch_cached(&mut self, block_nr: LogicalNr) {
+        self.cache_tick += 1;
+        if let Some(tick) = self.cache_lru.insert(block_nr, self.cache_tick) {
+            self.cache_order.remove(&tick);
+        }
+        self.cache_order.insert(self.cache_tick, block_nr);
+    }
+
+    // Remove a block from the cache.
+    fn uncache(&mut self, block_nr: LogicalNr) {
+        self.user.remove(&block_nr);
+        if let Some(tick) = self.cache_lru.remove(&block_nr) {
+            self.cache_order.remove(&tick);
+        }
+    }
+
+    // Drop the last use of blocks that are no longer cached.
+    fn retain_cached(&mut self) {
+        let user = &self.user;
+        self.cache_lru.retain(|k, _| user.contains_key(k));
+        self.cache_order.retain(|_, v| user.contains_key(v));
+    }
+
+    // Remove least recently used clean blocks until the cache limit is met.
+    // Starts with the oldest use, only dirty blocks are skipped.
+    fn evict_clean(&mut self, keep: Option<LogicalNr>) {
+        let Some(limit) = self.cache_limit else {
+            return;
+        };
+        if self.user.len() <= limit {
+            return;
+        }
+
+        let mut evict = Vec::new();
+        let mut len = self.user.len();
+        for block_nr in self.cache_order.values() {
+            if len <= limit {
+                break;
+            }
+            if Some(*block_nr) == keep {
+                continue;
+            }
+            if self.user.get(block_nr).is_some_and(|v| !v.is_dirty()) {
+                evict.push(*block_nr);
+                len -= 1;
+            }
+        }
+        for block_nr in evict {
+            self.uncache(block_nr);
+        }
     }
 
     /// Returns the block.
@@ -466,19 +1575,32 @@ impl Alloc {
         if !self.user.contains_key(&block_nr) {
             self.load_block(block_nr, align)?;
         }
+        self.touch_cached(block_nr);
+        self.evict_clean(Some(block_nr));
 
         Ok(self.user.get(&block_nr).expect("user-block"))
     }
 
     /// Returns the block.
+    ///
+    /// Unlike FileBlocks::get_mut() this gives the plain block and doesn't
+    /// mark it dirty. Call set_dirty(true) after changing the data,
+    /// otherwise the change is not stored.
     pub fn block_mut(&mut self, block_nr: LogicalNr, align: usize) -> Result<&'_ mut Block, Error> {
         if !self.user.contains_key(&block_nr) {
             self.load_block(block_nr, align)?;
         }
+        self.touch_cached(block_nr);
+        self.evict_clean(Some(block_nr));
 
         Ok(self.user.get_mut(&block_nr).expect("user-block"))
     }
 
+    /// Is the block in the block-cache.
+    pub fn is_cached(&self, block_nr: LogicalNr) -> bool {
+        self.user.contains_key(&block_nr)
+    }
+
     /// Load a block and inserts it into the block-cache.
     /// Reloads the block unconditionally.
     pub fn load_block(&mut self, block_nr: LogicalNr, align: usize) -> Result<(), Error> {
@@ -493,12 +1615,23 @@ impl Alloc {
             _ => self.physical.physical_nr(block_nr)?,
         };
 
-        let mut block = Block::new(block_nr, self.block_size, align, block_type);
+        let mut block = Block::new(block_nr, self.data_size, align, block_type);
         if block_pnr != 0 {
-            block_io::load_raw(&mut self.file, block_pnr, &mut block)?;
+            if let Err(e) =
+                block_io::load_raw(&mut *self.file, block_pnr, &mut block, self.checksum)
+            {
+                // only look at the file length if the read failed.
+                if block_pnr.as_u64() >= self.file_block_count()? {
+                    return Err(Error::err(FBErrorKind::PhysicalOutOfRange(
+                        block_nr, block_pnr,
+                    )));
+                }
+                return Err(e);
+            }
         }
 
         self.user.insert(block_nr, block);
+        self.touch_cached(block_nr);
 
         Ok(())
     }
@@ -511,17 +1644,208 @@ impl Alloc {
         self.streams.head_idx(block_type)
     }
 
-    /// Set the stream head-idx for a stream.
+    /// Length of the stream in bytes. 0 if there are no blocks of this type.
+    pub fn stream_len(&self, block_type: BlockType) -> u64 {
+        let block_count = self.iter_metadata(&|_nr, ty| ty == block_type).count();
+        if block_count == 0 {
+            0
+        } else {
+            (block_count as u64 - 1) * self.data_size as u64
+                + self.streams.head_idx(block_type) as u64
+        }
+    }
+
+    /// Cuts the stream to new_len bytes. The trailing blocks are freed and
+    /// the rest of the new last block is zeroed. Does nothing if new_len is
+    /// not smaller than the current length.
+    pub fn truncate_stream(
+        &mut self,
+        block_type: BlockType,
+        new_len: u64,
+        block_align: usize,
+    ) -> Result<(), Error> {
+        if new_len >= self.stream_len(block_type) {
+            return Ok(());
+        }
+
+        let data_size = self.data_size as u64;
+        // a full last block has head-idx data_size, an empty stream keeps
+        // its first block with head-idx 0.
+        let keep = new_len.div_ceil(data_size).max(1);
+        let head_idx = new_len - (keep - 1) * data_size;
+
+        let block_nrs: Vec<_> = self
+            .iter_metadata(&|_nr, ty| ty == block_type)
+            .skip(keep as usize - 1)
+            .map(|(nr, _ty)| nr)
+            .collect();
+        for block_nr in &block_nrs[1..] {
+            self.free_block(*block_nr)?;
+        }
+
+        // verify_streams() expects nothing behind the head-idx.
+        let head_idx = head_idx as usize;
+        let tail = self.block_mut(block_nrs[0], block_align)?;
+        if tail.data[head_idx..].iter().any(|v| *v != 0) {
+            tail.data[head_idx..].fill(0);
+            tail.set_dirty(true);
+        }
+
+        self.set_stream_head_idx(block_type, head_idx)
+    }
+
+    /// Set the stream head-idx for a stream. A new stream chains another
+    /// block to the streams block if it is full.
     pub fn set_stream_head_idx(&mut self, block_type: BlockType, idx: usize) -> Result<(), Error> {
+        if !self.streams.is_stream(block_type)
+            && self.streams.iter_streams().count() == self.streams.capacity()
+        {
+            self.append_streams_block()?;
+        }
         self.streams.set_head_idx(block_type, idx)
     }
 
+    /// Append a block to the streams block.
+    fn append_streams_block(&mut self) -> Result<(), Error> {
+        if self.types.free_len() == 2 {
+            self.append_blockmap()?;
+        }
+
+        let Some(streams_nr) = self.types.pop_free() else {
+            return Err(Error::err(FBErrorKind::NoFreeBlocks));
+        };
+        self.types.set_block_type(streams_nr, BlockType::Streams)?;
+        self.streams.append_block(streams_nr);
+        Ok(())
+    }
+
+    /// Checks that no stream has a tail block that was stored with a newer
+    /// generation than the streams block. This happens if a store wrote the
+    /// user blocks but failed before writing the streams block. The head-idx
+    /// is stale then and a further store would persist wrong data.
+    ///
+    /// Generations are only kept in memory. For every tail block, cached or
+    /// not, this checks that there is no data behind the head-idx too.
+    /// A tail that is not cached is read from storage but not cached.
+    pub fn verify_streams(&mut self) -> Result<(), Error> {
+        let streams: Vec<_> = self.streams.iter().collect();
+        for (block_type, head_idx) in streams {
+            let tail_nr = self
+                .iter_metadata(&|_nr, ty| ty == block_type)
+                .rev()
+                .map(|(nr, _ty)| nr)
+                .next();
+            let Some(tail_nr) = tail_nr else {
+                continue;
+            };
+
+            let tmp;
+            let tail = match self.user.get(&tail_nr) {
+                Some(tail) => {
+                    if tail.generation() > self.streams.generation() {
+                        return Err(Error::err(FBErrorKind::StreamIndexStale(block_type)));
+                    }
+                    tail
+                }
+                None => {
+                    let tail_pnr = self.physical.physical_nr(tail_nr)?;
+                    if tail_pnr == 0 {
+                        continue;
+                    }
+                    let mut block = Block::new(tail_nr, self.data_size, 1, block_type);
+                    block_io::load_raw(&mut *self.file, tail_pnr, &mut block, self.checksum)?;
+                    tmp = block;
+                    &tmp
+                }
+            };
+
+            let clean = match tail.data.get(head_idx..) {
+                Some(rest) => rest.iter().all(|v| *v == 0),
+                None => false,
+            };
+            if !clean {
+                return Err(Error::err(FBErrorKind::StreamIndexStale(block_type)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of gaps for each stream, where the next block of the stream
+    /// is not the physically next block. Blocks that were never stored
+    /// are not counted.
+    pub fn fragmentation_report(&self) -> Vec<(BlockType, usize)> {
+        self.streams
+            .iter_streams()
+            .map(|block_type| {
+                let pnrs: Vec<_> = self
+                    .iter_metadata(&|_nr, ty| ty == block_type)
+                    .map(|(nr, _ty)| self.physical.physical_nr(nr).unwrap_or(PhysicalNr(0)))
+                    .filter(|pnr| *pnr != 0)
+                    .collect();
+                let gaps = pnrs.windows(2).filter(|v| v[0] + 1 != v[1]).count();
+                (block_type, gaps)
+            })
+            .collect()
+    }
+
+    /// Streams with a head-idx but no blocks left.
+    pub fn find_orphan_streams(&self) -> Vec<BlockType> {
+        self.streams
+            .iter_streams()
+            .filter(|block_type| {
+                self.iter_metadata(&|_nr, ty| ty == *block_type)
+                    .next()
+                    .is_none()
+            })
+            .collect()
+    }
+
+    /// Removes the head-idx of all streams without blocks.
+    pub fn clear_orphan_streams(&mut self) {
+        for block_type in self.find_orphan_streams() {
+            self.streams.remove_stream(block_type);
+        }
+    }
+
+    /// Frees all blocks of the stream and removes its head-idx.
+    /// The next append_stream() starts a new stream.
+    pub fn clear_stream(&mut self, block_type: BlockType) -> Result<(), Error> {
+        let block_nrs: Vec<_> = self
+            .iter_metadata(&|_nr, ty| ty == block_type)
+            .map(|(nr, _ty)| nr)
+            .collect();
+        for block_nr in block_nrs {
+            self.free_block(block_nr)?;
+        }
+        self.streams.remove_stream(block_type);
+        Ok(())
+    }
+
     /// Get a Reader that reads the contents of one BlockType in order.
     pub fn read_stream(
         &mut self,
         block_type: BlockType,
         block_align: usize,
     ) -> Result<impl BlockRead + '_, Error> {
+        self.read_stream_opt(block_type, block_align, false)
+    }
+
+    /// Get a Reader that reads the contents of one BlockType in order.
+    /// Reading past the end of the stream fails with UnexpectedEof.
+    pub fn read_stream_strict(
+        &mut self,
+        block_type: BlockType,
+        block_align: usize,
+    ) -> Result<impl BlockRead + '_, Error> {
+        self.read_stream_opt(block_type, block_align, true)
+    }
+
+    fn read_stream_opt(
+        &mut self,
+        block_type: BlockType,
+        block_align: usize,
+        strict: bool,
+    ) -> Result<BlockReader<'_>, Error> {
         let block_nrs: Vec<_> = self
             .iter_metadata(&|_nr, ty| ty == block_type)
             .map(|(nr, _ty)| nr)
@@ -535,15 +1859,25 @@ impl Alloc {
             block_nrs,
             block_idx: 0,
             read_head: 0,
+            strict,
         })
     }
 
     /// Get a Writer that writes to consecutive blocks of blocktype.
+    ///
+    /// Fails with MixedStreamUsage if there are blocks of this type, that were
+    /// not written as a stream. A single stored block that is still empty is
+    /// taken as the tail of a stream, older versions didn't register a stream
+    /// before something was written to it.
+    ///
+    /// Small writes are buffered and copied to the block by flush() or when
+    /// the writer is dropped. Drop can't report an error, call flush() to
+    /// see it.
     pub fn append_stream(
         &mut self,
         block_type: BlockType,
         block_align: usize,
-    ) -> Result<impl BlockWrite + Write + '_, Error> {
+    ) -> Result<impl BlockWrite + '_, Error> {
         let block_nr = self
             .iter_metadata(&|_nr, ty| ty == block_type)
             .rev()
@@ -551,11 +1885,21 @@ impl Alloc {
             .next();
 
         let block_nr = if let Some(block_nr) = block_nr {
+            if !self.streams.is_stream(block_type) {
+                if !self.is_empty_tail(block_type, block_nr, block_align)? {
+                    return Err(Error::err(FBErrorKind::MixedStreamUsage(block_type)));
+                }
+                self.set_stream_head_idx(block_type, 0)?;
+            }
+
             let block = self.block_mut(block_nr, block_align)?;
             block.set_dirty(true);
             block.set_discard(true);
             block_nr
         } else {
+            // register the stream.
+            self.set_stream_head_idx(block_type, 0)?;
+
             let block_nr = self.alloc_block(block_type, block_align)?;
             let block = self.block_mut(block_nr, block_align)?;
             block.set_dirty(true);
@@ -570,9 +1914,90 @@ impl Alloc {
             block_align,
             block_nr,
             write_head: head_idx,
+            flush_head: head_idx,
+            buffer: Vec::new(),
         })
     }
 
+    /// The block is the only one of its type, it has been stored and contains
+    /// only zeros.
+    fn is_empty_tail(
+        &mut self,
+        block_type: BlockType,
+        block_nr: LogicalNr,
+        block_align: usize,
+    ) -> Result<bool, Error> {
+        if self.iter_metadata(&|_nr, ty| ty == block_type).count() != 1
+            || self.physical.physical_nr(block_nr)? == 0
+        {
+            return Ok(false);
+        }
+        let block = self.block(block_nr, block_align)?;
+        Ok(!block.is_dirty() && block.data.iter().all(|v| *v == 0))
+    }
+
+    /// Get a Writer that starts with a fresh block. If the last block of
+    /// the stream is partially filled, its rest is zeroed and a new block is
+    /// allocated. The zeroed bytes stay part of the stream.
+    pub fn append_stream_aligned(
+        &mut self,
+        block_type: BlockType,
+        block_align: usize,
+    ) -> Result<impl BlockWrite + '_, Error> {
+        let last_nr = self
+            .iter_metadata(&|_nr, ty| ty == block_type)
+            .next_back()
+            .map(|(nr, _ty)| nr);
+        let head_idx = self.stream_head_idx(block_type);
+
+        if let Some(last_nr) = last_nr {
+            if self.streams.is_stream(block_type) && head_idx != 0 {
+                let block = self.block_mut(last_nr, block_align)?;
+                if block.data[head_idx..].iter().any(|v| *v != 0) {
+                    block.data[head_idx..].fill(0);
+                    block.set_dirty(true);
+                }
+
+                let block_nr = self.alloc_block(block_type, block_align)?;
+                self.block_mut(block_nr, block_align)?.set_dirty(true);
+                self.set_stream_head_idx(block_type, 0)?;
+            }
+        }
+
+        self.append_stream(block_type, block_align)
+    }
+
+    /// Current end of the stream.
+    pub fn stream_position(&self, block_type: BlockType) -> StreamPosition {
+        let block_nr = self
+            .iter_metadata(&|_nr, ty| ty == block_type)
+            .next_back()
+            .map(|(nr, _ty)| nr)
+            .unwrap_or(LogicalNr(0));
+        StreamPosition {
+            block_type,
+            block_nr,
+            head_idx: self.streams.head_idx(block_type),
+        }
+    }
+
+    /// Get a Writer that continues at the position.
+    ///
+    /// Fails with StreamPositionMismatch if the stream has been changed
+    /// since the position was taken.
+    pub fn append_stream_resume(
+        &mut self,
+        position: StreamPosition,
+        block_align: usize,
+    ) -> Result<impl BlockWrite + '_, Error> {
+        if self.stream_position(position.block_type) != position {
+            return Err(Error::err(FBErrorKind::StreamPositionMismatch(
+                position.block_type,
+            )));
+        }
+        self.append_stream(position.block_type, block_align)
+    }
+
     /// Get the block-type for a block-nr.
     pub fn block_type(&self, logical: LogicalNr) -> Result<BlockType, Error> {
         self.types.block_type(logical)
@@ -583,6 +2008,12 @@ impl Alloc {
     pub fn physical_nr(&self, logical: LogicalNr) -> Result<PhysicalNr, Error> {
         self.physical.physical_nr(logical)
     }
+
+    /// Get the physical blocks for count consecutive block-nrs.
+    /// Fails with the first block-nr that is out of range.
+    pub fn physical_range(&self, start: LogicalNr, count: u32) -> Result<Vec<PhysicalNr>, Error> {
+        self.physical.physical_range(start, count)
+    }
 }
 
 pub trait BlockWrite: Write {
@@ -590,6 +2021,20 @@ pub trait BlockWrite: Write {
     fn block_nr(&self) -> LogicalNr;
     // Current write idx.
     fn idx(&self) -> usize;
+    // Usable size of a block.
+    fn block_size(&self) -> usize;
+
+    // Bytes that can be written before the next block is started.
+    fn remaining_in_block(&self) -> usize {
+        self.block_size() - self.idx()
+    }
+
+    /// Writes the bytes of a record. It may span two blocks, check
+    /// remaining_in_block() first to keep it in one block.
+    #[cfg(feature = "bytemuck")]
+    fn write_record<T: bytemuck::Pod>(&mut self, value: &T) -> io::Result<()> {
+        self.write_all(bytemuck::bytes_of(value))
+    }
 }
 
 struct BlockWriter<'a> {
@@ -599,6 +2044,38 @@ struct BlockWriter<'a> {
 
     block_nr: LogicalNr,
     write_head: usize,
+
+    // written to the block up to here.
+    flush_head: usize,
+    // data between flush_head and write_head.
+    buffer: Vec<u8>,
+}
+
+impl<'a> BlockWriter<'a> {
+    // Copy the buffer to the block and persist the head-idx.
+    fn flush_buffer(&mut self) -> Result<(), Error> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let block = self.alloc.block_mut(self.block_nr, self.block_align)?;
+        block.data[self.flush_head..self.write_head].copy_from_slice(&self.buffer);
+        self.buffer.clear();
+        self.flush_head = self.write_head;
+
+        self.alloc
+            .streams
+            .set_head_idx(self.block_type, self.write_head)
+    }
+}
+
+impl<'a> Drop for BlockWriter<'a> {
+    fn drop(&mut self) {
+        // The block is in the cache, this shouldn't fail. flush() reports
+        // the error if it does.
+        let r = self.flush_buffer();
+        debug_assert!(r.is_ok());
+    }
 }
 
 impl<'a> BlockWrite for BlockWriter<'a> {
@@ -609,89 +2086,48 @@ impl<'a> BlockWrite for BlockWriter<'a> {
     fn idx(&self) -> usize {
         self.write_head
     }
+
+    fn block_size(&self) -> usize {
+        self.alloc.data_size()
+    }
 }
 
 impl<'a> Write for BlockWriter<'a> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        let block_size = self.alloc.block_size();
-        let block_align = self.block_align;
-        let block_type = self.block_type;
-
-        let mut block_nr = self.block_nr;
-        let mut write_head = self.write_head;
-
-        let n = if buf.len() == 0 {
-            // noop
-            0
-        } else if block_size - write_head >= buf.len() {
-            // easy fit
-            // block_nr = block_nr;
-
-            let block = self.alloc.block_mut(block_nr, block_align)?;
-            let part = &mut block.data[write_head..block_size];
-            part[0..buf.len()].copy_from_slice(buf);
-
-            write_head += buf.len();
-
-            buf.len()
-        } else if block_size - write_head > 0 {
-            // some space left
-            // block_nr = block_nr;
-
-            let block = self.alloc.block_mut(block_nr, block_align)?;
-            let part = &mut block.data[write_head..block_size];
-            part.copy_from_slice(&buf[0..part.len()]);
-
-            write_head += part.len();
-
-            part.len()
-        } else if block_size >= buf.len() {
-            self.alloc.discard_block(block_nr);
-
-            // allocate and write complete buffer.
-            block_nr = self.alloc.alloc_block(block_type, block_align)?;
-            // write_head = 0;
-
-            let block = self.alloc.block_mut(block_nr, block_align)?;
-            block.set_dirty(true);
-            block.set_discard(true);
-            let part = &mut block.data[0..buf.len()];
-            part.copy_from_slice(buf);
-
-            write_head = buf.len();
+        if buf.is_empty() {
+            // noop, don't touch the streams block.
+            return Ok(0);
+        }
 
-            buf.len()
-        } else if block_size < buf.len() {
-            self.alloc.discard_block(block_nr);
+        let block_size = self.alloc.data_size();
 
-            // allocate and write whole block
-            block_nr = self.alloc.alloc_block(block_type, block_align)?;
-            // write_head = 0;
+        if self.write_head == block_size {
+            // block is full, buffer was flushed with the last write.
+            self.alloc.discard_block(self.block_nr);
 
-            let block = self.alloc.block_mut(block_nr, block_align)?;
+            let block_nr = self.alloc.alloc_block(self.block_type, self.block_align)?;
+            let block = self.alloc.block_mut(block_nr, self.block_align)?;
             block.set_dirty(true);
             block.set_discard(true);
-            let part = block.data.as_mut();
-            part.copy_from_slice(&buf[0..block_size]);
 
-            write_head = block_size;
+            self.block_nr = block_nr;
+            self.write_head = 0;
+            self.flush_head = 0;
+        }
 
-            block_size
-        } else {
-            unreachable!()
-        };
+        let n = (block_size - self.write_head).min(buf.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        self.write_head += n;
 
-        // persist state
-        self.block_nr = block_nr;
-        self.write_head = write_head;
-        self.alloc
-            .streams
-            .set_head_idx(self.block_type, self.write_head)?;
+        if self.write_head == block_size {
+            self.flush_buffer()?;
+        }
 
         Ok(n)
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
+        self.flush_buffer()?;
         Ok(())
     }
 }
@@ -716,11 +2152,38 @@ pub trait BlockRead: Read {
             unreachable!()
         }
     }
+
+    /// Reads the stream as records of T. Ends with the stream, a partial
+    /// record at the end is an UnexpectedEof error.
+    #[cfg(feature = "bytemuck")]
+    fn records<T: bytemuck::Pod>(mut self) -> impl Iterator<Item = io::Result<T>>
+    where
+        Self: Sized,
+    {
+        let mut done = false;
+        std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            let mut record = T::zeroed();
+            match self.read_maybe(bytemuck::bytes_of_mut(&mut record)) {
+                Ok(true) => Some(Ok(record)),
+                Ok(false) => {
+                    done = true;
+                    None
+                }
+                Err(e) => {
+                    done = true;
+                    Some(Err(e))
+                }
+            }
+        })
+    }
 }
 
 impl<'a> BlockRead for BlockReader<'a> {
     fn block_nr(&self) -> LogicalNr {
-        if self.block_nrs.len() == 0 {
+        if self.block_nrs.is_empty() {
             LogicalNr(0)
         } else {
             self.block_nrs[self.block_idx]
@@ -741,16 +2204,32 @@ struct BlockReader<'a> {
     block_nrs: Vec<LogicalNr>,
     block_idx: usize,
     read_head: usize,
+
+    strict: bool,
+}
+
+impl<'a> BlockReader<'a> {
+    // EOF is ok or an error.
+    fn eof(&self) -> std::io::Result<usize> {
+        if self.strict {
+            Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "read past the end of the stream",
+            ))
+        } else {
+            Ok(0)
+        }
+    }
 }
 
 #[inline]
 fn max_read_size(
-    block_nrs: &Vec<LogicalNr>,
+    block_nrs: &[LogicalNr],
     block_idx: usize,
     head_idx: usize,
     block_size: usize,
 ) -> usize {
-    if block_nrs.len() == 0 {
+    if block_nrs.is_empty() {
         0
     } else if block_idx + 1 == block_nrs.len() {
         head_idx
@@ -761,7 +2240,12 @@ fn max_read_size(
 
 impl<'a> Read for BlockReader<'a> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let block_size = self.alloc.block_size();
+        if buf.is_empty() {
+            // noop, don't move to the next block.
+            return Ok(0);
+        }
+
+        let block_size = self.alloc.data_size();
         let block_align = self.block_align;
 
         let write_head = self.write_head;
@@ -773,7 +2257,7 @@ impl<'a> Read for BlockReader<'a> {
 
         let block = if logical_block_size == 0 {
             // no stream at all
-            return Ok(0);
+            return self.eof();
         } else if data_idx < logical_block_size {
             // current block
             self.alloc.block(block_nrs[block_idx], block_align)?
@@ -788,7 +2272,7 @@ impl<'a> Read for BlockReader<'a> {
         } else if data_idx == logical_block_size && block_idx + 1 == block_nrs.len() {
             // end of last
             self.alloc.discard_block(block_nrs[block_idx]);
-            return Ok(0);
+            return self.eof();
         } else {
             unreachable!()
         };
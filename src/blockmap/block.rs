@@ -1,9 +1,10 @@
 use crate::blockmap::BlockType;
-use crate::{user_type_string, LogicalNr, UserBlockType};
+use crate::{user_type_string, Error, FBErrorKind, LogicalNr, UserBlockType};
 use std::alloc::Layout;
 use std::fmt::{Debug, Formatter};
 use std::marker::PhantomData;
-use std::mem::{align_of, align_of_val, size_of};
+use std::mem::{align_of, size_of};
+use std::ops::{Deref, DerefMut};
 use std::{alloc, mem, ptr};
 
 /// Data for one block of the file.
@@ -12,8 +13,9 @@ pub struct Block {
     block_type: BlockType,
     dirty: bool,
     discard: bool,
+    scratch: bool,
     generation: u32,
-    /// Datablock
+    /// Datablock. Changes need a set_dirty(true), data_mut() does this.
     pub data: Box<[u8]>,
 }
 
@@ -47,6 +49,7 @@ impl Block {
             block_type,
             dirty: false,
             discard: false,
+            scratch: false,
             generation: 0,
             data: alloc_box_buffer(block_size, align),
         }
@@ -57,11 +60,21 @@ impl Block {
         self.block_nr
     }
 
+    /// Logical block-nr. Allows reusing the buffer.
+    pub(crate) fn set_block_nr(&mut self, block_nr: LogicalNr) {
+        self.block_nr = block_nr;
+    }
+
     /// Block-type.
     pub fn block_type(&self) -> BlockType {
         self.block_type
     }
 
+    /// Block-type.
+    pub(crate) fn set_block_type(&mut self, block_type: BlockType) {
+        self.block_type = block_type;
+    }
+
     /// Modified.
     pub fn is_dirty(&self) -> bool {
         self.dirty
@@ -82,6 +95,16 @@ impl Block {
         self.discard = discard
     }
 
+    /// Scratch block. Is never stored and dropped with the next store.
+    pub fn is_scratch(&self) -> bool {
+        self.scratch
+    }
+
+    /// Scratch block. Is never stored and dropped with the next store.
+    pub(crate) fn set_scratch(&mut self, scratch: bool) {
+        self.scratch = scratch;
+    }
+
     /// Generation when this last was stored.
     pub fn generation(&self) -> u32 {
         self.generation
@@ -95,7 +118,8 @@ impl Block {
     /// Align of the allocated block. The alignment given for construction is the *minimal*
     /// alignment, so this value can differ.
     pub fn block_align(&self) -> usize {
-        align_of_val(&self.data)
+        let addr = self.data.as_ptr() as usize;
+        1 << addr.trailing_zeros()
     }
 
     /// Block-size.
@@ -108,6 +132,39 @@ impl Block {
         self.data.fill(0);
     }
 
+    /// Block data for modification. Marks the block dirty.
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        self.dirty = true;
+        &mut self.data
+    }
+
+    /// Copies buf into the data at offset and marks the block dirty.
+    ///
+    /// Fails with OutOfBounds if this would write past the end of the block.
+    pub fn write_at(&mut self, offset: usize, buf: &[u8]) -> Result<(), Error> {
+        let range = self.range_at(offset, buf.len())?;
+        self.data[range].copy_from_slice(buf);
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Copies the data at offset into buf.
+    ///
+    /// Fails with OutOfBounds if this would read past the end of the block.
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<(), Error> {
+        let range = self.range_at(offset, buf.len())?;
+        buf.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    // Checked range of len bytes at offset.
+    fn range_at(&self, offset: usize, len: usize) -> Result<std::ops::Range<usize>, Error> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.block_size() => Ok(offset..end),
+            _ => Err(Error::err(FBErrorKind::OutOfBounds(offset, len))),
+        }
+    }
+
     // Verify size and alignment of T conforms with the buffer.
     fn verify_cast<T>(&self) {
         debug_assert!(size_of::<T>() <= self.block_size());
@@ -117,7 +174,7 @@ impl Block {
     /// Transmutes the buffer to a reference to T.
     /// Asserts that size and alignment match.
     ///
-    /// Safety
+    /// # Safety
     /// This is still unsafe as this fn can make no assumptions the underlying bit-pattern
     /// is valid for T.
     pub unsafe fn cast<T>(&self) -> &T {
@@ -128,7 +185,7 @@ impl Block {
     /// Transmutes the buffer to a reference to T.
     /// Asserts that size and alignment match.
     ///
-    /// Safety
+    /// # Safety
     /// This is still unsafe as this fn can make no assumptions the underlying bit-pattern
     /// is valid for T.
     pub unsafe fn cast_mut<T>(&mut self) -> &mut T {
@@ -136,6 +193,46 @@ impl Block {
         unsafe { mem::transmute(&mut self.data[0]) }
     }
 
+    /// Casts the start of the buffer to a reference to T.
+    /// Returns None if the size or alignment doesn't fit.
+    #[cfg(feature = "bytemuck")]
+    pub fn try_cast<T: bytemuck::Pod>(&self) -> Option<&T> {
+        let data = self.data.get(..size_of::<T>())?;
+        bytemuck::try_from_bytes(data).ok()
+    }
+
+    /// Casts the start of the buffer to a reference to T.
+    /// Returns None if the size or alignment doesn't fit.
+    #[cfg(feature = "bytemuck")]
+    pub fn try_cast_mut<T: bytemuck::Pod>(&mut self) -> Option<&mut T> {
+        let data = self.data.get_mut(..size_of::<T>())?;
+        bytemuck::try_from_bytes_mut(data).ok()
+    }
+
+    /// Casts the buffer to an array of T. Fills the buffer to capacity.
+    /// Returns None if the size or alignment doesn't fit.
+    #[cfg(feature = "bytemuck")]
+    pub fn try_cast_slice<T: bytemuck::Pod>(&self) -> Option<&[T]> {
+        if size_of::<T>() == 0 {
+            return None;
+        }
+        let len_array = Self::len_array::<T>(self.block_size());
+        let data = &self.data[..len_array * size_of::<T>()];
+        bytemuck::try_cast_slice(data).ok()
+    }
+
+    /// Casts the buffer to an array of T. Fills the buffer to capacity.
+    /// Returns None if the size or alignment doesn't fit.
+    #[cfg(feature = "bytemuck")]
+    pub fn try_cast_slice_mut<T: bytemuck::Pod>(&mut self) -> Option<&mut [T]> {
+        if size_of::<T>() == 0 {
+            return None;
+        }
+        let len_array = Self::len_array::<T>(self.block_size());
+        let data = &mut self.data[..len_array * size_of::<T>()];
+        bytemuck::try_cast_slice_mut(data).ok()
+    }
+
     /// Returns the length of an array of T that can be placed on top of the buffer.
     /// Fills the buffer as much as possible but might leave unused bytes at the end.
     pub fn len_array<T>(block_size: usize) -> usize {
@@ -151,7 +248,7 @@ impl Block {
 
     /// Transmutes the buffer to a array of T. Fills the buffer to capacity.
     ///
-    /// Safety
+    /// # Safety
     /// This is still unsafe as this fn can make no assumptions the underlying bit-pattern
     /// is valid for T.    
     pub unsafe fn cast_array<T>(&self) -> &[T] {
@@ -165,7 +262,7 @@ impl Block {
 
     /// Transmutes the buffer to a array of T. Fills the buffer to capacity.
     ///
-    /// Safety
+    /// # Safety
     /// This is still unsafe as this fn can make no assumptions the underlying bit-pattern
     /// is valid for T.    
     pub unsafe fn cast_array_mut<T>(&mut self) -> &mut [T] {
@@ -214,7 +311,7 @@ impl Block {
     /// There can be a gap in the layout between the header and the array to align the array correctly.
     /// There can be some leftover space at the end of the buffer.
     ///
-    /// Safety
+    /// # Safety
     /// This is still unsafe as this fn can make no assumptions the underlying bit-pattern
     /// is valid for H and T.    
     pub unsafe fn cast_header_array<H, T>(&self) -> HeaderArray<'_, H, T> {
@@ -225,7 +322,7 @@ impl Block {
 
             let (header, array) = self.data.split_at(offset_array);
 
-            let header = mem::transmute::<_, &H>(&header[0]);
+            let header = mem::transmute::<&u8, &H>(&header[0]);
             let array = &*ptr::slice_from_raw_parts(&array[0] as *const u8 as *const T, len_array);
 
             HeaderArray { header, array }
@@ -236,7 +333,7 @@ impl Block {
     /// There can be a gap in the layout between the header and the array to align the array correctly.
     /// There can be some leftover space at the end of the buffer.
     ///
-    /// Safety
+    /// # Safety
     /// This is still unsafe as this fn can make no assumptions the underlying bit-pattern
     /// is valid for H and T.    
     pub unsafe fn cast_header_array_mut<H, T>(&mut self) -> HeaderArrayMut<'_, H, T> {
@@ -247,19 +344,114 @@ impl Block {
 
             let (header, array) = self.data.split_at_mut(offset_array);
 
-            let header = mem::transmute::<_, &mut H>(&mut header[0]);
+            let header = mem::transmute::<&mut u8, &mut H>(&mut header[0]);
             let array =
                 &mut *ptr::slice_from_raw_parts_mut(&mut array[0] as *mut u8 as *mut T, len_array);
 
             HeaderArrayMut { header, array }
         }
     }
+
+    // Header H and the aligned start of the array fit in the buffer.
+    #[cfg(feature = "bytemuck")]
+    fn fits_header_array<H, T>(&self) -> bool {
+        size_of::<H>() > 0
+            && size_of::<T>() > 0
+            && size_of::<H>().next_multiple_of(align_of::<T>()) <= self.block_size()
+    }
+
+    /// Casts the buffer to a header H followed by an array of T, with the
+    /// same layout as cast_header_array().
+    /// Returns None if the size or alignment doesn't fit.
+    #[cfg(feature = "bytemuck")]
+    pub fn header_array<H: bytemuck::Pod, T: bytemuck::Pod>(
+        &self,
+    ) -> Option<HeaderArray<'_, H, T>> {
+        if !self.fits_header_array::<H, T>() {
+            return None;
+        }
+        let (offset_array, len_array) = Self::len_header_array::<H, T>(self.block_size());
+
+        let (header, array) = self.data.split_at(offset_array);
+        let header = bytemuck::try_from_bytes(&header[..size_of::<H>()]).ok()?;
+        let array = bytemuck::try_cast_slice(&array[..len_array * size_of::<T>()]).ok()?;
+
+        Some(HeaderArray { header, array })
+    }
+
+    /// Casts the buffer to a header H followed by an array of T, with the
+    /// same layout as cast_header_array_mut().
+    /// Returns None if the size or alignment doesn't fit.
+    #[cfg(feature = "bytemuck")]
+    pub fn header_array_mut<H: bytemuck::Pod, T: bytemuck::Pod>(
+        &mut self,
+    ) -> Option<HeaderArrayMut<'_, H, T>> {
+        if !self.fits_header_array::<H, T>() {
+            return None;
+        }
+        let (offset_array, len_array) = Self::len_header_array::<H, T>(self.block_size());
+
+        let (header, array) = self.data.split_at_mut(offset_array);
+        let header = bytemuck::try_from_bytes_mut(&mut header[..size_of::<H>()]).ok()?;
+        let array = bytemuck::try_cast_slice_mut(&mut array[..len_array * size_of::<T>()]).ok()?;
+
+        Some(HeaderArrayMut { header, array })
+    }
+}
+
+/// Mutable access to a block. Any mutable access through it marks the
+/// block dirty, so a change can't get lost by a missing set_dirty(true).
+pub struct BlockMut<'a>(&'a mut Block);
+
+impl<'a> BlockMut<'a> {
+    pub(crate) fn new(block: &'a mut Block) -> Self {
+        Self(block)
+    }
+
+    /// The block itself. Changes need the dirty flag set manually again.
+    pub fn into_inner(self) -> &'a mut Block {
+        self.0
+    }
+}
+
+impl Deref for BlockMut<'_> {
+    type Target = Block;
+
+    fn deref(&self) -> &Self::Target {
+        self.0
+    }
+}
+
+impl DerefMut for BlockMut<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0.set_dirty(true);
+        self.0
+    }
+}
+
+impl Debug for BlockMut<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&*self.0, f)
+    }
 }
 
 /// Combines a block with a user-defined BlockType.
 /// Used to produce readable debug-output.
 pub struct UserBlock<'a, U>(pub &'a Block, pub PhantomData<U>);
 
+impl AsRef<[u8]> for Block {
+    fn as_ref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// Same as data_mut(), marks the block dirty.
+impl AsMut<[u8]> for Block {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self.data_mut()
+    }
+}
+
 impl Debug for Block {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -288,6 +480,9 @@ where
         if self.0.discard {
             write!(f, " discard")?;
         }
+        if self.0.scratch {
+            write!(f, " scratch")?;
+        }
         if width >= 1 {
             struct RefBlock<'a>(&'a [u8]);
             impl<'a> Debug for RefBlock<'a> {
@@ -1,31 +1,49 @@
 use crate::blockmap::block::Block;
+use crate::blockmap::BlockStorage;
 use crate::FBErrorKind;
-use crate::{Error, PhysicalNr};
-use std::fs::{File, Metadata};
-use std::io::{Read, Seek, SeekFrom, Write};
+use crate::{Error, LogicalNr, PhysicalNr};
+use std::mem::size_of;
+
+/// Size of the checksum trailer.
+pub(crate) const CHECKSUM_LEN: usize = size_of::<u32>();
+
+/// Size of the block data. With checksums the trailer is cut off.
+pub(crate) fn data_size(block_size: usize, checksum: bool) -> usize {
+    if checksum {
+        block_size - CHECKSUM_LEN
+    } else {
+        block_size
+    }
+}
 
 /// Sync file storage.
-pub(crate) fn sync(file: &mut File) -> Result<(), Error> {
-    match file.sync_all() {
+pub(crate) fn sync(file: &mut dyn BlockStorage) -> Result<(), Error> {
+    match file.sync() {
         Ok(v) => Ok(v),
         Err(e) => Err(Error::err(FBErrorKind::Sync(e))),
     }
 }
 
-/// Metadata
-pub(crate) fn metadata(file: &mut File) -> Result<Metadata, Error> {
-    match file.metadata() {
+/// Length of the storage.
+pub(crate) fn len(file: &mut dyn BlockStorage) -> Result<u64, Error> {
+    match file.len() {
         Ok(v) => Ok(v),
         Err(e) => Err(Error::err(FBErrorKind::Metadata(e))),
     }
 }
 
+/// Truncate or extend the storage.
+pub(crate) fn set_len(file: &mut dyn BlockStorage, len: u64) -> Result<(), Error> {
+    match file.set_len(len) {
+        Ok(v) => Ok(v),
+        Err(e) => Err(Error::err(FBErrorKind::Truncate(e))),
+    }
+}
+
 /// Write block 0 to storage. This one requires special attention as we use 0 as a marker for
 /// "no physical block assigned" too.
-pub(crate) fn store_raw_0(file: &mut File, block: &Block) -> Result<(), Error> {
-    seek_block(file, PhysicalNr(0), block.block_size())?;
-
-    match file.write_all(block.data.as_ref()) {
+pub(crate) fn store_raw_0(file: &mut dyn BlockStorage, block: &Block) -> Result<(), Error> {
+    match file.write_at(0, block.data.as_ref()) {
         Ok(v) => Ok(v),
         Err(e) => Err(Error::err(FBErrorKind::StoreRaw(
             block.block_nr(),
@@ -35,35 +53,117 @@ pub(crate) fn store_raw_0(file: &mut File, block: &Block) -> Result<(), Error> {
     }
 }
 
-/// Write a block to storage.
+/// Default capacity of the write buffer.
+pub(crate) const DEFAULT_WRITE_BUFFER: usize = 64 * 1024;
+
+/// Collects writes of consecutive physical blocks and writes them to
+/// storage with a single call. Writing a block that doesn't follow the
+/// buffered ones or doesn't fit flushes the buffer first.
 ///
-/// Panic
-/// Panics if this tries to store block 0.
-pub(crate) fn store_raw(
-    file: &mut File,
-    physical_block: PhysicalNr,
-    block: &Block,
-) -> Result<(), Error> {
-    assert_ne!(physical_block, PhysicalNr(0));
+/// Any error discards the buffered blocks, which leaves the buffer empty.
+/// The failed store has to be repeated completely.
+#[derive(Debug)]
+pub(crate) struct WriteBuffer {
+    capacity: usize,
+    pos: u64,
+    buf: Vec<u8>,
+    // first block in the buffer, for errors.
+    first: (LogicalNr, PhysicalNr),
+}
+
+impl WriteBuffer {
+    /// Write buffer with a capacity in bytes. With 0 every block is
+    /// written on its own.
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            pos: 0,
+            buf: Vec::new(),
+            first: (LogicalNr(0), PhysicalNr(0)),
+        }
+    }
 
-    seek_block(file, physical_block, block.block_size())?;
+    /// Change the capacity. Must be flushed.
+    pub(crate) fn set_capacity(&mut self, capacity: usize) {
+        debug_assert!(self.buf.is_empty());
+        self.capacity = capacity;
+        self.buf = Vec::new();
+    }
 
-    match file.write_all(block.data.as_ref()) {
-        Ok(v) => Ok(v),
-        Err(e) => Err(Error::err(FBErrorKind::StoreRaw(
-            block.block_nr(),
-            physical_block,
-            e,
-        ))),
+    /// Write a block to storage. With checksum a crc32 of the data is
+    /// appended as trailer. The write may be delayed until flush().
+    ///
+    /// Fails with InvalidPhysicalZero if this tries to store block 0.
+    /// On error the buffer is discarded.
+    pub(crate) fn store(
+        &mut self,
+        file: &mut dyn BlockStorage,
+        physical_block: PhysicalNr,
+        block: &Block,
+        checksum: bool,
+    ) -> Result<(), Error> {
+        if physical_block == 0 {
+            self.clear();
+            return Err(Error::err(FBErrorKind::InvalidPhysicalZero(
+                block.block_nr(),
+            )));
+        }
+
+        let stride = block.block_size() + if checksum { CHECKSUM_LEN } else { 0 };
+        let pos = block_pos(physical_block, stride);
+
+        if !self.buf.is_empty()
+            && (pos != self.pos + self.buf.len() as u64 || self.buf.len() + stride > self.capacity)
+        {
+            self.flush(file)?;
+        }
+        if self.buf.is_empty() {
+            self.pos = pos;
+            self.first = (block.block_nr(), physical_block);
+        }
+
+        self.buf.extend_from_slice(block.data.as_ref());
+        if checksum {
+            let crc = crc32fast::hash(block.data.as_ref());
+            self.buf.extend_from_slice(&crc.to_le_bytes());
+        }
+
+        Ok(())
+    }
+
+    /// Write the buffered blocks. The buffer is empty afterwards, with
+    /// or without an error.
+    pub(crate) fn flush(&mut self, file: &mut dyn BlockStorage) -> Result<(), Error> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        let res = file.write_at(self.pos, &self.buf);
+        let (block_nr, physical_block) = self.first;
+        self.clear();
+
+        match res {
+            Ok(v) => Ok(v),
+            Err(e) => Err(Error::err(FBErrorKind::StoreRaw(
+                block_nr,
+                physical_block,
+                e,
+            ))),
+        }
+    }
+
+    /// Discard the buffered blocks.
+    pub(crate) fn clear(&mut self) {
+        self.buf.clear();
+        self.pos = 0;
+        self.first = (LogicalNr(0), PhysicalNr(0));
     }
 }
 
 /// Read the 0 block. This one requires special attention as we use 0 as a marker for
 /// "no physical block assigned" too.
-pub(crate) fn load_raw_0(file: &mut File, block: &mut Block) -> Result<(), Error> {
-    seek_block(file, PhysicalNr(0), block.block_size())?;
-
-    match file.read_exact(block.data.as_mut()) {
+pub(crate) fn load_raw_0(file: &mut dyn BlockStorage, block: &mut Block) -> Result<(), Error> {
+    match file.read_at(0, block.data.as_mut()) {
         Ok(v) => Ok(v),
         Err(e) => Err(Error::err(FBErrorKind::LoadRaw(
             block.block_nr(),
@@ -73,70 +173,90 @@ pub(crate) fn load_raw_0(file: &mut File, block: &mut Block) -> Result<(), Error
     }
 }
 
-/// Read a block from storage.
+/// Read a block from storage. With checksum the trailer is verified.
 ///
-/// Panic
-/// Panics if this tries to read block 0.
+/// Fails with InvalidPhysicalZero if this tries to read block 0.
 pub(crate) fn load_raw(
-    file: &mut File,
+    file: &mut dyn BlockStorage,
     physical_block: PhysicalNr,
     block: &mut Block,
+    checksum: bool,
 ) -> Result<(), Error> {
-    assert_ne!(physical_block, PhysicalNr(0));
+    if physical_block == 0 {
+        return Err(Error::err(FBErrorKind::InvalidPhysicalZero(
+            block.block_nr(),
+        )));
+    }
 
-    seek_block(file, physical_block, block.block_size())?;
+    let stride = block.block_size() + if checksum { CHECKSUM_LEN } else { 0 };
+    let pos = block_pos(physical_block, stride);
 
-    match file.read_exact(block.data.as_mut()) {
-        Ok(v) => Ok(v),
-        Err(e) => Err(Error::err(FBErrorKind::LoadRaw(
+    if let Err(e) = file.read_at(pos, block.data.as_mut()) {
+        return Err(Error::err(FBErrorKind::LoadRaw(
             block.block_nr(),
             physical_block,
             e,
-        ))),
+        )));
+    }
+
+    if checksum {
+        let mut crc = [0u8; CHECKSUM_LEN];
+        if let Err(e) = file.read_at(pos + block.block_size() as u64, &mut crc) {
+            return Err(Error::err(FBErrorKind::LoadRaw(
+                block.block_nr(),
+                physical_block,
+                e,
+            )));
+        }
+        if u32::from_le_bytes(crc) != crc32fast::hash(block.data.as_ref()) {
+            return Err(Error::err(FBErrorKind::ChecksumMismatch(
+                block.block_nr(),
+                physical_block,
+            )));
+        }
     }
+
+    Ok(())
 }
 
-/// Seek to the block_nr.
-fn seek_block(file: &mut File, physical_block: PhysicalNr, block_size: usize) -> Result<(), Error> {
-    let seek_pos = (physical_block.as_usize() * block_size) as u64;
+/// Position of the block.
+fn block_pos(physical_block: PhysicalNr, block_size: usize) -> u64 {
+    physical_block.as_u64() * block_size as u64
+}
 
-    let seeked_pos = match file.seek(SeekFrom::Start(seek_pos)) {
-        Ok(v) => v,
-        Err(e) => return Err(Error::err(FBErrorKind::SeekBlock(physical_block, e))),
-    };
+/// Read the complete storage.
+pub(crate) fn load_all_raw(file: &mut dyn BlockStorage) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![0u8; len(file)? as usize];
+    load_at(file, 0, &mut buf)?;
+    Ok(buf)
+}
 
-    if seek_pos != seeked_pos {
-        return Err(Error::err(FBErrorKind::SeekBlockOffset(
-            physical_block,
-            seeked_pos,
-        )));
+/// Read raw bytes at some position.
+pub(crate) fn load_at(file: &mut dyn BlockStorage, pos: u64, buf: &mut [u8]) -> Result<(), Error> {
+    match file.read_at(pos, buf) {
+        Ok(v) => Ok(v),
+        Err(e) => Err(Error::err(FBErrorKind::LoadRaw(
+            LogicalNr(0),
+            PhysicalNr(0),
+            e,
+        ))),
     }
-    Ok(())
 }
 
 /// Write part of block 0 to storage.
 ///
-/// Panic
-/// Panics if this would write outside of a block.
+/// Fails with OutOfBounds if this would write outside of the block.
 pub(crate) fn sub_store_raw_0(
-    file: &mut File,
+    file: &mut dyn BlockStorage,
     block_size: usize,
     offset: usize,
     block: &[u8],
 ) -> Result<(), Error> {
-    debug_assert!((offset + block.len()) <= block_size);
-    let seeked_pos = match file.seek(SeekFrom::Start(offset as u64)) {
-        Ok(v) => v,
-        Err(e) => return Err(Error::err(FBErrorKind::SubSeekBlock(PhysicalNr(0), e))),
-    };
-    if seeked_pos != offset as u64 {
-        return Err(Error::err(FBErrorKind::SubSeekBlockOffset(
-            PhysicalNr(0),
-            seeked_pos,
-        )));
+    if offset + block.len() > block_size {
+        return Err(Error::err(FBErrorKind::OutOfBounds(offset, block.len())));
     }
 
-    match file.write_all(block) {
+    match file.write_at(offset as u64, block) {
         Ok(v) => Ok(v),
         Err(e) => Err(Error::err(FBErrorKind::SubStoreRaw(PhysicalNr(0), e))),
     }
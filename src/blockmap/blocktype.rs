@@ -43,6 +43,16 @@ pub enum BlockType {
     User16 = 31,
 }
 
+impl BlockType {
+    /// Block-types of the internal structures.
+    pub fn is_internal(self) -> bool {
+        matches!(
+            self,
+            BlockType::Header | BlockType::Types | BlockType::Physical | BlockType::Streams
+        )
+    }
+}
+
 impl UserBlockType for BlockType {
     fn block_type(self) -> BlockType {
         self
@@ -1,9 +1,9 @@
 use crate::blockmap::block::Block;
+use crate::blockmap::BlockStorage;
 use crate::blockmap::{block_io, BlockType, _INIT_HEADER_NR};
-use crate::{Error, LogicalNr, PhysicalNr};
+use crate::{Error, FBErrorKind, LogicalNr, PhysicalNr};
 use std::fmt::{Debug, Formatter};
-use std::fs::File;
-use std::mem::align_of;
+use std::mem::{align_of, offset_of, size_of};
 
 /// File-header.
 ///
@@ -24,24 +24,56 @@ pub enum State {
 #[repr(C)]
 #[derive(Debug)]
 struct BlockMapHeader {
+    // offsets with u32 / u64 physical block-nrs.
     state: State,        //0
     block_size: u32,     //4
     low: PhysicalPages,  //8
-    high: PhysicalPages, //20
+    high: PhysicalPages, //20 / 32
+    open: u32,           //32 / 56
+    magic: [u8; 4],      //36 / 60
+    stores: u64,         //40 / 64
+    flags: u32,          //48 / 72
+    format_version: u16, //52 / 76
+    low_sequence: u64,   //56 / 80
+    high_sequence: u64,  //64 / 88
 }
 
-const OFFSET_STATE: usize = 0;
-const OFFSET_LOW: usize = 8;
-const OFFSET_HIGH: usize = 20;
-const OFFSET_END: usize = 32;
+/// Blocks are written with a checksum trailer.
+const FLAG_CHECKSUM: u32 = 1;
+
+/// Identifies a block-file. Files written before this was added have 0 here.
+const MAGIC: [u8; 4] = *b"BKF2";
+/// Current format version. Written with a new file.
+const FORMAT_VERSION: u16 = 1;
+/// Oldest format version load() accepts.
+const MIN_FORMAT_VERSION: u16 = 1;
+
+// Offsets differ with the feature physical64.
+const OFFSET_STATE: usize = offset_of!(BlockMapHeader, state);
+const OFFSET_LOW: usize = offset_of!(BlockMapHeader, low);
+const OFFSET_HIGH: usize = offset_of!(BlockMapHeader, high);
+const OFFSET_OPEN: usize = offset_of!(BlockMapHeader, open);
+const OFFSET_STORES: usize = offset_of!(BlockMapHeader, stores);
+const OFFSET_END: usize = OFFSET_STORES + size_of::<u64>();
+const OFFSET_LOW_SEQUENCE: usize = offset_of!(BlockMapHeader, low_sequence);
+const OFFSET_HIGH_SEQUENCE: usize = offset_of!(BlockMapHeader, high_sequence);
+// the rest of the block is free for the user.
+const OFFSET_USER_META: usize = size_of::<BlockMapHeader>();
+
+/// Size of the header data. The header block must hold at least this.
+///
+/// This grew to 72 (96 with physical64) with the sequence counters. Files
+/// with a smaller block-size can't be loaded any longer.
+pub(super) const HEADER_LEN: usize = size_of::<BlockMapHeader>();
 
 /// Part of the header data.
 #[repr(C)]
 #[derive(Debug)]
 struct PhysicalPages {
+    // offsets with u32 / u64 physical block-nrs.
     types: PhysicalNr,    //0
-    physical: PhysicalNr, //4
-    streams: PhysicalNr,  //8
+    physical: PhysicalNr, //4 / 8
+    streams: PhysicalNr,  //8 / 16
 }
 
 impl HeaderBlock {
@@ -65,6 +97,13 @@ impl HeaderBlock {
         header_0.high.types = PhysicalNr(0);
         header_0.high.physical = PhysicalNr(0);
         header_0.high.streams = PhysicalNr(0);
+        header_0.open = 0;
+        header_0.magic = MAGIC;
+        header_0.stores = 0;
+        header_0.flags = 0;
+        header_0.format_version = FORMAT_VERSION;
+        header_0.low_sequence = 0;
+        header_0.high_sequence = 0;
 
         Self(block_0)
     }
@@ -86,7 +125,11 @@ impl HeaderBlock {
 
     /// Set the state independent of the rest of the data.
     /// Needs a sync afterwards to make this atomic.
-    pub(super) fn store_state(&mut self, file: &mut File, state: State) -> Result<(), Error> {
+    pub(super) fn store_state(
+        &mut self,
+        file: &mut dyn BlockStorage,
+        state: State,
+    ) -> Result<(), Error> {
         let state_bytes = (state as u32).to_ne_bytes();
         block_io::sub_store_raw_0(
             file,
@@ -98,6 +141,11 @@ impl HeaderBlock {
         Ok(())
     }
 
+    /// Set the state in memory only. Written with the initial header.
+    pub(super) fn set_state(&mut self, state: State) {
+        self.data_mut().state = state;
+    }
+
     /// Current state.
     pub fn state(&self) -> State {
         self.data().state
@@ -106,15 +154,17 @@ impl HeaderBlock {
     /// Stores the physical block for the first type-map.
     pub(super) fn store_low(
         &mut self,
-        file: &mut File,
+        file: &mut dyn BlockStorage,
         types: PhysicalNr,
         physical: PhysicalNr,
         streams: PhysicalNr,
+        sequence: u64,
     ) -> Result<(), Error> {
         let data = self.data_mut();
         data.low.types = types;
         data.low.physical = physical;
         data.low.streams = streams;
+        data.low_sequence = sequence;
 
         block_io::sub_store_raw_0(
             file,
@@ -122,6 +172,12 @@ impl HeaderBlock {
             OFFSET_LOW,
             &self.0.data[OFFSET_LOW..OFFSET_HIGH],
         )?;
+        block_io::sub_store_raw_0(
+            file,
+            self.0.block_size(),
+            OFFSET_LOW_SEQUENCE,
+            &self.0.data[OFFSET_LOW_SEQUENCE..OFFSET_LOW_SEQUENCE + size_of::<u64>()],
+        )?;
         Ok(())
     }
 
@@ -143,21 +199,29 @@ impl HeaderBlock {
     /// Stores the physical block for the first type-map.
     pub(super) fn store_high(
         &mut self,
-        file: &mut File,
+        file: &mut dyn BlockStorage,
         types: PhysicalNr,
         physical: PhysicalNr,
         streams: PhysicalNr,
+        sequence: u64,
     ) -> Result<(), Error> {
         let data = self.data_mut();
         data.high.types = types;
         data.high.physical = physical;
         data.high.streams = streams;
+        data.high_sequence = sequence;
 
         block_io::sub_store_raw_0(
             file,
             self.0.block_size(),
             OFFSET_HIGH,
-            &self.0.data[OFFSET_HIGH..OFFSET_END],
+            &self.0.data[OFFSET_HIGH..OFFSET_OPEN],
+        )?;
+        block_io::sub_store_raw_0(
+            file,
+            self.0.block_size(),
+            OFFSET_HIGH_SEQUENCE,
+            &self.0.data[OFFSET_HIGH_SEQUENCE..OFFSET_HIGH_SEQUENCE + size_of::<u64>()],
         )?;
         Ok(())
     }
@@ -177,11 +241,163 @@ impl HeaderBlock {
         self.data().high.streams
     }
 
+    /// Set the process-open marker independent of the rest of the data.
+    pub(super) fn store_open(
+        &mut self,
+        file: &mut dyn BlockStorage,
+        open: bool,
+    ) -> Result<(), Error> {
+        self.set_open(open);
+        block_io::sub_store_raw_0(
+            file,
+            self.0.block_size(),
+            OFFSET_OPEN,
+            &self.0.data[OFFSET_OPEN..OFFSET_OPEN + size_of::<u32>()],
+        )?;
+        Ok(())
+    }
+
+    /// Set the process-open marker in memory only.
+    pub(super) fn set_open(&mut self, open: bool) {
+        self.data_mut().open = open as u32;
+    }
+
+    /// Process-open marker. Is set while some FileBlocks has the file open and
+    /// cleared when it is dropped. This is advisory only, a crash leaves the
+    /// marker set.
+    pub fn is_open(&self) -> bool {
+        self.data().open != 0
+    }
+
+    /// Set the counter of all stores independent of the rest of the data.
+    pub(super) fn store_total_stores(
+        &mut self,
+        file: &mut dyn BlockStorage,
+        stores: u64,
+    ) -> Result<(), Error> {
+        self.data_mut().stores = stores;
+        block_io::sub_store_raw_0(
+            file,
+            self.0.block_size(),
+            OFFSET_STORES,
+            &self.0.data[OFFSET_STORES..OFFSET_END],
+        )?;
+        Ok(())
+    }
+
+    /// Number of stores since the file was created. This only ever increments
+    /// and is kept across sessions.
+    pub fn total_stores(&self) -> u64 {
+        self.data().stores
+    }
+
+    /// Set the sequence counter of both states in memory only.
+    pub(super) fn set_sequence(&mut self, sequence: u64) {
+        let data = self.data_mut();
+        data.low_sequence = sequence;
+        data.high_sequence = sequence;
+    }
+
+    /// Sequence counter of the current state. Each store writes the
+    /// counter to the new state.
+    pub fn sequence(&self) -> u64 {
+        match self.state() {
+            State::Low => self.data().low_sequence,
+            State::High => self.data().high_sequence,
+        }
+    }
+
+    /// Blocks are written with a checksum. This is set when the file is
+    /// created and never changes.
+    pub fn is_checksummed(&self) -> bool {
+        self.data().flags & FLAG_CHECKSUM != 0
+    }
+
+    /// Set the checksum flag in memory only. Written with the initial header.
+    pub(super) fn set_checksummed(&mut self, checksum: bool) {
+        if checksum {
+            self.data_mut().flags |= FLAG_CHECKSUM;
+        } else {
+            self.data_mut().flags &= !FLAG_CHECKSUM;
+        }
+    }
+
+    /// Magic number. All 0 for files that predate it.
+    pub fn magic(&self) -> [u8; 4] {
+        self.data().magic
+    }
+
+    /// Format version. Files without a magic number are assumed to have
+    /// the current version.
+    pub fn format_version(&self) -> u16 {
+        if self.data().magic == [0; 4] {
+            FORMAT_VERSION
+        } else {
+            self.data().format_version
+        }
+    }
+
+    /// Written with the magic number and the current format version.
+    pub fn is_current_format(&self) -> bool {
+        self.data().magic == MAGIC && self.data().format_version == FORMAT_VERSION
+    }
+
+    /// Checks the magic number and the format version after load.
+    pub(super) fn verify_magic(&self) -> Result<(), Error> {
+        let magic = self.data().magic;
+        if magic != MAGIC && magic != [0; 4] {
+            return Err(Error::err(FBErrorKind::BadMagic));
+        }
+        let format_version = self.format_version();
+        if !(MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&format_version) {
+            return Err(Error::err(FBErrorKind::UnsupportedFormatVersion(
+                format_version,
+            )));
+        }
+        Ok(())
+    }
+
     /// Stored block-size.
     pub fn stored_block_size(&self) -> usize {
         self.data().block_size as usize
     }
 
+    /// Application data in the leftover space of the header block.
+    /// This is everything after the header data up to the block-size.
+    pub fn user_meta(&self) -> &[u8] {
+        &self.0.data[OFFSET_USER_META..]
+    }
+
+    /// Set the application data in the header block. The rest of the
+    /// space is filled with 0. Written with the next store, but not part of
+    /// the copy-on-write state.
+    ///
+    /// Fails with DataTooLarge if it doesn't fit.
+    pub fn set_user_meta(&mut self, meta: &[u8]) -> Result<(), Error> {
+        let user_meta = &mut self.0.data[OFFSET_USER_META..];
+        if meta.len() > user_meta.len() {
+            return Err(Error::err(FBErrorKind::DataTooLarge(meta.len())));
+        }
+        user_meta[..meta.len()].copy_from_slice(meta);
+        user_meta[meta.len()..].fill(0);
+        self.0.set_dirty(true);
+        Ok(())
+    }
+
+    /// Write the application data if it changed.
+    pub(super) fn store_user_meta(&mut self, file: &mut dyn BlockStorage) -> Result<(), Error> {
+        if self.0.is_dirty() {
+            block_io::sub_store_raw_0(
+                file,
+                self.0.block_size(),
+                OFFSET_USER_META,
+                &self.0.data[OFFSET_USER_META..],
+            )?;
+            self.0.set_dirty(false);
+        }
+        Ok(())
+    }
+
     /// View over the block-data.
     fn data_mut(&mut self) -> &mut BlockMapHeader {
         unsafe { self.0.cast_mut() }
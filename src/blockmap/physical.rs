@@ -1,11 +1,12 @@
 use crate::blockmap::block::{Block, HeaderArray, HeaderArrayMut};
+use crate::blockmap::block_io::CHECKSUM_LEN;
+use crate::blockmap::BlockStorage;
 use crate::blockmap::{block_io, BlockType, _INIT_PHYSICAL_NR};
-use crate::{Error, FBErrorKind, LogicalNr, PhysicalNr};
+use crate::{Error, FBErrorKind, LogicalNr, PhysicalNr, PhysicalRepr};
 use bit_set::BitSet;
 use std::cmp::max;
 use std::collections::HashMap;
 use std::fmt::{Debug, Formatter};
-use std::fs::File;
 use std::mem::{align_of, size_of};
 
 /// Maps logical->physical block.
@@ -26,6 +27,11 @@ pub(crate) struct Physical {
     blocks: Vec<PhysicalBlock>,
     max: PhysicalNr,
     free: Vec<PhysicalNr>,
+    // number of blocks in the file with the last init_free_list.
+    file_blocks: usize,
+    checksum: bool,
+    #[cfg(debug_assertions)]
+    check_double_assign: bool,
 }
 
 /// Wrapper around a block.
@@ -46,7 +52,9 @@ type PhysicalDataMut<'a> = HeaderArrayMut<'a, PhysicalHeader, PhysicalNr>;
 
 impl Physical {
     /// Init new map.
-    pub fn init(block_size: usize) -> Self {
+    ///
+    /// The block-size is the size of the block-data, without a checksum.
+    pub fn init(block_size: usize, checksum: bool) -> Self {
         let mut block_0 = PhysicalBlock::init(block_size);
         block_0.set_dirty(true);
 
@@ -55,6 +63,10 @@ impl Physical {
             blocks: vec![block_0],
             max: PhysicalNr(0),
             free: Vec::default(),
+            file_blocks: 0,
+            checksum,
+            #[cfg(debug_assertions)]
+            check_double_assign: true,
         };
 
         new_self.init_free_list(0);
@@ -63,9 +75,14 @@ impl Physical {
     }
 
     /// Load from file.
-    pub fn load(file: &mut File, block_size: usize, block_pnr: PhysicalNr) -> Result<Self, Error> {
+    pub fn load(
+        file: &mut dyn BlockStorage,
+        block_size: usize,
+        checksum: bool,
+        block_pnr: PhysicalNr,
+    ) -> Result<Self, Error> {
         let mut start_block = PhysicalBlock::new(_INIT_PHYSICAL_NR, block_size);
-        block_io::load_raw(file, block_pnr, &mut start_block.0)?;
+        block_io::load_raw(file, block_pnr, &mut start_block.0, checksum)?;
 
         let mut next = start_block.next_nr();
 
@@ -74,6 +91,10 @@ impl Physical {
             blocks: vec![start_block],
             max: PhysicalNr(0),
             free: vec![],
+            file_blocks: 0,
+            checksum,
+            #[cfg(debug_assertions)]
+            check_double_assign: true,
         };
 
         loop {
@@ -83,21 +104,57 @@ impl Physical {
 
             let next_pnr = new_self.physical_nr(next)?;
             let mut block = PhysicalBlock::new(next, block_size);
-            block_io::load_raw(file, next_pnr, &mut block.0)?;
+            block_io::load_raw(file, next_pnr, &mut block.0, checksum)?;
+
+            // the sequence must advance, a broken chain could loop forever.
+            let end_nr = new_self.end_nr();
+            if block.start_nr() != end_nr {
+                return Err(Error::err(FBErrorKind::InvalidBlockSequence(
+                    block.block_nr(),
+                    block.start_nr(),
+                )));
+            }
 
             next = block.next_nr();
 
             new_self.blocks.push(block);
         }
 
-        let file_size = block_io::metadata(file)?.len();
+        let file_size = block_io::len(file)?;
         new_self.init_free_list(file_size);
         new_self.verify()?;
 
         Ok(new_self)
     }
 
-    fn verify(&self) -> Result<(), Error> {
+    /// Copy of the structure of the map without any physical blocks assigned.
+    /// All blocks are dirty.
+    pub fn copy_empty(&self) -> Self {
+        let blocks = self
+            .blocks
+            .iter()
+            .map(|v| {
+                let mut block = PhysicalBlock::new(v.block_nr(), self.block_size);
+                block.set_start_nr(v.start_nr());
+                block.set_next_nr(v.next_nr());
+                block.set_dirty(true);
+                block
+            })
+            .collect();
+
+        Self {
+            block_size: self.block_size,
+            blocks,
+            max: PhysicalNr(0),
+            free: Vec::default(),
+            file_blocks: 0,
+            checksum: self.checksum,
+            #[cfg(debug_assertions)]
+            check_double_assign: self.check_double_assign,
+        }
+    }
+
+    pub fn verify(&self) -> Result<(), Error> {
         let mut assigned_pnr = HashMap::new();
 
         let mut start_nr = LogicalNr(0);
@@ -123,31 +180,44 @@ impl Physical {
         Ok(())
     }
 
-    /// Rebuild the free-list.
+    /// Rebuild the free-list. New blocks are appended after the end of the file.
     pub fn init_free_list(&mut self, file_size: u64) {
         self.free.clear();
 
+        let stride = self.block_size + if self.checksum { CHECKSUM_LEN } else { 0 };
+        self.file_blocks = file_size as usize / stride;
+
+        // build bitset of used blocks. only blocks inside the file matter.
         let mut used_pnr = BitSet::new();
+        used_pnr.insert(0); // 0 is reserved
         for physical_block in &self.blocks {
-            // build bitset of used blocks.
-            used_pnr.insert(0); // 0 is reserved
             for (_nr, pnr) in physical_block.iter_nr() {
-                if pnr != 0 {
+                if pnr != 0 && pnr.as_u64() < self.file_blocks as u64 {
                     used_pnr.insert(pnr.as_usize());
                 }
             }
         }
 
         // find free blocks.
-        let mut i = file_size as usize / self.block_size;
+        let mut i = self.file_blocks;
         while i > 0 {
             i -= 1;
             if !used_pnr.contains(i) {
-                self.free.push(PhysicalNr(i as u32));
-            } else {
-                self.max = max(self.max, PhysicalNr(i as u32));
+                self.free.push(PhysicalNr(i as PhysicalRepr));
             }
         }
+        let last = PhysicalNr(self.file_blocks.saturating_sub(1) as PhysicalRepr);
+        self.max = max(last, self.max_used());
+    }
+
+    /// Highest physical block assigned to any block-nr.
+    pub fn max_used(&self) -> PhysicalNr {
+        self.blocks
+            .iter()
+            .flat_map(|v| v.iter_nr())
+            .map(|(_nr, pnr)| pnr)
+            .max()
+            .unwrap_or(PhysicalNr(0))
     }
 
     /// Give back a free physical block.
@@ -160,25 +230,89 @@ impl Physical {
         }
     }
 
+    /// Takes count consecutive physical blocks off the free-list and
+    /// returns the first. Extends the file if no free extent is large enough.
+    pub fn pop_free_extent(&mut self, count: u32) -> PhysicalNr {
+        let start = match self.free_extents().into_iter().find(|v| v.1 >= count) {
+            Some((start, _)) => start,
+            None => {
+                let start = self.max + 1;
+                self.max += count;
+                start
+            }
+        };
+        self.free.retain(|v| *v < start || *v >= start + count);
+        start
+    }
+
+    /// Length of the free-list.
+    pub fn free_len(&self) -> usize {
+        self.free.len()
+    }
+
+    /// Number of blocks in the file when the free-list was built.
+    pub fn file_blocks(&self) -> usize {
+        self.file_blocks
+    }
+
+    /// Is the physical block on the free-list.
+    pub fn is_free(&self, pnr: PhysicalNr) -> bool {
+        self.free.contains(&pnr)
+    }
+
+    /// Runs of consecutive free physical blocks as (start, len).
+    pub fn free_extents(&self) -> Vec<(PhysicalNr, u32)> {
+        let mut free = self.free.clone();
+        free.sort();
+
+        let mut extents: Vec<(PhysicalNr, u32)> = Vec::new();
+        for pnr in free {
+            match extents.last_mut() {
+                Some((start, len)) if *start + *len == pnr => *len += 1,
+                _ => extents.push((pnr, 1)),
+            }
+        }
+        extents
+    }
+
+    /// For testing only. Switches the check for double assigned physical blocks
+    /// in set_physical_nr. This check scans the whole map for every call.
+    #[cfg(debug_assertions)]
+    pub fn set_check_double_assign(&mut self, check: bool) {
+        self.check_double_assign = check;
+    }
+
+    /// For testing only. The check for double assigned physical blocks is on.
+    #[cfg(debug_assertions)]
+    pub fn is_check_double_assign(&self) -> bool {
+        self.check_double_assign
+    }
+
     /// Set the physical block.
     pub fn set_physical_nr(
         &mut self,
         block_nr: LogicalNr,
         block_pnr: PhysicalNr,
     ) -> Result<(), Error> {
-        debug_assert!({
-            'll: {
-                for block in &self.blocks {
-                    for (nr, pnr) in block.iter_nr() {
-                        if block_pnr == pnr {
-                            eprintln!("pnr {} used for block-nr {}", pnr, nr);
-                            break 'll false;
+        #[cfg(debug_assertions)]
+        if self.check_double_assign {
+            debug_assert!({
+                'll: {
+                    if block_pnr == 0 {
+                        break 'll true;
+                    }
+                    for block in &self.blocks {
+                        for (nr, pnr) in block.iter_nr() {
+                            if block_pnr == pnr {
+                                eprintln!("pnr {} used for block-nr {}", pnr, nr);
+                                break 'll false;
+                            }
                         }
                     }
+                    true
                 }
-                true
-            }
-        });
+            });
+        }
 
         let Some(map) = self.map_mut(block_nr) else {
             return Err(Error::err(FBErrorKind::InvalidBlock(block_nr)));
@@ -187,6 +321,21 @@ impl Physical {
         map.set_physical_nr(block_nr, block_pnr)
     }
 
+    /// For testing only. Sets the physical block without the check for
+    /// double assigned blocks.
+    #[cfg(debug_assertions)]
+    pub fn set_physical_nr_unchecked(
+        &mut self,
+        block_nr: LogicalNr,
+        block_pnr: PhysicalNr,
+    ) -> Result<(), Error> {
+        let Some(map) = self.map_mut(block_nr) else {
+            return Err(Error::err(FBErrorKind::InvalidBlock(block_nr)));
+        };
+
+        map.set_physical_nr(block_nr, block_pnr)
+    }
+
     /// Find the physical block.
     pub fn physical_nr(&self, block_nr: LogicalNr) -> Result<PhysicalNr, Error> {
         let Some(map) = self.map(block_nr) else {
@@ -195,6 +344,34 @@ impl Physical {
         map.physical_nr(block_nr)
     }
 
+    /// Find the physical blocks for a range of block-nrs.
+    pub fn physical_range(&self, start: LogicalNr, count: u32) -> Result<Vec<PhysicalNr>, Error> {
+        let Some(end) = start.0.checked_add(count).map(LogicalNr) else {
+            return Err(Error::err(FBErrorKind::InvalidBlock(start)));
+        };
+        // fail before allocating for the whole range.
+        if end > self.end_nr() {
+            return Err(Error::err(FBErrorKind::InvalidBlock(max(
+                start,
+                self.end_nr(),
+            ))));
+        }
+
+        let mut range = Vec::with_capacity(count as usize);
+        let mut block_nr = start;
+        while block_nr < end {
+            let Some(map) = self.map(block_nr) else {
+                return Err(Error::err(FBErrorKind::InvalidBlock(block_nr)));
+            };
+            while block_nr < end && map.contains(block_nr) {
+                range.push(map.physical_nr(block_nr)?);
+                block_nr += 1;
+            }
+        }
+
+        Ok(range)
+    }
+
     /// Add a new blockmap and links it to the last one.
     pub fn append_blockmap(&mut self, next_nr: LogicalNr) -> Result<(), Error> {
         let Some(last_block) = self.blocks.last_mut() else {
@@ -212,6 +389,11 @@ impl Physical {
         Ok(())
     }
 
+    /// End of the mapped block-nrs. Exclusive.
+    pub fn end_nr(&self) -> LogicalNr {
+        self.blocks.last().expect("last").end_nr()
+    }
+
     /// Get the blockmap with this block-nr.
     pub fn blockmap_mut(&mut self, block_nr: LogicalNr) -> Result<&mut PhysicalBlock, Error> {
         let find = self.blocks.iter_mut().find(|v| v.block_nr() == block_nr);
@@ -277,7 +459,12 @@ impl Physical {
 impl PhysicalBlock {
     /// Init default.
     pub(super) fn init(block_size: usize) -> Self {
-        let block_0 = Block::new(_INIT_PHYSICAL_NR, block_size, 4, BlockType::Physical);
+        let block_0 = Block::new(
+            _INIT_PHYSICAL_NR,
+            block_size,
+            align_of::<PhysicalNr>(),
+            BlockType::Physical,
+        );
         Self(block_0)
     }
 
@@ -497,7 +684,7 @@ impl Debug for PhysicalBlock {
 
         s.field(
             "physical",
-            &RefPhysical(&self.data().array, self.start_nr().as_usize()),
+            &RefPhysical(self.data().array, self.start_nr().as_usize()),
         );
         s.finish()?;
         Ok(())
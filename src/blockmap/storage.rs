@@ -0,0 +1,205 @@
+use std::fmt::Debug;
+use std::fs::File;
+use std::io;
+use std::io::{Cursor, Read, Write};
+#[cfg(not(any(unix, windows)))]
+use std::io::{Seek, SeekFrom};
+use std::panic::{RefUnwindSafe, UnwindSafe};
+
+/// Storage backend for the blocks.
+///
+/// Implemented for File and for an in-memory `Cursor<Vec<u8>>`.
+/// The auto-trait bounds keep Alloc Send, Sync and unwind-safe.
+pub trait BlockStorage: Debug + Send + Sync + UnwindSafe + RefUnwindSafe {
+    /// Read exactly buf.len() bytes at the offset.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    /// Write all of buf at the offset. Extends the storage if necessary.
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()>;
+
+    /// Make all writes durable.
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Current length in bytes.
+    fn len(&mut self) -> io::Result<u64>;
+
+    /// Storage is empty.
+    fn is_empty(&mut self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Truncate or extend the storage to this length.
+    /// The default fails with ErrorKind::Unsupported.
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        _ = len;
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+}
+
+/// Uses positioned IO where available, which saves the separate seek.
+impl BlockStorage for File {
+    #[cfg(unix)]
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        std::os::unix::fs::FileExt::read_exact_at(self, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_at(&mut self, mut offset: u64, mut buf: &mut [u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match std::os::windows::fs::FileExt::seek_read(self, buf, offset) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.seek(SeekFrom::Start(offset))?;
+        self.read_exact(buf)
+    }
+
+    #[cfg(unix)]
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        std::os::unix::fs::FileExt::write_all_at(self, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn write_at(&mut self, mut offset: u64, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match std::os::windows::fs::FileExt::seek_write(self, buf, offset) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(n) => {
+                    buf = &buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        self.seek(SeekFrom::Start(offset))?;
+        self.write_all(buf)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+
+    fn len(&mut self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+}
+
+impl BlockStorage for Cursor<Vec<u8>> {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.set_position(offset);
+        self.read_exact(buf)
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        self.set_position(offset);
+        self.write_all(buf)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn len(&mut self) -> io::Result<u64> {
+        Ok(self.get_ref().len() as u64)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.get_mut().resize(len as usize, 0);
+        Ok(())
+    }
+}
+
+/// File storage that serves reads from a memory map of the file.
+///
+/// This only saves the read syscall for each block. It is not zero-copy,
+/// every read still copies the bytes from the map into the block buffer,
+/// and no block borrows from the map. Writes go to the file as usual, the
+/// map is renewed when a read goes past its end.
+#[cfg(feature = "mmap")]
+#[derive(Debug)]
+pub struct MmapStorage {
+    file: File,
+    map: Option<memmap2::Mmap>,
+}
+
+#[cfg(feature = "mmap")]
+impl MmapStorage {
+    /// Storage for the file. The map is created with the first read.
+    ///
+    /// # Safety
+    /// The map aliases the file contents. Modifying or truncating the file
+    /// outside of this storage while it exists is undefined behaviour and
+    /// may crash the process. Only use it for files nobody else writes.
+    pub unsafe fn new(file: File) -> Self {
+        Self { file, map: None }
+    }
+
+    /// Drop the map and return the file.
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+
+    fn remap(&mut self) -> io::Result<()> {
+        self.map = None;
+        if self.file.metadata()?.len() > 0 {
+            // Safety: see new().
+            self.map = Some(unsafe { memmap2::Mmap::map(&self.file)? });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl BlockStorage for MmapStorage {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let end = offset.saturating_add(buf.len() as u64);
+        if self.map.as_ref().map(|v| v.len() as u64).unwrap_or(0) < end {
+            self.remap()?;
+        }
+        let Some(map) = self.map.as_ref().filter(|v| v.len() as u64 >= end) else {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        };
+        // copied, the blocks own their data.
+        buf.copy_from_slice(&map[offset as usize..end as usize]);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        BlockStorage::write_at(&mut self.file, offset, buf)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    fn len(&mut self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        // the map must not reach past the end of the file.
+        self.map = None;
+        self.file.set_len(len)
+    }
+}
@@ -1,11 +1,21 @@
-use crate::blockmap::_INIT_STREAM_NR;
-use crate::{user_type_string, Block, BlockType, Error, FBErrorKind, LogicalNr, UserBlockType};
+use crate::blockmap::physical::Physical;
+use crate::blockmap::types::Types;
+use crate::blockmap::{block_io, BlockStorage, _INIT_STREAM_NR};
+use crate::{
+    user_type_string, Block, BlockType, Error, FBErrorKind, LogicalNr, PhysicalNr, UserBlockType,
+};
+use std::collections::BTreeSet;
 use std::fmt::{Debug, Formatter};
 use std::marker::PhantomData;
 use std::mem::align_of;
 
 /// Contains the end-idx into the last block of a data-stream.
-pub struct StreamsBlock(pub(crate) Block);
+///
+/// Starts with a single block. When it fills up further blocks are chained,
+/// the last entry of a full block then links to the next one.
+pub struct StreamsBlock {
+    blocks: Vec<Block>,
+}
 
 #[repr(C)]
 #[derive(Debug)]
@@ -17,59 +27,177 @@ struct StreamIdx {
 impl StreamsBlock {
     /// Init
     pub(super) fn init(block_size: usize) -> Self {
-        let block = Block::new(
-            _INIT_STREAM_NR,
-            block_size,
-            align_of::<StreamIdx>(),
-            BlockType::Streams,
-        );
-        Self(block)
+        Self {
+            blocks: vec![Self::new_block(_INIT_STREAM_NR, block_size)],
+        }
     }
 
-    /// New for load.
-    pub(super) fn new(block_size: usize) -> Self {
-        let block = Block::new(
-            _INIT_STREAM_NR,
+    /// Load the streams block and all chained blocks.
+    pub(super) fn load(
+        file: &mut dyn BlockStorage,
+        physical: &Physical,
+        types: &Types,
+        block_size: usize,
+        checksum: bool,
+        physical_block: PhysicalNr,
+    ) -> Result<Self, Error> {
+        let mut block = Self::new_block(_INIT_STREAM_NR, block_size);
+        block_io::load_raw(file, physical_block, &mut block, checksum)?;
+
+        let mut new_self = Self { blocks: Vec::new() };
+        let mut seen = BTreeSet::new();
+        seen.insert(_INIT_STREAM_NR);
+        let mut next = Self::link(&block);
+        new_self.blocks.push(block);
+
+        while let Some(next_nr) = next {
+            // a broken chain could loop forever.
+            if !seen.insert(next_nr) {
+                let last_nr = new_self.blocks.last().expect("block").block_nr();
+                return Err(Error::err(FBErrorKind::InvalidBlockSequence(
+                    last_nr, next_nr,
+                )));
+            }
+            let block_type = types.block_type(next_nr)?;
+            if block_type != BlockType::Streams {
+                return Err(Error::err(FBErrorKind::InvalidBlockType(
+                    next_nr, block_type,
+                )));
+            }
+
+            let next_p = physical.physical_nr(next_nr)?;
+            let mut block = Self::new_block(next_nr, block_size);
+            block_io::load_raw(file, next_p, &mut block, checksum)?;
+
+            next = Self::link(&block);
+            new_self.blocks.push(block);
+        }
+
+        new_self.compact();
+        Ok(new_self)
+    }
+
+    /// Copy of the streams blocks. The copy is dirty.
+    pub(super) fn copy(&self) -> Self {
+        let blocks = self
+            .blocks
+            .iter()
+            .map(|v| {
+                let mut block = Self::new_block(v.block_nr(), v.block_size());
+                block.data.copy_from_slice(&v.data);
+                block.set_dirty(true);
+                block
+            })
+            .collect();
+        Self { blocks }
+    }
+
+    fn new_block(block_nr: LogicalNr, block_size: usize) -> Block {
+        Block::new(
+            block_nr,
             block_size,
             align_of::<StreamIdx>(),
             BlockType::Streams,
-        );
-        Self(block)
+        )
+    }
+
+    /// Next block of the chain.
+    fn link(block: &Block) -> Option<LogicalNr> {
+        let data: &[StreamIdx] = unsafe { block.cast_array() };
+        let last = &data[data.len() - 1];
+        if last.block_type == BlockType::Streams {
+            Some(LogicalNr(last.idx))
+        } else {
+            None
+        }
+    }
+
+    /// Appends a block to the chain.
+    pub(super) fn append_block(&mut self, block_nr: LogicalNr) {
+        let entries = self.entries();
+
+        let block_size = self.blocks[0].block_size();
+        let last = self.blocks.last_mut().expect("block");
+        let data: &mut [StreamIdx] = unsafe { last.cast_array_mut() };
+        let link = data.len() - 1;
+        data[link].block_type = BlockType::Streams;
+        data[link].idx = block_nr.as_u32();
+        last.set_dirty(true);
+
+        self.blocks.push(Self::new_block(block_nr, block_size));
+        self.store_entries(&entries);
     }
 
     /// Block-nr.
     pub fn block_nr(&self) -> LogicalNr {
-        self.0.block_nr()
+        self.blocks[0].block_nr()
+    }
+
+    /// Block-nrs of all blocks of the chain.
+    pub fn iter_block_nrs(&self) -> impl Iterator<Item = LogicalNr> + '_ {
+        self.blocks.iter().map(|v| v.block_nr())
+    }
+
+    /// First block of the chain.
+    pub(super) fn root(&self) -> &Block {
+        &self.blocks[0]
+    }
+
+    /// Iterate all blocks.
+    pub(super) fn iter_blocks(&self) -> impl Iterator<Item = &Block> {
+        self.blocks.iter()
+    }
+
+    /// Iterate the dirty blocks.
+    pub(super) fn iter_dirty_mut(&mut self) -> impl Iterator<Item = &mut Block> {
+        self.blocks.iter_mut().filter(|v| v.is_dirty())
     }
 
     /// Dirty.
     pub fn is_dirty(&self) -> bool {
-        self.0.is_dirty()
+        self.blocks.iter().any(|v| v.is_dirty())
     }
 
     /// Dirty.
     pub fn set_dirty(&mut self, dirty: bool) {
-        self.0.set_dirty(dirty)
+        for block in &mut self.blocks {
+            block.set_dirty(dirty);
+        }
+    }
+
+    /// Generation when this last was stored. This is the latest generation
+    /// of all blocks of the chain.
+    pub fn generation(&self) -> u32 {
+        self.blocks
+            .iter()
+            .map(|v| v.generation())
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Number of streams that can be registered without chaining
+    /// another block.
+    pub fn capacity(&self) -> usize {
+        let per_block = Block::len_array::<StreamIdx>(self.blocks[0].block_size());
+        self.blocks.len() * (per_block - 1) + 1
     }
 
     /// Set the head-idx for a stream.
     /// idx into the last block of the stream-data.
+    ///
+    /// Fails with MaxStreams if this is a new stream and there is no
+    /// capacity left.
     pub fn set_head_idx(&mut self, block_type: BlockType, idx: usize) -> Result<(), Error> {
-        self.0.set_dirty(true);
-
-        let data = self.data_mut();
-        for i in 0..data.len() {
-            if data[i].block_type == block_type {
-                data[i].idx = idx as u32;
-                return Ok(());
-            } else if data[i].block_type == BlockType::Free {
-                data[i].block_type = block_type;
-                data[i].idx = idx as u32;
-                return Ok(());
-            }
+        let mut entries = self.entries();
+        if let Some(v) = entries.iter_mut().find(|v| v.0 == block_type) {
+            v.1 = idx as u32;
+        } else if entries.len() < self.capacity() {
+            entries.push((block_type, idx as u32));
+        } else {
+            return Err(Error::err(FBErrorKind::MaxStreams(self.capacity())));
         }
-
-        return Err(Error::err(FBErrorKind::MaxStreams(data.len())));
+        self.store_entries(&entries);
+        Ok(())
     }
 
     /// Returns the stored last position of the stream as a index into the last
@@ -77,26 +205,100 @@ impl StreamsBlock {
     ///
     /// Returns 0 if no current position is stored.
     pub fn head_idx(&self, block_type: BlockType) -> usize {
-        let data = self.data();
-        for i in 0..data.len() {
-            if data[i].block_type == block_type {
-                return data[i].idx as usize;
-            } else if data[i].block_type == BlockType::Free {
-                break;
-            }
-        }
+        self.iter()
+            .find(|v| v.0 == block_type)
+            .map(|v| v.1)
+            .unwrap_or(0)
+    }
 
-        return 0;
+    /// Is there an entry for this stream.
+    pub fn is_stream(&self, block_type: BlockType) -> bool {
+        self.iter_streams().any(|v| v == block_type)
     }
 
-    /// View over the block-data.
-    fn data_mut(&mut self) -> &mut [StreamIdx] {
-        unsafe { self.0.cast_array_mut() }
+    /// Removes the entry for a stream. The following entries move up, the
+    /// list stays terminated by a Free entry.
+    pub fn remove_stream(&mut self, block_type: BlockType) {
+        let mut entries = self.entries();
+        let Some(pos) = entries.iter().position(|v| v.0 == block_type) else {
+            return;
+        };
+        entries.remove(pos);
+        self.store_entries(&entries);
     }
 
-    /// View over the block-data.
-    fn data(&self) -> &[StreamIdx] {
-        unsafe { self.0.cast_array() }
+    /// Removes gaps between the entries. All lookups stop at the first Free
+    /// entry, so anything behind a gap would be invisible.
+    ///
+    /// Returns true if any entry was moved.
+    pub fn compact(&mut self) -> bool {
+        let entries: Vec<_> = self
+            .slots()
+            .filter(|v| v.block_type != BlockType::Free)
+            .map(|v| (v.block_type, v.idx))
+            .collect();
+        self.store_entries(&entries)
+    }
+
+    /// Iterate the block-types of all registered streams.
+    pub fn iter_streams(&self) -> impl Iterator<Item = BlockType> + '_ {
+        self.iter().map(|v| v.0)
+    }
+
+    /// Iterate all registered streams with their head-idx.
+    pub fn iter(&self) -> impl Iterator<Item = (BlockType, usize)> + '_ {
+        self.slots()
+            .take_while(|v| v.block_type != BlockType::Free)
+            .map(|v| (v.block_type, v.idx as usize))
+    }
+
+    /// Registered streams.
+    fn entries(&self) -> Vec<(BlockType, u32)> {
+        self.iter().map(|(ty, idx)| (ty, idx as u32)).collect()
+    }
+
+    /// Writes the entries to the blocks, the rest is filled with Free.
+    /// Only blocks that change are marked dirty.
+    ///
+    /// Returns true if any block changed.
+    fn store_entries(&mut self, entries: &[(BlockType, u32)]) -> bool {
+        debug_assert!(entries.len() <= self.capacity());
+
+        let mut entries = entries.iter();
+        let mut changed = false;
+        let last = self.blocks.len() - 1;
+        for (i, block) in self.blocks.iter_mut().enumerate() {
+            let data: &mut [StreamIdx] = unsafe { block.cast_array_mut() };
+            let len = if i < last { data.len() - 1 } else { data.len() };
+
+            let mut block_changed = false;
+            for v in data[..len].iter_mut() {
+                let (block_type, idx) = entries.next().copied().unwrap_or((BlockType::Free, 0));
+                if v.block_type != block_type || v.idx != idx {
+                    v.block_type = block_type;
+                    v.idx = idx;
+                    block_changed = true;
+                }
+            }
+            if block_changed {
+                block.set_dirty(true);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// All entries except the links between the blocks.
+    fn slots(&self) -> impl Iterator<Item = &StreamIdx> {
+        let last = self.blocks.len() - 1;
+        self.blocks.iter().enumerate().flat_map(move |(i, block)| {
+            let data: &[StreamIdx] = unsafe { block.cast_array() };
+            if i < last {
+                &data[..data.len() - 1]
+            } else {
+                data
+            }
+        })
     }
 }
 
@@ -115,11 +317,14 @@ where
 {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let mut s = f.debug_struct("StreamsBlock");
-        s.field("0", &self.0 .0);
-        s.field("streams", &RefStreams::<U>(self.0.data(), PhantomData::<U>));
+        s.field("blocks", &self.0.blocks);
+        s.field(
+            "streams",
+            &RefStreams::<U>(self.0.slots().collect(), PhantomData::<U>),
+        );
         s.finish()?;
 
-        struct RefStreams<'a, U>(&'a [StreamIdx], PhantomData<U>);
+        struct RefStreams<'a, U>(Vec<&'a StreamIdx>, PhantomData<U>);
         impl<'a, U> Debug for RefStreams<'a, U>
         where
             U: UserBlockType + Debug,
@@ -1,11 +1,11 @@
 use crate::blockmap::block::{Block, HeaderArray, HeaderArrayMut};
 use crate::blockmap::physical::Physical;
+use crate::blockmap::BlockStorage;
 use crate::blockmap::{
     block_io, BlockType, _INIT_HEADER_NR, _INIT_PHYSICAL_NR, _INIT_STREAM_NR, _INIT_TYPES_NR,
 };
 use crate::{user_type_string, Error, FBErrorKind, LogicalNr, PhysicalNr, UserBlockType};
 use std::fmt::{Debug, Formatter};
-use std::fs::File;
 use std::marker::PhantomData;
 use std::mem::size_of;
 
@@ -48,13 +48,14 @@ impl Types {
 
     /// Load from file
     pub fn load(
-        file: &mut File,
+        file: &mut dyn BlockStorage,
         physical: &Physical,
         block_size: usize,
+        checksum: bool,
         physical_block: PhysicalNr,
     ) -> Result<Self, Error> {
         let mut start_block = TypesBlock::new(_INIT_TYPES_NR, block_size);
-        block_io::load_raw(file, physical_block, &mut start_block.0)?;
+        block_io::load_raw(file, physical_block, &mut start_block.0, checksum)?;
 
         let mut next = start_block.next_nr();
 
@@ -71,7 +72,7 @@ impl Types {
 
             let next_p = physical.physical_nr(next)?;
             let mut block = TypesBlock::new(next, block_size);
-            block_io::load_raw(file, next_p, &mut block.0)?;
+            block_io::load_raw(file, next_p, &mut block.0, checksum)?;
 
             next = block.next_nr();
 
@@ -84,7 +85,31 @@ impl Types {
         Ok(new_self)
     }
 
-    fn verify(&self) -> Result<(), Error> {
+    /// Copy of the type-map with all blocks dirty.
+    pub fn copy(&self) -> Self {
+        let blocks = self
+            .blocks
+            .iter()
+            .map(|v| {
+                let mut block = TypesBlock::new(v.block_nr(), self.block_size);
+                block.0.data.copy_from_slice(&v.0.data);
+                block.set_dirty(true);
+                block
+            })
+            .collect();
+
+        let mut new_self = Self {
+            block_size: self.block_size,
+            blocks,
+            free: Vec::default(),
+        };
+
+        new_self.init_free_list();
+
+        new_self
+    }
+
+    pub fn verify(&self) -> Result<(), Error> {
         let mut start_nr = LogicalNr(0);
         for block in &self.blocks {
             if start_nr != block.start_nr() {
@@ -98,7 +123,7 @@ impl Types {
             let data = unsafe { block.0.cast_header_array::<TypesHeader, u32>() };
             for v in data.array {
                 BlockType::try_from(*v)
-                    .or_else(|v| Err(Error::err(FBErrorKind::IllegalBlockType(v))))?;
+                    .map_err(|v| Error::err(FBErrorKind::IllegalBlockType(v)))?;
             }
         }
 
@@ -121,6 +146,13 @@ impl Types {
         self.free.len()
     }
 
+    /// Free block-nrs in ascending order.
+    pub fn iter_free(&self) -> impl Iterator<Item = LogicalNr> {
+        let mut free = self.free.clone();
+        free.sort();
+        free.into_iter()
+    }
+
     /// Get a free block from the currently adressable.
     pub fn pop_free(&mut self) -> Option<LogicalNr> {
         self.free.pop()
@@ -175,6 +207,11 @@ impl Types {
         self.free = free;
     }
 
+    /// End of the addressable block-nrs. Exclusive.
+    pub fn end_nr(&self) -> LogicalNr {
+        self.blocks.last().expect("last").end_nr()
+    }
+
     /// Returns the block-map with the given block-nr.
     pub fn blockmap_mut(&mut self, block_nr: LogicalNr) -> Result<&mut TypesBlock, Error> {
         let find = self.blocks.iter_mut().find(|v| v.block_nr() == block_nr);
@@ -229,7 +266,7 @@ impl Types {
     pub fn iter_block_type<F>(
         &self,
         filter: &F,
-    ) -> impl Iterator<Item = (LogicalNr, BlockType)> + DoubleEndedIterator
+    ) -> impl DoubleEndedIterator<Item = (LogicalNr, BlockType)>
     where
         F: Fn(LogicalNr, BlockType) -> bool,
     {
@@ -377,9 +414,7 @@ impl TypesBlock {
     }
 
     /// Iterate LogicalNr+BlockType for this part of the block-map.
-    pub fn iter_block_type(
-        &self,
-    ) -> impl Iterator<Item = (LogicalNr, BlockType)> + DoubleEndedIterator + '_ {
+    pub fn iter_block_type(&self) -> impl DoubleEndedIterator<Item = (LogicalNr, BlockType)> + '_ {
         struct NrIter<'a> {
             idx: usize,
             idx_end: usize,
@@ -1,29 +1,61 @@
 use crate::blockmap::types::UserTypes;
 use crate::blockmap::{block_io, Alloc, UserStreamsBlock};
+use crate::slab::SlabLayout;
+#[cfg(feature = "mmap")]
+use crate::MmapStorage;
 use crate::{
-    Block, BlockRead, BlockType, BlockWrite, Error, FBErrorKind, HeaderBlock, LogicalNr,
-    PhysicalBlock, State, StreamsBlock, TypesBlock, UserBlockType,
+    Block, BlockMut, BlockRead, BlockStats, BlockStorage, BlockType, BlockWrite, Error,
+    FBErrorKind, HeaderBlock, LogicalNr, MapGenerations, PhysicalBlock, PhysicalNr, SlabRef, State,
+    StreamPosition, StreamsBlock, TypesBlock, UserBlockType,
 };
 use std::cell::Cell;
+use std::collections::BTreeSet;
 use std::fmt::{Debug, Formatter};
-use std::fs::{File, OpenOptions};
-use std::io::Write;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Cursor;
 use std::marker::PhantomData;
-use std::path::Path;
+use std::mem::align_of;
+use std::path::{Path, PathBuf};
 
 /// Manages a file split in equal-sized blocks.
 ///
 /// Blocks can be allocated for a specific blocktype.
-/// The minimum block-size is 24 bytes, but something bigger is advisable.
+/// The minimum block-size is checked with Alloc::check_block_size(),
+/// it must hold the header and a few map entries. Something much bigger
+/// is advisable. Since the header has the sequence counter this is at least
+/// 72 bytes (96 with physical64), older files with a smaller block-size
+/// can't be loaded.
 ///
 /// The strategy for fail-safety is copy-on-write. Each logical block is mapped to a physical
 /// block and this mapping is updated for every safe. Unchanged blocks are ignored of course.
 /// This way every store can be seen as atomic.
 pub struct FileBlocks<U> {
     alloc: Alloc,
+    path: PathBuf,
+    read_only: bool,
+    drop_policy: DropPolicy,
     _phantom: PhantomData<(U, Cell<()>)>,
 }
 
+/// Behaviour when a FileBlocks with unsaved changes is dropped.
+///
+/// Drop can't report any errors, so the default is to not store.
+/// Except with Ignore, drop writes at most one message to stderr. This
+/// includes a failure to clear the open marker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DropPolicy {
+    /// Silently lose all changes.
+    #[default]
+    Ignore,
+    /// Write a warning to stderr.
+    WarnLog,
+    /// Panic in debug mode, a warning otherwise.
+    Panic,
+    /// Try to store. Errors are written to stderr.
+    Store,
+}
+
 /// FileBlocks without user block-type mapping.
 pub type BasicFileBlocks = FileBlocks<BlockType>;
 
@@ -33,23 +65,68 @@ where
 {
     /// Init new block-file.
     pub fn create(path: &Path, block_size: usize) -> Result<Self, Error> {
-        let Ok(file) = File::create(path) else {
+        Self::create_opt(path, block_size, false)
+    }
+
+    /// Init new block-file that stores a crc32 checksum with every block.
+    /// The checksum is verified with each load and fails with ChecksumMismatch.
+    /// It takes 4 bytes of each block, see data_size().
+    ///
+    /// The setting is stored in the file, load() picks it up.
+    pub fn create_with_checksums(path: &Path, block_size: usize) -> Result<Self, Error> {
+        Self::create_opt(path, block_size, true)
+    }
+
+    fn create_opt(path: &Path, block_size: usize, checksum: bool) -> Result<Self, Error> {
+        Alloc::check_block_size(block_size, checksum)?;
+
+        let Ok(file) = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(path)
+        else {
             return Err(Error::err(FBErrorKind::Create));
         };
 
+        let mut alloc = if checksum {
+            Alloc::init_with_checksums(file, block_size)
+        } else {
+            Alloc::init(file, block_size)
+        };
+        alloc.set_open_marker(true)?;
+
         Ok(Self {
-            alloc: Alloc::init(file, block_size),
+            alloc,
+            path: path.to_path_buf(),
+            read_only: false,
+            drop_policy: Default::default(),
             _phantom: Default::default(),
         })
     }
 
     /// Opens a block-file. Initializes a new one if necessary.
-    /// Minimum block-size is 24.
+    /// Fails with InvalidBlockSize if the block-size is too small.
+    ///
+    /// Fails with PossiblyInUse if the process-open marker is set.
+    /// This is advisory only and no replacement for file locking.
     pub fn load(path: &Path, block_size: usize) -> Result<Self, Error> {
-        assert!(block_size >= 24);
+        Self::load_opt(path, block_size, false)
+    }
+
+    /// Opens a block-file. Initializes a new one if necessary.
+    /// Ignores the process-open marker. Use this after a crash.
+    pub fn load_force(path: &Path, block_size: usize) -> Result<Self, Error> {
+        Self::load_opt(path, block_size, true)
+    }
+
+    fn load_opt(path: &Path, block_size: usize, force: bool) -> Result<Self, Error> {
+        Alloc::check_block_size(block_size, false)?;
 
-        let Ok(mut file) = OpenOptions::new()
+        let Ok(file) = OpenOptions::new()
             .create(true)
+            .truncate(false)
             .read(true)
             .write(true)
             .open(path)
@@ -57,18 +134,226 @@ where
             return Err(Error::err(FBErrorKind::Open));
         };
 
-        let alloc = if block_io::metadata(&mut file)?.len() == 0 {
+        Self::load_storage(file, path, block_size, force)
+    }
+
+    /// Opens a block-file and reads it through a memory map. Initializes
+    /// a new one if necessary. Otherwise the same as load().
+    ///
+    /// Loaded blocks are still copied out of the map, this only saves the
+    /// read syscalls.
+    ///
+    /// # Safety
+    /// The file must not be modified by anyone else while it is open,
+    /// see MmapStorage::new().
+    #[cfg(feature = "mmap")]
+    pub unsafe fn load_mmap(path: &Path, block_size: usize) -> Result<Self, Error> {
+        Alloc::check_block_size(block_size, false)?;
+
+        let Ok(file) = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(path)
+        else {
+            return Err(Error::err(FBErrorKind::Open));
+        };
+        // Safety: passed on to the caller.
+        let file = unsafe { MmapStorage::new(file) };
+
+        Self::load_storage(file, path, block_size, false)
+    }
+
+    fn load_storage<S: BlockStorage + 'static>(
+        mut file: S,
+        path: &Path,
+        block_size: usize,
+        force: bool,
+    ) -> Result<Self, Error> {
+        let mut alloc = if block_io::len(&mut file)? == 0 {
             Alloc::init(file, block_size)
         } else {
             Alloc::load(file, block_size)?
         };
+        if !force && alloc.header().is_open() {
+            return Err(Error::err(FBErrorKind::PossiblyInUse));
+        }
+        alloc.set_open_marker(true)?;
+
+        Ok(Self {
+            alloc,
+            path: path.to_path_buf(),
+            read_only: false,
+            drop_policy: Default::default(),
+            _phantom: Default::default(),
+        })
+    }
+
+    /// Init a new block-file that lives in memory only.
+    /// Uses the same store and load as the file version, and
+    /// into_bytes() + load_in_memory() allow for a round-trip.
+    /// The path is empty.
+    pub fn in_memory(block_size: usize) -> Result<Self, Error> {
+        Alloc::check_block_size(block_size, false)?;
+
+        let mut alloc = Alloc::init(Cursor::new(Vec::new()), block_size);
+        alloc.set_open_marker(true)?;
+
+        Ok(Self {
+            alloc,
+            path: PathBuf::new(),
+            read_only: false,
+            drop_policy: Default::default(),
+            _phantom: Default::default(),
+        })
+    }
+
+    /// Opens a block-file from the bytes of into_bytes(). Lives in memory only.
+    ///
+    /// Fails with PossiblyInUse if the process-open marker is set.
+    pub fn load_in_memory(data: Vec<u8>, block_size: usize) -> Result<Self, Error> {
+        Alloc::check_block_size(block_size, false)?;
+
+        let mut alloc = Alloc::load(Cursor::new(data), block_size)?;
+        if alloc.header().is_open() {
+            return Err(Error::err(FBErrorKind::PossiblyInUse));
+        }
+        alloc.set_open_marker(true)?;
+
+        Ok(Self {
+            alloc,
+            path: PathBuf::new(),
+            read_only: false,
+            drop_policy: Default::default(),
+            _phantom: Default::default(),
+        })
+    }
+
+    /// Clears the process-open marker and returns the raw contents.
+    /// Only the stored state is contained, unsaved changes are lost.
+    pub fn into_bytes(mut self) -> Result<Vec<u8>, Error> {
+        if !self.read_only {
+            self.alloc.set_open_marker(false)?;
+        }
+        self.alloc.storage_bytes()
+    }
+
+    /// Opens a block-file and upgrades it to the current format if it
+    /// was written with an older one. The upgrade is a compact rewrite,
+    /// the original is copied to path.bak first. A file with the current
+    /// format is just opened.
+    pub fn migrate_format(path: &Path, block_size: usize) -> Result<Self, Error> {
+        let mut fb = Self::load(path, block_size)?;
+        if fb.header().is_current_format() {
+            return Ok(fb);
+        }
+
+        // backup without the open-marker.
+        fb.alloc.set_open_marker(false)?;
+        let mut bak_path = path.as_os_str().to_owned();
+        bak_path.push(".bak");
+        if let Err(e) = fs::copy(path, PathBuf::from(bak_path)) {
+            return Err(Error::err(FBErrorKind::Backup(e)));
+        }
+
+        // the original must be closed before it is replaced.
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".migrate");
+        let tmp_path = PathBuf::from(tmp_path);
+        fb.compact_to(&tmp_path)?;
+        drop(fb);
+        if fs::rename(&tmp_path, path).is_err() {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(Error::err(FBErrorKind::Create));
+        }
+
+        Self::load(path, block_size)
+    }
+
+    /// Opens an existing block-file for reading only. The process-open
+    /// marker is neither checked nor set. Store fails with ReadOnly.
+    pub fn load_read_only(path: &Path, block_size: usize) -> Result<Self, Error> {
+        Alloc::check_block_size(block_size, false)?;
+
+        let Ok(file) = OpenOptions::new().read(true).open(path) else {
+            return Err(Error::err(FBErrorKind::Open));
+        };
+        let alloc = Alloc::load(file, block_size)?;
 
         Ok(Self {
             alloc,
+            path: path.to_path_buf(),
+            read_only: true,
+            drop_policy: Default::default(),
             _phantom: Default::default(),
         })
     }
 
+    /// Opens the file again with write access. Does nothing if the file
+    /// is not read-only. Sets the process-open marker.
+    ///
+    /// Fails with PossiblyInUse if the process-open marker is set,
+    /// the same as load().
+    pub fn reopen_writable(&mut self) -> Result<(), Error> {
+        self.reopen_writable_opt(false)
+    }
+
+    /// Opens the file again with write access. Ignores the process-open
+    /// marker, the same as load_force().
+    pub fn reopen_writable_force(&mut self) -> Result<(), Error> {
+        self.reopen_writable_opt(true)
+    }
+
+    fn reopen_writable_opt(&mut self, force: bool) -> Result<(), Error> {
+        if !self.read_only {
+            return Ok(());
+        }
+
+        let Ok(mut file) = OpenOptions::new().read(true).write(true).open(&self.path) else {
+            return Err(Error::err(FBErrorKind::Open));
+        };
+        if !force && Alloc::stored_open_marker(&mut file, self.alloc.block_size())? {
+            return Err(Error::err(FBErrorKind::PossiblyInUse));
+        }
+        self.alloc.replace_file(file);
+        self.read_only = false;
+        self.alloc.set_open_marker(true)?;
+
+        Ok(())
+    }
+
+    /// Path of the file as given to create/load.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Opened with load_read_only.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Sets the behaviour for dropping with unsaved changes.
+    pub fn with_drop_policy(mut self, drop_policy: DropPolicy) -> Self {
+        self.drop_policy = drop_policy;
+        self
+    }
+
+    /// Store unsaved changes when dropped. This sets DropPolicy::Store,
+    /// false goes back to the default policy.
+    pub fn flush_on_drop(&mut self, flush: bool) {
+        self.drop_policy = if flush {
+            DropPolicy::Store
+        } else {
+            DropPolicy::default()
+        };
+    }
+
+    /// Behaviour for dropping with unsaved changes.
+    pub fn drop_policy(&self) -> DropPolicy {
+        self.drop_policy
+    }
+
     /// For testing only. Triggers a panic at a specific step while storing the data.
     /// Nice to test recovering.
     #[cfg(debug_assertions)]
@@ -76,19 +361,241 @@ where
         self.alloc.set_store_panic(step);
     }
 
+    /// For testing only. Disarms the panic set with set_store_panic.
+    #[cfg(debug_assertions)]
+    pub fn clear_store_panic(&mut self) {
+        self.alloc.clear_store_panic();
+    }
+
+    /// For testing only. The step set with set_store_panic. 0 if disarmed.
+    #[cfg(debug_assertions)]
+    pub fn store_panic_step(&self) -> u32 {
+        self.alloc.store_panic_step()
+    }
+
+    /// For testing only. Calls the hook with each step of store(), with the
+    /// numbering of set_store_panic.
+    #[cfg(debug_assertions)]
+    pub fn set_store_hook(&mut self, hook: Option<fn(u32)>) {
+        self.alloc.set_store_hook(hook);
+    }
+
+    /// Which state is recovered if a store is interrupted at this step of
+    /// store(). Steps are numbered as with set_store_panic. Returns true if
+    /// the new store is recovered, false for the previous one.
+    pub fn step_recovers_new_generation(step: u32) -> bool {
+        Alloc::step_recovers_new_generation(step)
+    }
+
+    /// For testing only. Switches the check for double assigned physical blocks
+    /// that runs with every block written. This check scans the whole physical map
+    /// each time, which gets slow for big stores. Default is on.
+    #[cfg(debug_assertions)]
+    pub fn set_check_double_assign(&mut self, check: bool) {
+        self.alloc.set_check_double_assign(check);
+    }
+
+    /// For testing only. Sets the physical block of a block-nr without
+    /// any checks. Allows to simulate a broken map.
+    #[cfg(debug_assertions)]
+    pub fn set_physical_nr_unchecked(
+        &mut self,
+        block_nr: LogicalNr,
+        block_pnr: PhysicalNr,
+    ) -> Result<(), Error> {
+        self.alloc.set_physical_nr_unchecked(block_nr, block_pnr)
+    }
+
+    /// Runs all the checks from load against the current state.
+    pub fn verify(&self) -> Result<(), Error> {
+        self.alloc.verify()
+    }
+
+    /// Checks that no physical block is assigned to more than one block-nr.
+    /// Fails with DoubleAssignedPhysicalBlock. This runs the check from
+    /// load on demand.
+    pub fn verify_no_double_mapping(&self) -> Result<(), Error> {
+        self.alloc.verify_no_double_mapping()
+    }
+
+    /// Checks that the active and the inactive header state don't share
+    /// a root block that was rewritten by the last stores. Fails with
+    /// SharedHeaderState.
+    pub fn verify_header_states(&self) -> Result<(), Error> {
+        self.alloc.verify_header_states()
+    }
+
+    /// Throws away all blocks and streams and truncates the file to the
+    /// state of a newly created file. The block-size and the checksum
+    /// setting are kept, as are the user meta-data and the sequence
+    /// counter. The empty state is stored immediately.
+    pub fn reset(&mut self) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::err(FBErrorKind::ReadOnly));
+        }
+        self.alloc.reset()
+    }
+
+    /// Throws away the cached blocks and loads the maps and streams again
+    /// from the file. Use this to see the changes of another process.
+    ///
+    /// Fails with UnsavedChanges if there is anything not stored yet.
+    pub fn reload(&mut self) -> Result<(), Error> {
+        self.alloc.reload()
+    }
+
+    /// Same as reload() but drops any unsaved changes.
+    pub fn reload_force(&mut self) -> Result<(), Error> {
+        self.alloc.reload_force()
+    }
+
+    /// Shrinks the file by cutting off the free physical blocks at the end.
+    /// Call this after a store() that freed a lot of blocks. Returns the
+    /// number of blocks removed.
+    ///
+    /// Blocks are not moved, a used block near the end keeps the file
+    /// long. This includes the blocks of the previous store, as long as
+    /// it can still be rolled back to. Use compact_to() to rewrite the
+    /// file densely.
+    ///
+    /// Fails with UnsavedChanges if there is anything not stored yet.
+    pub fn truncate_file(&mut self) -> Result<u64, Error> {
+        if self.read_only {
+            return Err(Error::err(FBErrorKind::ReadOnly));
+        }
+        self.alloc.truncate_file()
+    }
+
     /// Stores all dirty blocks.
     pub fn store(&mut self) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::err(FBErrorKind::ReadOnly));
+        }
         self.alloc.store()
     }
 
+    /// Stores all dirty blocks without syncing the file.
+    ///
+    /// This is not crash-safe until the next sync(). Useful for a batch of
+    /// stores that is synced once at the end.
+    pub fn store_no_sync(&mut self) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::err(FBErrorKind::ReadOnly));
+        }
+        self.alloc.store_no_sync()
+    }
+
+    /// Syncs the file. Makes the last store_no_sync() durable.
+    pub fn sync(&mut self) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::err(FBErrorKind::ReadOnly));
+        }
+        self.alloc.sync()
+    }
+
+    /// First half of a two-phase store. Writes and syncs all dirty blocks
+    /// and the maps, but leaves the header state as it is. Until commit()
+    /// the file still reads as the previous store.
+    pub fn prepare(&mut self) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::err(FBErrorKind::ReadOnly));
+        }
+        self.alloc.prepare()
+    }
+
+    /// Second half of a two-phase store. Flips the header state and syncs.
+    ///
+    /// Fails with NotPrepared if there was no prepare().
+    pub fn commit(&mut self) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::err(FBErrorKind::ReadOnly));
+        }
+        self.alloc.commit()
+    }
+
+    /// Stores all dirty blocks and reports the physical block for every
+    /// block that was written. Includes the streams block and the map-blocks.
+    pub fn store_and_report(&mut self) -> Result<Vec<(LogicalNr, PhysicalNr)>, Error> {
+        if self.read_only {
+            return Err(Error::err(FBErrorKind::ReadOnly));
+        }
+        self.alloc.store_and_report()
+    }
+
+    /// Number of stores since the file was created. In contrast to the
+    /// generation this is persisted and keeps counting across sessions.
+    pub fn total_stores(&self) -> u64 {
+        self.alloc.header().total_stores()
+    }
+
+    /// Increments the sequence counter in the header and returns the new
+    /// value. The header is dirty afterwards, the counter is persisted
+    /// with the next store.
+    pub fn next_sequence(&mut self) -> Result<u64, Error> {
+        if self.read_only {
+            return Err(Error::err(FBErrorKind::ReadOnly));
+        }
+        Ok(self.alloc.next_sequence())
+    }
+
+    /// SHA-256 of the exact file contents, including both header states.
+    /// Bitwise identical files give the same digest.
+    #[cfg(feature = "sha2")]
+    pub fn file_digest(&mut self) -> Result<[u8; 32], Error> {
+        self.alloc.storage_digest()
+    }
+
     /// Header state.
     pub fn state(&self) -> State {
         self.alloc.header().state()
     }
 
+    /// Can the inactive header state still be used to go back one store?
+    /// This is possible until the next store starts to reuse the blocks of
+    /// the previous state.
+    pub fn can_rollback(&mut self) -> bool {
+        self.alloc.can_rollback()
+    }
+
     /// Stores a compact copy. The copy contains no unused blocks.
-    pub fn compact_to(&mut self, _path: &Path) -> Result<(), Error> {
-        unimplemented!()
+    ///
+    /// The copy is written to a temporary file next to the path first,
+    /// and renamed to the path after it has been synced. If anything fails
+    /// an existing file at the path is left untouched. This file is never
+    /// modified.
+    ///
+    /// Fails with SameFile if the path refers to this file.
+    pub fn compact_to(&mut self, path: &Path) -> Result<(), Error> {
+        if let (Ok(this), Ok(other)) = (self.path.canonicalize(), path.canonicalize()) {
+            if this == other {
+                return Err(Error::err(FBErrorKind::SameFile));
+            }
+        }
+
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".compact");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let Ok(file) = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(&tmp_path)
+        else {
+            return Err(Error::err(FBErrorKind::Create));
+        };
+
+        if let Err(e) = self.alloc.compact_to(file) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+        if fs::rename(&tmp_path, path).is_err() {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(Error::err(FBErrorKind::Create));
+        }
+
+        Ok(())
     }
 
     /// Blocksize.
@@ -96,6 +603,48 @@ where
         self.alloc.block_size()
     }
 
+    /// Size of the block data. Smaller than the block-size if the
+    /// file uses checksums.
+    pub fn data_size(&self) -> usize {
+        self.alloc.data_size()
+    }
+
+    /// Blocks are written with a checksum.
+    pub fn is_checksummed(&self) -> bool {
+        self.alloc.is_checksummed()
+    }
+
+    /// Checks the block-size against the one stored in the header.
+    pub fn verify_block_size(&self) -> Result<(), Error> {
+        let stored_block_size = self.alloc.header().stored_block_size();
+        if self.alloc.block_size() != stored_block_size {
+            return Err(Error::err(FBErrorKind::InvalidBlockSize(stored_block_size)));
+        }
+        Ok(())
+    }
+
+    /// Fixed overhead in blocks. Both header states live in the single header
+    /// block, so this is 1 plus the number of type-map and physical-map blocks.
+    /// The streams block is not included.
+    pub fn header_overhead_blocks(&self) -> usize {
+        1 + self.alloc.iter_types().count() + self.alloc.iter_physical().count()
+    }
+
+    /// Bytes of user data a file of n_user_blocks blocks can hold.
+    ///
+    /// Every block needs a block-nr, so the type-map and the physical-map
+    /// must cover all n_user_blocks. Each map block covers len_types_g()
+    /// resp. len_physical_g() block-nrs. Those map blocks, the header and
+    /// the streams block are subtracted. With checksums only the data_size()
+    /// of a block counts.
+    pub fn usable_bytes_for(&self, n_user_blocks: usize) -> usize {
+        let data_size = self.alloc.data_size();
+        let types = n_user_blocks.div_ceil(TypesBlock::len_types_g(data_size));
+        let physical = n_user_blocks.div_ceil(PhysicalBlock::len_physical_g(data_size));
+        let user_blocks = n_user_blocks.saturating_sub(2 + types + physical);
+        user_blocks * data_size
+    }
+
     /// Returns the alignment for the block.
     pub fn block_align(&self, block_nr: LogicalNr) -> Result<usize, Error> {
         let block_type = self.alloc.block_type(block_nr)?;
@@ -105,12 +654,49 @@ where
         Ok(U::align(user_block_type))
     }
 
+    /// Checks that every block in memory is aligned as required by its
+    /// user block-type. Blocks that are not loaded have no buffer to check.
+    pub fn check_alignments(&self) -> Result<(), Error> {
+        for block in self.alloc.iter_blocks() {
+            let Some(user_type) = U::user_type(block.block_type()) else {
+                continue;
+            };
+            if block.block_align() < user_type.align() {
+                return Err(Error::err(FBErrorKind::AlignmentTooSmall(
+                    block.block_nr(),
+                    block.block_align(),
+                    user_type.align(),
+                )));
+            }
+        }
+        Ok(())
+    }
+
     /// Header data.
     pub fn header(&self) -> &HeaderBlock {
         self.alloc.header()
     }
 
-    /// Stream data.
+    /// Version of the file format. Load fails with UnsupportedFormatVersion
+    /// for versions it can't read.
+    pub fn format_version(&self) -> u16 {
+        self.alloc.header().format_version()
+    }
+
+    /// Application data in the leftover space of the header block.
+    pub fn user_meta(&self) -> &[u8] {
+        self.alloc.header().user_meta()
+    }
+
+    /// Set the application data in the header block. Written with the
+    /// next store.
+    ///
+    /// Fails with DataTooLarge if it doesn't fit.
+    pub fn set_user_meta(&mut self, meta: &[u8]) -> Result<(), Error> {
+        self.alloc.set_user_meta(meta)
+    }
+
+    /// Stream data. StreamsBlock::iter() lists the head-idx of all streams.
     pub fn streams(&self) -> &StreamsBlock {
         self.alloc.streams()
     }
@@ -125,9 +711,56 @@ where
         self.alloc.iter_physical()
     }
 
+    /// Iterate over the logical->physical map as (map block-nr, start-nr, end-nr).
+    /// The end-nr is exclusive.
+    pub fn iter_physical_ranges(
+        &self,
+    ) -> impl Iterator<Item = (LogicalNr, LogicalNr, LogicalNr)> + '_ {
+        self.alloc
+            .iter_physical()
+            .map(|v| (v.block_nr(), v.start_nr(), v.end_nr()))
+    }
+
+    /// Runs of consecutive free physical blocks as (start, len).
+    /// Shows whether the free space is fragmented or contiguous.
+    pub fn free_extents(&self) -> Vec<(PhysicalNr, u32)> {
+        self.alloc.free_extents()
+    }
+
+    /// Largest run of consecutive free physical blocks as (start, len).
+    /// The first one if there are several. (PhysicalNr(0), 0) if there
+    /// are no free blocks.
+    pub fn largest_free_extent(&self) -> (PhysicalNr, u32) {
+        self.alloc
+            .free_extents()
+            .into_iter()
+            .fold(
+                (PhysicalNr(0), 0),
+                |max, v| if v.1 > max.1 { v } else { max },
+            )
+    }
+
+    /// Number of free block-nrs without growing the map chain.
+    /// The chain grows when only 2 are left, so roughly this minus 2
+    /// allocations are possible before that happens.
+    pub fn immediate_free_capacity(&self) -> usize {
+        self.alloc.immediate_free_capacity()
+    }
+
+    /// Free block-nrs in ascending order.
+    pub fn iter_free(&self) -> impl Iterator<Item = LogicalNr> {
+        self.alloc.iter_free()
+    }
+
+    /// Copy of the complete type-map. Contains every block-nr covered by the
+    /// map including free and internal blocks.
+    pub fn export_type_map(&self) -> Vec<(LogicalNr, BlockType)> {
+        self.alloc.iter_metadata(&|_nr, _ty| true).collect()
+    }
+
     /// Metadata iterator. Returns all allocated block-nr + user-types.
     /// Filters out blocktypes that are not mapped to a user-type.
-    pub fn iter_metadata(&self) -> impl Iterator<Item = (LogicalNr, U)> + DoubleEndedIterator {
+    pub fn iter_metadata(&self) -> impl DoubleEndedIterator<Item = (LogicalNr, U)> {
         self.alloc
             .iter_metadata(&|_nr, _ty| true)
             .filter_map(|(nr, ty)| U::user_type(ty).map(|ty| (nr, ty)))
@@ -138,7 +771,7 @@ where
     pub fn iter_metadata_filter<F>(
         &self,
         filter: F,
-    ) -> impl Iterator<Item = (LogicalNr, U)> + DoubleEndedIterator
+    ) -> impl DoubleEndedIterator<Item = (LogicalNr, U)>
     where
         F: Fn(LogicalNr, U) -> bool,
     {
@@ -150,6 +783,24 @@ where
             .filter_map(|(nr, ty)| U::user_type(ty).map(|ty| (nr, ty)))
     }
 
+    /// Block-nrs of all blocks of this user-type in ascending order.
+    pub fn iter_type(&self, user_type: U) -> impl DoubleEndedIterator<Item = LogicalNr> {
+        let block_type = user_type.block_type();
+        self.alloc
+            .iter_metadata(&move |_nr, ty| ty == block_type)
+            .map(|(nr, _ty)| nr)
+    }
+
+    /// User-types with at least one allocated block, ordered by block-type.
+    pub fn present_types(&self) -> Vec<U> {
+        let block_types: BTreeSet<_> = self
+            .alloc
+            .iter_metadata(&|_nr, ty| ty != BlockType::Free && !ty.is_internal())
+            .map(|(_nr, ty)| ty)
+            .collect();
+        block_types.into_iter().filter_map(U::user_type).collect()
+    }
+
     /// Iterate all blocks in memory.
     pub fn iter_blocks(&self) -> impl Iterator<Item = &Block> {
         self.alloc.iter_blocks()
@@ -161,6 +812,45 @@ where
         self.alloc.generation()
     }
 
+    /// Generation of the store that last wrote the block.
+    ///
+    /// This only knows about the blocks in the memory cache. A block that
+    /// was loaded from the file or discarded since has generation 0, so
+    /// changes by another process are only seen after the file is loaded
+    /// again.
+    pub fn block_generation(&self, block_nr: LogicalNr) -> Result<u32, Error> {
+        self.alloc.block_generation(block_nr)
+    }
+
+    /// Was the block written by a store after the given generation.
+    /// Same limits as block_generation().
+    pub fn stored_since(&self, block_nr: LogicalNr, generation: u32) -> Result<bool, Error> {
+        self.alloc.stored_since(block_nr, generation)
+    }
+
+    /// Block counts for monitoring.
+    pub fn stats(&self) -> BlockStats {
+        self.alloc.block_stats()
+    }
+
+    /// Capacity of the write buffer for store in bytes. Consecutive
+    /// physical blocks are written with one call. 0 writes every block
+    /// on its own.
+    pub fn set_write_buffer_size(&mut self, bytes: usize) {
+        self.alloc.set_write_buffer_size(bytes);
+    }
+
+    /// Size of the file in blocks.
+    pub fn file_block_count(&mut self) -> Result<u64, Error> {
+        self.alloc.file_block_count()
+    }
+
+    /// Generations of the type-map and physical-map blocks.
+    /// Shows which of them were rewritten with the last store.
+    pub fn map_generations(&self) -> MapGenerations {
+        self.alloc.map_generations()
+    }
+
     /// Block type for a block-nr.
     pub fn block_type(&self, block_nr: LogicalNr) -> Result<U, Error> {
         match self.alloc.block_type(block_nr) {
@@ -172,6 +862,42 @@ where
         }
     }
 
+    /// Block type for a block-nr without the mapping to the user type.
+    /// Returns the internal types Header, Types, Physical and Streams too.
+    pub fn raw_block_type(&self, block_nr: LogicalNr) -> Result<BlockType, Error> {
+        self.alloc.block_type(block_nr)
+    }
+
+    /// Get the physical block for a block-nr. Returns 0 if no such page has been assigned yet.
+    pub fn physical_nr(&self, block_nr: LogicalNr) -> Result<PhysicalNr, Error> {
+        self.alloc.physical_nr(block_nr)
+    }
+
+    /// Get the physical blocks for count consecutive block-nrs.
+    /// Fails with the first block-nr that is out of range.
+    pub fn physical_range(&self, start: LogicalNr, count: u32) -> Result<Vec<PhysicalNr>, Error> {
+        self.alloc.physical_range(start, count)
+    }
+
+    /// Drops all unmodified blocks from the memory cache. Unlike discard()
+    /// this works on all blocks at once and never touches modified ones.
+    pub fn release_clean(&mut self) {
+        self.alloc.release_clean();
+    }
+
+    /// Limits the number of cached blocks. Least recently used clean
+    /// blocks are dropped from the cache when get() or get_mut() exceed the
+    /// limit. Dirty blocks are always kept. None for no limit.
+    pub fn set_cache_limit(&mut self, limit: Option<usize>) {
+        self.alloc.set_cache_limit(limit);
+    }
+
+    /// Is the block in the memory cache. If true, get() doesn't need to
+    /// load it.
+    pub fn is_cached(&self, block_nr: LogicalNr) -> bool {
+        self.alloc.is_cached(block_nr)
+    }
+
     /// Discard a block. Remove from memory cache but do nothing otherwise.
     /// If the block was modified, the discard flag is set and the block is removed
     /// after store.
@@ -187,7 +913,215 @@ where
         self.alloc.block_mut(alloc_nr, align)
     }
 
+    /// Allocate count new blocks and return the block-nrs.
+    /// The block maps are grown up front, so this fails before any block
+    /// is allocated.
+    pub fn alloc_many(&mut self, user_type: U, count: usize) -> Result<Vec<LogicalNr>, Error> {
+        self.alloc
+            .alloc_blocks(user_type.block_type(), user_type.align(), count)
+    }
+
+    /// Grows the block maps up front, so that additional blocks can be
+    /// allocated without growing them again.
+    ///
+    /// Fails with NoFreeBlocks if additional is too large.
+    pub fn reserve(&mut self, additional: usize) -> Result<(), Error> {
+        self.alloc.reserve(additional)
+    }
+
+    /// Allocate count new blocks on consecutive physical blocks. The
+    /// physical blocks are reserved now and used by the next store, which
+    /// writes them in one run. The blocks are marked dirty.
+    pub fn alloc_contiguous(
+        &mut self,
+        user_type: U,
+        count: usize,
+    ) -> Result<Vec<LogicalNr>, Error> {
+        self.alloc
+            .alloc_contiguous(user_type.block_type(), user_type.align(), count)
+    }
+
+    /// Allocate a new block and copies the data to the start of the block.
+    /// The rest of the block is zeroed. The block is marked dirty.
+    ///
+    /// Fails with DataTooLarge if the data exceeds the data_size().
+    pub fn alloc_from(&mut self, user_type: U, data: &[u8]) -> Result<LogicalNr, Error> {
+        if data.len() > self.alloc.data_size() {
+            return Err(Error::err(FBErrorKind::DataTooLarge(data.len())));
+        }
+
+        let block = self.alloc(user_type)?;
+        block.data[..data.len()].copy_from_slice(data);
+        block.set_dirty(true);
+        Ok(block.block_nr())
+    }
+
+    /// Allocate a new block with the block-type of src and copies the data.
+    /// The new block is marked dirty.
+    ///
+    /// Fails with NotAllocated for a free block and with AccessDenied for
+    /// internal blocks.
+    pub fn copy_block(&mut self, src: LogicalNr) -> Result<LogicalNr, Error> {
+        let block_type = self.alloc.block_type(src)?;
+        if block_type == BlockType::Free {
+            return Err(Error::err(FBErrorKind::NotAllocated(src)));
+        }
+        if block_type.is_internal() {
+            return Err(Error::err(FBErrorKind::AccessDenied(src)));
+        }
+
+        let align = self.block_align(src)?;
+        let data = self.alloc.block(src, align)?.data.to_vec();
+
+        let new_nr = self.alloc.alloc_block(block_type, align)?;
+        let block = self.alloc.block_mut(new_nr, align)?;
+        block.data.copy_from_slice(&data);
+        block.set_dirty(true);
+        Ok(new_nr)
+    }
+
+    /// Allocates a block for each slice and writes it directly to the file,
+    /// bypassing the cache. This ends with a store() of everything else too.
+    /// Use this for append-heavy loads.
+    ///
+    /// Fails with DataTooLarge if a slice exceeds the data_size().
+    pub fn bulk_write<'a>(
+        &mut self,
+        user_type: U,
+        blocks: impl Iterator<Item = &'a [u8]>,
+    ) -> Result<Vec<LogicalNr>, Error> {
+        if self.read_only {
+            return Err(Error::err(FBErrorKind::ReadOnly));
+        }
+        self.alloc
+            .bulk_write(user_type.block_type(), user_type.align(), blocks)
+    }
+
+    /// Allocate a slot of this size in a slab block. Slab blocks pack many
+    /// small fixed-size slots into one block. The user-type must be used
+    /// for slab blocks only, blocks with a different slot-size are skipped.
+    /// New slots are zeroed.
+    ///
+    /// Fails with DataTooLarge if not even one slot fits in a block.
+    pub fn slab_alloc(&mut self, user_type: U, size: usize) -> Result<SlabRef, Error> {
+        let Some(layout) = SlabLayout::new(size, self.alloc.data_size()) else {
+            return Err(Error::err(FBErrorKind::DataTooLarge(size)));
+        };
+        let align = user_type.align();
+
+        let block_nrs: Vec<_> = self.iter_type(user_type).collect();
+        for block_nr in block_nrs {
+            let cached = self.alloc.is_cached(block_nr);
+            let block = self.alloc.block_mut(block_nr, align)?;
+            if SlabLayout::of(block) == Some(layout) {
+                if let Some(slot) = layout.alloc(block) {
+                    block.set_dirty(true);
+                    return Ok(SlabRef { block_nr, slot });
+                }
+            }
+            if !cached {
+                self.alloc.discard_block(block_nr);
+            }
+        }
+
+        let block_nr = self.alloc.alloc_block(user_type.block_type(), align)?;
+        let block = self.alloc.block_mut(block_nr, align)?;
+        layout.init(block);
+        let slot = layout.alloc(block).expect("slot");
+        block.set_dirty(true);
+        Ok(SlabRef { block_nr, slot })
+    }
+
+    /// Data of a slab slot.
+    ///
+    /// Fails with InvalidSlot if the slot is not allocated.
+    pub fn slab_get(&mut self, slab: SlabRef) -> Result<&[u8], Error> {
+        let block = self.get(slab.block_nr)?;
+        match SlabLayout::of(block) {
+            Some(layout) if layout.is_used(block, slab.slot) => {
+                Ok(&block.data[layout.slot_range(slab.slot)])
+            }
+            _ => Err(Error::err(FBErrorKind::InvalidSlot(
+                slab.block_nr,
+                slab.slot,
+            ))),
+        }
+    }
+
+    /// Data of a slab slot. Marks the block dirty.
+    ///
+    /// Fails with InvalidSlot if the slot is not allocated.
+    pub fn slab_get_mut(&mut self, slab: SlabRef) -> Result<&mut [u8], Error> {
+        let block = self.block_mut(slab.block_nr)?;
+        match SlabLayout::of(block) {
+            Some(layout) if layout.is_used(block, slab.slot) => {
+                block.set_dirty(true);
+                Ok(&mut block.data[layout.slot_range(slab.slot)])
+            }
+            _ => Err(Error::err(FBErrorKind::InvalidSlot(
+                slab.block_nr,
+                slab.slot,
+            ))),
+        }
+    }
+
+    /// Free a slab slot. The block is freed with its last slot.
+    ///
+    /// Fails with InvalidSlot if the slot is not allocated.
+    pub fn slab_free(&mut self, slab: SlabRef) -> Result<(), Error> {
+        let block = self.block_mut(slab.block_nr)?;
+        let Some(layout) = SlabLayout::of(block) else {
+            return Err(Error::err(FBErrorKind::InvalidSlot(
+                slab.block_nr,
+                slab.slot,
+            )));
+        };
+        layout.free(block, slab.slot)?;
+        block.set_dirty(true);
+
+        if layout.used(block) == 0 {
+            self.alloc.free_block(slab.block_nr)?;
+        }
+        Ok(())
+    }
+
+    /// Allocate a scratch block of data_size(). It is aligned for u64.
+    /// A scratch block is never stored and is dropped with the next store.
+    pub fn alloc_scratch(&mut self) -> &mut Block {
+        self.alloc.alloc_scratch(align_of::<u64>())
+    }
+
+    /// Estimated memory used by the block cache in bytes. This is about
+    /// the number of cached blocks times the block-size plus a small
+    /// overhead per block.
+    pub fn cache_memory_bytes(&self) -> usize {
+        self.alloc.cache_memory_bytes()
+    }
+
+    /// Number of scratch blocks.
+    pub fn scratch_len(&self) -> usize {
+        self.alloc.scratch_len()
+    }
+
+    /// Changes the user-type of an allocated block. The data and the
+    /// physical block stay as they are.
+    pub fn retype_block(&mut self, block_nr: LogicalNr, user_type: U) -> Result<(), Error> {
+        self.alloc.retype_block(block_nr, user_type.block_type())
+    }
+
+    /// Changes the user-type of all blocks of one user-type to another.
+    /// The stream head-idx is moved too. Returns the number of blocks.
+    ///
+    /// Fails with MixedStreamUsage or TypeInUse if the user-type to is
+    /// already used, see Alloc::rename_type().
+    pub fn rename_type(&mut self, from: U, to: U) -> Result<usize, Error> {
+        self.alloc.rename_type(from.block_type(), to.block_type())
+    }
+
     /// Free a block.
+    ///
+    /// Fails with NotAllocated if the block is already free and with
+    /// AccessDenied for an internal block.
     pub fn free(&mut self, block_nr: LogicalNr) -> Result<(), Error> {
         self.alloc.free_block(block_nr)
     }
@@ -206,12 +1140,144 @@ where
         self.alloc.block(block_nr, align)
     }
 
-    /// Get a data block.
-    pub fn get_mut(&mut self, block_nr: LogicalNr) -> Result<&mut Block, Error> {
+    /// Get a data block for modification. Any mutable access through the
+    /// returned BlockMut marks the block dirty.
+    pub fn get_mut(&mut self, block_nr: LogicalNr) -> Result<BlockMut<'_>, Error> {
+        Ok(BlockMut::new(self.block_mut(block_nr)?))
+    }
+
+    // Data block without the dirty tracking of BlockMut.
+    fn block_mut(&mut self, block_nr: LogicalNr) -> Result<&mut Block, Error> {
         let align = self.block_align(block_nr)?;
         self.alloc.block_mut(block_nr, align)
     }
 
+    /// Loads each block of this user-type in ascending order and calls f.
+    /// Blocks that were not in the cache before are discarded afterwards.
+    pub fn for_each_block_of_type<F>(&mut self, user_type: U, mut f: F) -> Result<(), Error>
+    where
+        F: FnMut(LogicalNr, &Block),
+    {
+        let align = user_type.align();
+        let block_nrs: Vec<_> = self.iter_type(user_type).collect();
+        for block_nr in block_nrs {
+            let cached = self.alloc.is_cached(block_nr);
+            f(block_nr, self.alloc.block(block_nr, align)?);
+            if !cached {
+                self.alloc.discard_block(block_nr);
+            }
+        }
+        Ok(())
+    }
+
+    /// Swaps the data of two user blocks and marks both dirty.
+    /// The block-nrs and the physical map are not touched.
+    pub fn swap_block_contents(&mut self, a: LogicalNr, b: LogicalNr) -> Result<(), Error> {
+        let a_data = self.get(a)?.data.to_vec();
+
+        let block_b = self.block_mut(b)?;
+        let b_data = block_b.data.to_vec();
+        block_b.data.copy_from_slice(&a_data);
+        block_b.set_dirty(true);
+
+        let block_a = self.block_mut(a)?;
+        block_a.data.copy_from_slice(&b_data);
+        block_a.set_dirty(true);
+
+        Ok(())
+    }
+
+    // All allocated blocks, that are not internal and have a user-type.
+    fn user_blocks(&self) -> Vec<(LogicalNr, U)> {
+        self.alloc
+            .iter_metadata(&|_nr, ty| ty != BlockType::Free && !ty.is_internal())
+            .filter_map(|(nr, ty)| U::user_type(ty).map(|ty| (nr, ty)))
+            .collect()
+    }
+
+    /// Loads every allocated user block into the cache.
+    /// Internal blocks and blocks that have never been stored are skipped,
+    /// as are blocks already in the cache.
+    ///
+    /// Returns the number of blocks loaded.
+    ///
+    /// Fails with CacheLimit before loading anything, if the blocks don't
+    /// fit into the cache-limit.
+    pub fn load_all(&mut self) -> Result<usize, Error> {
+        let mut block_nrs = Vec::new();
+        for (block_nr, user_type) in self.user_blocks() {
+            if self.alloc.is_cached(block_nr) {
+                continue;
+            }
+            if self.alloc.physical_nr(block_nr)? == 0 {
+                continue;
+            }
+            block_nrs.push((block_nr, user_type));
+        }
+
+        if let Some(limit) = self.alloc.cache_limit() {
+            let needed = self.alloc.iter_blocks().count() + block_nrs.len();
+            if needed > limit {
+                return Err(Error::err(FBErrorKind::CacheLimit(needed)));
+            }
+        }
+
+        for (block_nr, user_type) in &block_nrs {
+            self.alloc.load_block(*block_nr, user_type.align())?;
+        }
+
+        Ok(block_nrs.len())
+    }
+
+    /// Brute-force search over all allocated user blocks.
+    ///
+    /// Blocks that are not already in the cache are loaded one by one
+    /// and discarded after the check.
+    pub fn find_blocks<F>(&mut self, pred: F) -> Result<Vec<LogicalNr>, Error>
+    where
+        F: Fn(LogicalNr, &[u8]) -> bool,
+    {
+        let block_nrs = self.user_blocks();
+
+        let mut found = Vec::new();
+        for (block_nr, user_type) in block_nrs {
+            let cached = self.alloc.is_cached(block_nr);
+            let block = self.alloc.block(block_nr, user_type.align())?;
+            if pred(block_nr, block.data.as_ref()) {
+                found.push(block_nr);
+            }
+            if !cached {
+                self.alloc.discard_block(block_nr);
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// Length of the stream in bytes. 0 if there are no blocks of this type.
+    pub fn stream_len(&self, user_type: U) -> u64 {
+        self.alloc.stream_len(user_type.block_type())
+    }
+
+    /// Frees all blocks of the stream and resets the head-idx.
+    /// The next append_stream() starts fresh.
+    pub fn clear_stream(&mut self, user_type: U) -> Result<(), Error> {
+        if !user_type.is_stream() {
+            return Err(Error::err(FBErrorKind::NotAStream(user_type.block_type())));
+        }
+        self.alloc.clear_stream(user_type.block_type())
+    }
+
+    /// Cuts the stream to new_len bytes and frees the blocks after that.
+    /// Does nothing if the stream is not longer than this.
+    pub fn truncate_stream(&mut self, user_type: U, new_len: u64) -> Result<(), Error> {
+        if !user_type.is_stream() {
+            return Err(Error::err(FBErrorKind::NotAStream(user_type.block_type())));
+        }
+        self.alloc
+            .truncate_stream(user_type.block_type(), new_len, user_type.align())
+    }
+
     /// Get a Reader that reads the contents of one BlockType in order.
     pub fn read_stream(&mut self, user_type: U) -> Result<impl BlockRead + '_, Error> {
         if !user_type.is_stream() {
@@ -221,14 +1287,156 @@ where
             .read_stream(user_type.block_type(), user_type.align())
     }
 
+    /// Get a Reader that reads the contents of one BlockType in order.
+    /// Reading past the end of the stream fails with an io::Error of kind
+    /// UnexpectedEof instead of returning 0. Use this to detect truncated data.
+    pub fn read_stream_strict(&mut self, user_type: U) -> Result<impl BlockRead + '_, Error> {
+        if !user_type.is_stream() {
+            return Err(Error::err(FBErrorKind::NotAStream(user_type.block_type())));
+        }
+        self.alloc
+            .read_stream_strict(user_type.block_type(), user_type.align())
+    }
+
     /// Get a Writer that writes to consecutive blocks of blocktype.
-    pub fn append_stream(&mut self, user_type: U) -> Result<impl BlockWrite + Write + '_, Error> {
+    ///
+    /// Fails with MixedStreamUsage if there are blocks of this type, that were
+    /// not written as a stream. A single stored, empty block is accepted as
+    /// the tail of a stream written by an older version.
+    ///
+    /// Buffered writes are copied to the block on drop, but only flush()
+    /// can report an error.
+    pub fn append_stream(&mut self, user_type: U) -> Result<impl BlockWrite + '_, Error> {
         if !user_type.is_stream() {
             return Err(Error::err(FBErrorKind::NotAStream(user_type.block_type())));
         }
         self.alloc
             .append_stream(user_type.block_type(), user_type.align())
     }
+
+    /// Get a Writer that starts at the beginning of a new block, so a
+    /// record written with it is block-aligned. If the last block is
+    /// partially filled, the rest of it is zeroed and a new block is
+    /// allocated. This costs up to one block of padding per call, and the
+    /// padding is read back as zeros.
+    ///
+    /// Fails with MixedStreamUsage if there are blocks of this type, that were
+    /// not written as a stream.
+    pub fn append_stream_aligned(&mut self, user_type: U) -> Result<impl BlockWrite + '_, Error> {
+        if !user_type.is_stream() {
+            return Err(Error::err(FBErrorKind::NotAStream(user_type.block_type())));
+        }
+        self.alloc
+            .append_stream_aligned(user_type.block_type(), user_type.align())
+    }
+
+    /// Current end of the stream. Can be used with append_stream_resume()
+    /// to continue writing at exactly this position.
+    pub fn stream_position(&self, user_type: U) -> StreamPosition {
+        self.alloc.stream_position(user_type.block_type())
+    }
+
+    /// Get a Writer that continues at the position.
+    ///
+    /// Fails with StreamPositionMismatch if the stream has been changed
+    /// since the position was taken.
+    pub fn append_stream_resume(
+        &mut self,
+        position: StreamPosition,
+    ) -> Result<impl BlockWrite + '_, Error> {
+        let Some(user_type) = U::user_type(position.block_type) else {
+            return Err(Error::err(FBErrorKind::NoUserBlockType(
+                position.block_type,
+            )));
+        };
+        if !user_type.is_stream() {
+            return Err(Error::err(FBErrorKind::NotAStream(position.block_type)));
+        }
+        self.alloc.append_stream_resume(position, user_type.align())
+    }
+
+    /// Number of gaps for each stream, where the next block of the stream
+    /// is not stored in the physically next block. A high count slows down
+    /// read_stream(). compact_to() renumbers the physical blocks in
+    /// block-nr order.
+    pub fn fragmentation_report(&self) -> Vec<(U, usize)> {
+        self.alloc
+            .fragmentation_report()
+            .into_iter()
+            .filter_map(|(ty, gaps)| U::user_type(ty).map(|ty| (ty, gaps)))
+            .collect()
+    }
+
+    /// Stream types with a recorded head-idx but without any blocks.
+    pub fn find_orphan_streams(&self) -> Vec<U> {
+        self.alloc
+            .find_orphan_streams()
+            .into_iter()
+            .filter_map(U::user_type)
+            .collect()
+    }
+
+    /// Removes the head-idx of all streams without blocks.
+    pub fn clear_orphan_streams(&mut self) {
+        self.alloc.clear_orphan_streams()
+    }
+
+    /// Checks that no stream has a tail block newer than the stored head-idx.
+    /// Fails with StreamIndexStale if a store was interrupted between writing
+    /// the stream data and the streams block, or if a tail block contains
+    /// data behind the head-idx. Tail blocks that are not cached are read
+    /// from the file.
+    pub fn verify_streams(&mut self) -> Result<(), Error> {
+        self.alloc.verify_streams()
+    }
+}
+
+impl<U> Drop for FileBlocks<U> {
+    fn drop(&mut self) {
+        // only the first problem is reported.
+        let mut msg = None;
+
+        // store before the open marker is cleared.
+        if self.drop_policy == DropPolicy::Store && self.alloc.is_dirty() {
+            let r = if self.read_only {
+                Err(Error::err(FBErrorKind::ReadOnly))
+            } else {
+                self.alloc.store()
+            };
+            if let Err(e) = r {
+                msg = Some(format!("failed to store on drop: {:?}", e.kind));
+            }
+        }
+
+        if !self.read_only {
+            if let Err(e) = self.alloc.set_open_marker(false) {
+                if self.drop_policy != DropPolicy::Ignore && msg.is_none() {
+                    msg = Some(format!("failed to clear the open marker: {:?}", e.kind));
+                }
+            }
+        }
+
+        // with DropPolicy::Store a failed store has been reported already.
+        if self.alloc.is_dirty() {
+            match self.drop_policy {
+                DropPolicy::Ignore | DropPolicy::Store => {}
+                DropPolicy::WarnLog => {
+                    msg.get_or_insert_with(|| "dropped with unsaved changes".into());
+                }
+                DropPolicy::Panic => {
+                    if cfg!(debug_assertions) && !std::thread::panicking() {
+                        panic!("blockfile2: FileBlocks dropped with unsaved changes");
+                    } else {
+                        msg.get_or_insert_with(|| "dropped with unsaved changes".into());
+                    }
+                }
+            }
+        }
+
+        if let Some(msg) = msg {
+            eprintln!("blockfile2: FileBlocks {}", msg);
+        }
+    }
 }
 
 impl<U> Debug for FileBlocks<U>
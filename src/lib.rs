@@ -1,19 +1,23 @@
 use std::backtrace::Backtrace;
 use std::cmp::Ordering;
 use std::fmt::{Debug, Display, Formatter};
-use std::io::ErrorKind;
 use std::ops::{Add, AddAssign, Sub};
 use std::{io, mem};
 
 mod blockmap;
 mod fileblocks;
+mod slab;
 
+#[cfg(feature = "mmap")]
+pub use crate::blockmap::MmapStorage;
 pub use crate::blockmap::{
-    alloc_box_buffer, Alloc, Block, BlockRead, BlockType, BlockWrite, HeaderArray, HeaderArrayMut,
-    HeaderBlock, PhysicalBlock, State, StreamsBlock, TypesBlock, UserBlock, UserStreamsBlock,
+    alloc_box_buffer, Alloc, Block, BlockMut, BlockRead, BlockStats, BlockStorage, BlockType,
+    BlockWrite, HeaderArray, HeaderArrayMut, HeaderBlock, MapGeneration, MapGenerations,
+    PhysicalBlock, State, StreamPosition, StreamsBlock, TypesBlock, UserBlock, UserStreamsBlock,
     UserTypesBlock,
 };
-pub use crate::fileblocks::{BasicFileBlocks, FileBlocks};
+pub use crate::fileblocks::{BasicFileBlocks, DropPolicy, FileBlocks};
+pub use crate::slab::SlabRef;
 
 /// User defined mapping of block-types.
 pub trait UserBlockType: Copy {
@@ -43,16 +47,35 @@ where
     }
 }
 
+/// Underlying integer of a physical block-nr.
+///
+/// With the feature `physical64` this is an u64. This changes the on-disk format,
+/// files are not interchangeable between both variants.
+#[cfg(not(feature = "physical64"))]
+pub type PhysicalRepr = u32;
+/// Underlying integer of a physical block-nr.
+///
+/// With the feature `physical64` this is an u64. This changes the on-disk format,
+/// files are not interchangeable between both variants.
+#[cfg(feature = "physical64")]
+pub type PhysicalRepr = u64;
+
 /// Newtype for physical block-nr.
 #[repr(transparent)]
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct PhysicalNr(pub u32);
+pub struct PhysicalNr(pub PhysicalRepr);
 
 impl PhysicalNr {
+    #[cfg(not(feature = "physical64"))]
     pub fn as_u32(&self) -> u32 {
         self.0
     }
 
+    #[allow(clippy::unnecessary_cast)]
+    pub fn as_u64(&self) -> u64 {
+        self.0 as u64
+    }
+
     pub fn as_usize(&self) -> usize {
         self.0 as usize
     }
@@ -74,18 +97,18 @@ impl Add<u32> for PhysicalNr {
     type Output = PhysicalNr;
 
     fn add(self, rhs: u32) -> Self::Output {
-        PhysicalNr(self.0 + rhs)
+        PhysicalNr(self.0 + rhs as PhysicalRepr)
     }
 }
 
 impl AddAssign<u32> for PhysicalNr {
     fn add_assign(&mut self, rhs: u32) {
-        self.0 += rhs;
+        self.0 += rhs as PhysicalRepr;
     }
 }
 
 impl Sub for PhysicalNr {
-    type Output = u32;
+    type Output = PhysicalRepr;
 
     fn sub(self, rhs: Self) -> Self::Output {
         self.0 - rhs.0
@@ -94,13 +117,13 @@ impl Sub for PhysicalNr {
 
 impl PartialEq<u32> for PhysicalNr {
     fn eq(&self, other: &u32) -> bool {
-        self.0 == *other
+        self.0 == *other as PhysicalRepr
     }
 }
 
 impl PartialOrd<u32> for PhysicalNr {
     fn partial_cmp(&self, other: &u32) -> Option<Ordering> {
-        self.0.partial_cmp(other)
+        self.0.partial_cmp(&(*other as PhysicalRepr))
     }
 }
 
@@ -187,10 +210,24 @@ pub enum FBErrorKind {
     Sync(io::Error),
     /// Metadata failed. IO error.
     Metadata(io::Error),
+    /// Truncating the file failed. IO error.
+    Truncate(io::Error),
+    /// Copying the file to the backup failed. IO error.
+    Backup(io::Error),
     /// Cannot create the file.
     Create,
     /// Cannot open the file.
     Open,
+    /// The file was opened read-only.
+    ReadOnly,
+    /// Commit without a prepare.
+    NotPrepared,
+    /// There are changes that have not been stored yet.
+    UnsavedChanges,
+    /// The target is the file itself.
+    SameFile,
+    /// The blocks would exceed the cache-limit. Number of cached blocks needed.
+    CacheLimit(usize),
 
     /// Block has not been allocated.
     NotAllocated(LogicalNr),
@@ -206,11 +243,33 @@ pub enum FBErrorKind {
     MaxStreams(usize),
     /// Not a stream block-type
     NotAStream(BlockType),
+    /// Blocks of this block-type exist, but were not written as a stream.
+    MixedStreamUsage(BlockType),
+    /// The tail block of the stream is newer than the stored head-idx.
+    StreamIndexStale(BlockType),
+    /// The stream was modified since the StreamPosition was taken.
+    StreamPositionMismatch(BlockType),
+    /// There are blocks of this block-type already.
+    TypeInUse(BlockType),
 
     /// Not a known block-nr.
     InvalidBlock(LogicalNr),
+    /// Internal error. Physical block 0 is the header, but was used for
+    /// this block-nr.
+    InvalidPhysicalZero(LogicalNr),
+    /// The physical block of this block-nr is beyond the end of the file.
+    PhysicalOutOfRange(LogicalNr, PhysicalNr),
     /// Loading a file with a different block-size.
     InvalidBlockSize(usize),
+    /// The block is not aligned as required by its user block-type.
+    /// Block-nr, actual alignment, required alignment.
+    AlignmentTooSmall(LogicalNr, usize, usize),
+    /// Data with this length doesn't fit in a block.
+    DataTooLarge(usize),
+    /// Access at offset with length is outside of the block.
+    OutOfBounds(usize, usize),
+    /// Slab slot is not allocated.
+    InvalidSlot(LogicalNr, usize),
     /// Severe load error. Block-data is garbage?
     NoBlockType(LogicalNr),
     /// Severe load error. Block-data is garbage?
@@ -223,6 +282,17 @@ pub enum FBErrorKind {
     DoubleAssignedPhysicalBlock(LogicalNr, LogicalNr),
     /// Severe load error. Header is broken.
     HeaderCorrupted,
+    /// The file has no valid magic number. Not a block-file.
+    BadMagic,
+    /// The file was written with a format version outside the supported range.
+    UnsupportedFormatVersion(u16),
+    /// Both header states use the same physical block for this root block.
+    SharedHeaderState(LogicalNr, PhysicalNr),
+    /// The checksum of the block doesn't match its data.
+    ChecksumMismatch(LogicalNr, PhysicalNr),
+    /// The process-open marker is set. Either the file is opened elsewhere
+    /// or was not closed cleanly.
+    PossiblyInUse,
 }
 
 impl PartialEq for FBErrorKind {
@@ -255,6 +325,12 @@ impl PartialEq for FBErrorKind {
                 };
                 pnr == o_pnr
             }
+            FBErrorKind::Backup(e) => {
+                let FBErrorKind::Backup(o_e) = other else {
+                    unreachable!()
+                };
+                e.kind() == o_e.kind()
+            }
             FBErrorKind::SubStoreRaw(pnr, _) => {
                 let FBErrorKind::SubStoreRaw(o_pnr, _) = other else {
                     unreachable!()
@@ -285,12 +361,30 @@ impl PartialEq for FBErrorKind {
                 };
                 v == o_v
             }
+            FBErrorKind::CacheLimit(v) => {
+                let FBErrorKind::CacheLimit(o_v) = other else {
+                    unreachable!()
+                };
+                v == o_v
+            }
             FBErrorKind::NotAStream(ty) => {
                 let FBErrorKind::NotAStream(o_ty) = other else {
                     unreachable!()
                 };
                 ty == o_ty
             }
+            FBErrorKind::MixedStreamUsage(ty) => {
+                let FBErrorKind::MixedStreamUsage(o_ty) = other else {
+                    unreachable!()
+                };
+                ty == o_ty
+            }
+            FBErrorKind::StreamIndexStale(ty) => {
+                let FBErrorKind::StreamIndexStale(o_ty) = other else {
+                    unreachable!()
+                };
+                ty == o_ty
+            }
             FBErrorKind::InvalidBlock(nr) => {
                 let FBErrorKind::InvalidBlock(o_nr) = other else {
                     unreachable!()
@@ -303,6 +397,24 @@ impl PartialEq for FBErrorKind {
                 };
                 sz == o_sz
             }
+            FBErrorKind::AlignmentTooSmall(nr, align, req) => {
+                let FBErrorKind::AlignmentTooSmall(o_nr, o_align, o_req) = other else {
+                    unreachable!()
+                };
+                nr == o_nr && align == o_align && req == o_req
+            }
+            FBErrorKind::DataTooLarge(len) => {
+                let FBErrorKind::DataTooLarge(o_len) = other else {
+                    unreachable!()
+                };
+                len == o_len
+            }
+            FBErrorKind::OutOfBounds(offset, len) => {
+                let FBErrorKind::OutOfBounds(o_offset, o_len) = other else {
+                    unreachable!()
+                };
+                offset == o_offset && len == o_len
+            }
             FBErrorKind::NoBlockType(nr) => {
                 let FBErrorKind::NoBlockType(o_nr) = other else {
                     unreachable!()
@@ -315,6 +427,78 @@ impl PartialEq for FBErrorKind {
                 };
                 nr == o_nr && ty == o_ty
             }
+            FBErrorKind::InvalidSlot(nr, slot) => {
+                let FBErrorKind::InvalidSlot(o_nr, o_slot) = other else {
+                    unreachable!()
+                };
+                nr == o_nr && slot == o_slot
+            }
+            FBErrorKind::TypeInUse(ty) => {
+                let FBErrorKind::TypeInUse(o_ty) = other else {
+                    unreachable!()
+                };
+                ty == o_ty
+            }
+            FBErrorKind::StreamPositionMismatch(ty) => {
+                let FBErrorKind::StreamPositionMismatch(o_ty) = other else {
+                    unreachable!()
+                };
+                ty == o_ty
+            }
+            FBErrorKind::InvalidBlockSequence(nr, nr2) => {
+                let FBErrorKind::InvalidBlockSequence(o_nr, o_nr2) = other else {
+                    unreachable!()
+                };
+                nr == o_nr && nr2 == o_nr2
+            }
+            FBErrorKind::DoubleAssignedPhysicalBlock(nr, nr2) => {
+                let FBErrorKind::DoubleAssignedPhysicalBlock(o_nr, o_nr2) = other else {
+                    unreachable!()
+                };
+                nr == o_nr && nr2 == o_nr2
+            }
+            FBErrorKind::ChecksumMismatch(nr, pnr) => {
+                let FBErrorKind::ChecksumMismatch(o_nr, o_pnr) = other else {
+                    unreachable!()
+                };
+                nr == o_nr && pnr == o_pnr
+            }
+            FBErrorKind::UnsupportedFormatVersion(v) => {
+                let FBErrorKind::UnsupportedFormatVersion(o_v) = other else {
+                    unreachable!()
+                };
+                v == o_v
+            }
+            FBErrorKind::SharedHeaderState(nr, pnr) => {
+                let FBErrorKind::SharedHeaderState(o_nr, o_pnr) = other else {
+                    unreachable!()
+                };
+                nr == o_nr && pnr == o_pnr
+            }
+            FBErrorKind::InvalidPhysicalZero(nr) => {
+                let FBErrorKind::InvalidPhysicalZero(o_nr) = other else {
+                    unreachable!()
+                };
+                nr == o_nr
+            }
+            FBErrorKind::PhysicalOutOfRange(nr, pnr) => {
+                let FBErrorKind::PhysicalOutOfRange(o_nr, o_pnr) = other else {
+                    unreachable!()
+                };
+                nr == o_nr && pnr == o_pnr
+            }
+            FBErrorKind::Create
+            | FBErrorKind::Open
+            | FBErrorKind::ReadOnly
+            | FBErrorKind::NotPrepared
+            | FBErrorKind::UnsavedChanges
+            | FBErrorKind::SameFile
+            | FBErrorKind::Truncate(_)
+            | FBErrorKind::NoFreeBlocks
+            | FBErrorKind::NoBlockMap
+            | FBErrorKind::HeaderCorrupted
+            | FBErrorKind::BadMagic
+            | FBErrorKind::PossiblyInUse => true,
             _ => {
                 unreachable!()
             }
@@ -357,6 +541,6 @@ impl std::error::Error for Error {}
 
 impl From<Error> for io::Error {
     fn from(value: Error) -> Self {
-        io::Error::new(ErrorKind::Other, value)
+        io::Error::other(value)
     }
 }
@@ -0,0 +1,139 @@
+use crate::{Block, Error, FBErrorKind, LogicalNr};
+use std::mem::size_of;
+
+/// Reference to a slot in a slab block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SlabRef {
+    /// Block containing the slot.
+    pub block_nr: LogicalNr,
+    /// Slot index in the block.
+    pub slot: usize,
+}
+
+/// Layout of a slab block.
+///
+/// The block starts with the slot-size and the number of used slots as u32.
+/// Then follows a bitmap of used slots as u64 words, then the slots.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct SlabLayout {
+    slot_size: usize,
+    slots: usize,
+    words: usize,
+}
+
+const OFFSET_SLOT_SIZE: usize = 0;
+const OFFSET_USED: usize = size_of::<u32>();
+const OFFSET_BITMAP: usize = 2 * size_of::<u32>();
+
+impl SlabLayout {
+    /// Layout for slots of this size. None if not even one slot fits.
+    pub(crate) fn new(slot_size: usize, data_size: usize) -> Option<Self> {
+        if slot_size == 0 || data_size <= OFFSET_BITMAP {
+            return None;
+        }
+
+        let mut slots = (data_size - OFFSET_BITMAP) / slot_size;
+        loop {
+            if slots == 0 {
+                return None;
+            }
+            let words = slots.div_ceil(64);
+            if OFFSET_BITMAP + words * size_of::<u64>() + slots * slot_size <= data_size {
+                return Some(Self {
+                    slot_size,
+                    slots,
+                    words,
+                });
+            }
+            slots -= 1;
+        }
+    }
+
+    /// Layout of an existing slab block. None if the block is not initialized.
+    pub(crate) fn of(block: &Block) -> Option<Self> {
+        let slot_size = read_u32(block, OFFSET_SLOT_SIZE) as usize;
+        Self::new(slot_size, block.data.len())
+    }
+
+    /// Initialize an empty slab block.
+    pub(crate) fn init(&self, block: &mut Block) {
+        block.data.fill(0);
+        write_u32(block, OFFSET_SLOT_SIZE, self.slot_size as u32);
+    }
+
+    /// Number of used slots.
+    pub(crate) fn used(&self, block: &Block) -> usize {
+        read_u32(block, OFFSET_USED) as usize
+    }
+
+    /// Slot is in use.
+    pub(crate) fn is_used(&self, block: &Block, slot: usize) -> bool {
+        if slot >= self.slots {
+            return false;
+        }
+        let word = read_u64(block, OFFSET_BITMAP + (slot / 64) * size_of::<u64>());
+        word & (1 << (slot % 64)) != 0
+    }
+
+    /// Marks the first free slot as used and returns it.
+    pub(crate) fn alloc(&self, block: &mut Block) -> Option<usize> {
+        for w in 0..self.words {
+            let offset = OFFSET_BITMAP + w * size_of::<u64>();
+            let word = read_u64(block, offset);
+            if word == u64::MAX {
+                continue;
+            }
+            let slot = w * 64 + word.trailing_ones() as usize;
+            if slot >= self.slots {
+                return None;
+            }
+            write_u64(block, offset, word | (1 << (slot % 64)));
+            let used = self.used(block) + 1;
+            write_u32(block, OFFSET_USED, used as u32);
+            return Some(slot);
+        }
+        None
+    }
+
+    /// Marks the slot as free and zeroes it.
+    pub(crate) fn free(&self, block: &mut Block, slot: usize) -> Result<(), Error> {
+        if !self.is_used(block, slot) {
+            return Err(Error::err(FBErrorKind::InvalidSlot(block.block_nr(), slot)));
+        }
+        let offset = OFFSET_BITMAP + (slot / 64) * size_of::<u64>();
+        let word = read_u64(block, offset);
+        write_u64(block, offset, word & !(1 << (slot % 64)));
+        let used = self.used(block) - 1;
+        write_u32(block, OFFSET_USED, used as u32);
+
+        let range = self.slot_range(slot);
+        block.data[range].fill(0);
+        Ok(())
+    }
+
+    /// Byte range of the slot.
+    pub(crate) fn slot_range(&self, slot: usize) -> std::ops::Range<usize> {
+        let start = OFFSET_BITMAP + self.words * size_of::<u64>() + slot * self.slot_size;
+        start..start + self.slot_size
+    }
+}
+
+fn read_u32(block: &Block, offset: usize) -> u32 {
+    let mut buf = [0u8; size_of::<u32>()];
+    buf.copy_from_slice(&block.data[offset..offset + size_of::<u32>()]);
+    u32::from_ne_bytes(buf)
+}
+
+fn write_u32(block: &mut Block, offset: usize, v: u32) {
+    block.data[offset..offset + size_of::<u32>()].copy_from_slice(&v.to_ne_bytes());
+}
+
+fn read_u64(block: &Block, offset: usize) -> u64 {
+    let mut buf = [0u8; size_of::<u64>()];
+    buf.copy_from_slice(&block.data[offset..offset + size_of::<u64>()]);
+    u64::from_ne_bytes(buf)
+}
+
+fn write_u64(block: &mut Block, offset: usize, v: u64) {
+    block.data[offset..offset + size_of::<u64>()].copy_from_slice(&v.to_ne_bytes());
+}
@@ -1,16 +1,27 @@
+// Tests with hardcoded block-nrs or offsets assume the default layout with
+// u32 physical block-nrs.
+
 use blockfile2::{
-    Alloc, BasicFileBlocks, BlockType, BlockWrite, Error, FBErrorKind, LogicalNr, PhysicalNr,
-    State, UserBlockType,
+    Alloc, BasicFileBlocks, BlockRead, BlockStorage, BlockType, BlockWrite, DropPolicy, Error,
+    FBErrorKind, FileBlocks, LogicalNr, PhysicalNr, State, UserBlockType,
 };
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 use std::mem::{align_of, size_of};
-use std::panic::catch_unwind;
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::path::Path;
 use std::str::from_utf8;
+use std::sync::Mutex;
 
 const BLOCK_SIZE: usize = 128;
 
+#[cfg(not(feature = "physical64"))]
+#[test]
+fn test_physical_nr_as_u32() {
+    assert_eq!(PhysicalNr(7).as_u32(), 7);
+    assert_eq!(PhysicalNr(7).as_u64(), 7);
+}
+
 #[test]
 fn test_size() {
     #[repr(C)]
@@ -52,6 +63,7 @@ fn test_size() {
     println!("align_of [Three; 5] {}", align_of::<[Three; 5]>());
 }
 
+#[cfg(not(feature = "physical64"))]
 #[test]
 fn test_init() {
     let f = File::create("tmp/test_init.bin").expect("file");
@@ -67,7 +79,7 @@ fn test_init() {
 
     for t in alloc.iter_types() {
         assert_eq!(t.block_nr(), LogicalNr(1));
-        assert_eq!(t.is_dirty(), true);
+        assert!(t.is_dirty());
         assert_eq!(t.start_nr(), LogicalNr(0));
         assert_eq!(t.end_nr(), LogicalNr(30));
         assert_eq!(t.len_types(), 30);
@@ -75,7 +87,7 @@ fn test_init() {
 
     for p in alloc.iter_physical() {
         assert_eq!(p.block_nr(), LogicalNr(2));
-        assert_eq!(p.is_dirty(), true);
+        assert!(p.is_dirty());
         assert_eq!(p.start_nr(), LogicalNr(0));
         assert_eq!(p.end_nr(), LogicalNr(30));
         assert_eq!(p.len_physical(), 30);
@@ -111,16 +123,18 @@ fn test_1() -> Result<(), Error> {
     Ok(())
 }
 
+#[cfg(not(feature = "physical64"))]
 #[test]
 fn test_store() -> Result<(), Error> {
-    let mut fb = BasicFileBlocks::create(&Path::new("tmp/store.bin"), BLOCK_SIZE)?;
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/store.bin"), BLOCK_SIZE)?;
     let block = fb.alloc(BlockType::User1)?;
     block.set_dirty(true);
     fb.store()?;
 
     dbg!(&fb);
+    drop(fb);
 
-    let fb = BasicFileBlocks::load(&Path::new("tmp/store.bin"), BLOCK_SIZE)?;
+    let fb = BasicFileBlocks::load(Path::new("tmp/store.bin"), BLOCK_SIZE)?;
 
     let m = fb.block_type(LogicalNr(0)).expect("meta-data");
     assert_eq!(m.block_type(), BlockType::Header);
@@ -132,9 +146,10 @@ fn test_store() -> Result<(), Error> {
     Ok(())
 }
 
+#[cfg(not(feature = "physical64"))]
 #[test]
 fn test_illegal() -> Result<(), Error> {
-    let mut fb = BasicFileBlocks::create(&Path::new("tmp/not_dirty.bin"), BLOCK_SIZE)?;
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/not_dirty.bin"), BLOCK_SIZE)?;
     dbg!(&fb);
     let r = fb.get(LogicalNr(0));
     assert_eq!(
@@ -159,24 +174,71 @@ fn test_illegal() -> Result<(), Error> {
     Ok(())
 }
 
+#[cfg(not(feature = "physical64"))]
 #[test]
 fn test_not_dirty() -> Result<(), Error> {
-    let mut fb = BasicFileBlocks::create(&Path::new("tmp/not_dirty.bin"), BLOCK_SIZE)?;
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/not_dirty.bin"), BLOCK_SIZE)?;
     let block = fb.alloc(BlockType::User1)?;
     block.data[0] = 255;
     // forgot: block.set_dirty(true);
     fb.store()?;
+    drop(fb);
 
-    let mut fb = BasicFileBlocks::load(&Path::new("tmp/not_dirty.bin"), BLOCK_SIZE)?;
+    let mut fb = BasicFileBlocks::load(Path::new("tmp/not_dirty.bin"), BLOCK_SIZE)?;
 
-    let m = fb.get(LogicalNr(3))?;
+    let m = fb.get(LogicalNr(4))?;
     assert_eq!(m.data[0], 0);
 
     Ok(())
 }
 
+#[test]
+fn test_data_mut() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    let block = fb.alloc(BlockType::User1)?;
+    let nr = block.block_nr();
+    block.data_mut()[0] = 255;
+    assert!(block.is_dirty());
+    fb.store()?;
+
+    let mut block = fb.get_mut(nr)?;
+    assert!(!block.is_dirty());
+    block.as_mut()[1] = 254;
+    assert!(block.is_dirty());
+    fb.store()?;
+
+    let mut fb = BasicFileBlocks::load_in_memory(fb.into_bytes()?, BLOCK_SIZE)?;
+    let block = fb.get(nr)?;
+    assert_eq!(&block.as_ref()[..2], &[255, 254]);
+    assert!(!block.is_dirty());
+
+    Ok(())
+}
+
+#[test]
+fn test_get_mut_dirty() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    let nr = fb.alloc(BlockType::User1)?.block_nr();
+    fb.store()?;
+
+    assert!(!fb.get_mut(nr)?.is_dirty());
+    fb.get_mut(nr)?.data[0] = 255;
+    assert!(fb.get(nr)?.is_dirty());
+    fb.store()?;
+
+    fb.get_mut(nr)?.into_inner().data[0] = 1;
+    assert!(!fb.get(nr)?.is_dirty());
+    fb.store()?;
+
+    let mut fb = BasicFileBlocks::load_in_memory(fb.into_bytes()?, BLOCK_SIZE)?;
+    assert_eq!(fb.get(nr)?.data[0], 255);
+
+    Ok(())
+}
+
+#[cfg(not(feature = "physical64"))]
 fn store_panic(panic_: u32) -> Result<BasicFileBlocks, Error> {
-    let mut fb = BasicFileBlocks::create(&Path::new("tmp/recover.bin"), BLOCK_SIZE)?;
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/recover.bin"), BLOCK_SIZE)?;
     fb.store()?;
     for _ in 0..52 {
         let block = fb.alloc(BlockType::User1)?;
@@ -191,6 +253,7 @@ fn store_panic(panic_: u32) -> Result<BasicFileBlocks, Error> {
     BasicFileBlocks::load(Path::new("tmp/recover.bin"), BLOCK_SIZE)
 }
 
+#[cfg(not(feature = "physical64"))]
 #[cfg(debug_assertions)]
 #[test]
 fn test_recover() -> Result<(), Error> {
@@ -198,7 +261,7 @@ fn test_recover() -> Result<(), Error> {
         let fb = store_panic(i)?;
         assert_eq!(
             fb.block_type(LogicalNr(4)).expect("block_type"),
-            BlockType::NotAllocated
+            BlockType::Free
         );
     }
 
@@ -214,19 +277,20 @@ fn test_recover() -> Result<(), Error> {
 
 #[test]
 fn test_stream_1() -> Result<(), Error> {
-    let mut fb = BasicFileBlocks::create(&Path::new("tmp/stream_1.bin"), BLOCK_SIZE)?;
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/stream_1.bin"), BLOCK_SIZE)?;
 
     let mut ws = fb.append_stream(BlockType::User1)?;
-    ws.write("small_string".as_bytes()).expect("");
-    ws.write("other_string".as_bytes()).expect("");
+    ws.write_all("small_string".as_bytes()).expect("");
+    ws.write_all("other_string".as_bytes()).expect("");
     drop(ws);
 
     dbg!(&fb);
     fb.store()?;
 
     // dbg!(&fb);
+    drop(fb);
 
-    let mut fb = BasicFileBlocks::load(&Path::new("tmp/stream_1.bin"), BLOCK_SIZE)?;
+    let mut fb = BasicFileBlocks::load(Path::new("tmp/stream_1.bin"), BLOCK_SIZE)?;
 
     assert_eq!(fb.streams().head_idx(BlockType::User1), 24);
 
@@ -240,17 +304,18 @@ fn test_stream_1() -> Result<(), Error> {
 
 #[test]
 fn test_stream_2() -> Result<(), Error> {
-    let mut fb = BasicFileBlocks::create(&Path::new("tmp/stream_2.bin"), BLOCK_SIZE)?;
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/stream_2.bin"), BLOCK_SIZE)?;
 
     let mut ws = fb.append_stream(BlockType::User1)?;
-    ws.write("small_string".as_bytes()).expect("");
+    ws.write_all("small_string".as_bytes()).expect("");
     ws.write_all(&[1u8; 3 * BLOCK_SIZE]).expect("");
-    ws.write("other_string".as_bytes()).expect("");
+    ws.write_all("other_string".as_bytes()).expect("");
     drop(ws);
 
     fb.store()?;
+    drop(fb);
 
-    let mut fb = BasicFileBlocks::load(&Path::new("tmp/stream_2.bin"), BLOCK_SIZE)?;
+    let mut fb = BasicFileBlocks::load(Path::new("tmp/stream_2.bin"), BLOCK_SIZE)?;
 
     assert_eq!(fb.streams().head_idx(BlockType::User1), 24);
 
@@ -268,3 +333,3254 @@ fn test_stream_2() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[test]
+fn test_possibly_in_use() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/in_use.bin"), BLOCK_SIZE)?;
+    fb.store()?;
+
+    let r = BasicFileBlocks::load(Path::new("tmp/in_use.bin"), BLOCK_SIZE);
+    assert_eq!(r.expect_err("error").kind, FBErrorKind::PossiblyInUse);
+
+    let fb2 = BasicFileBlocks::load_force(Path::new("tmp/in_use.bin"), BLOCK_SIZE)?;
+    assert!(fb2.header().is_open());
+    let r = BasicFileBlocks::load(Path::new("tmp/in_use.bin"), BLOCK_SIZE);
+    assert_eq!(r.expect_err("error").kind, FBErrorKind::PossiblyInUse);
+
+    // both handles clear the marker, drop them before the next open.
+    drop(fb);
+    drop(fb2);
+
+    let fb = BasicFileBlocks::load(Path::new("tmp/in_use.bin"), BLOCK_SIZE)?;
+    assert!(fb.header().is_open());
+
+    Ok(())
+}
+
+#[cfg(debug_assertions)]
+#[test]
+fn test_drop_policy() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/drop_policy.bin"), BLOCK_SIZE)?
+        .with_drop_policy(DropPolicy::Panic);
+    fb.store()?;
+    drop(fb);
+
+    let mut fb = BasicFileBlocks::load(Path::new("tmp/drop_policy.bin"), BLOCK_SIZE)?
+        .with_drop_policy(DropPolicy::Panic);
+    let block = fb.alloc(BlockType::User1)?;
+    block.set_dirty(true);
+    let r = catch_unwind(move || drop(fb));
+    assert!(r.is_err());
+
+    let mut fb = BasicFileBlocks::load(Path::new("tmp/drop_policy.bin"), BLOCK_SIZE)?
+        .with_drop_policy(DropPolicy::Ignore);
+    let block = fb.alloc(BlockType::User1)?;
+    block.set_dirty(true);
+    let r = catch_unwind(move || drop(fb));
+    assert!(r.is_ok());
+
+    Ok(())
+}
+
+#[test]
+fn test_verify_block_size() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/verify_block_size.bin"), BLOCK_SIZE)?;
+    fb.verify_block_size()?;
+    fb.store()?;
+    drop(fb);
+
+    let fb = BasicFileBlocks::load(Path::new("tmp/verify_block_size.bin"), BLOCK_SIZE)?;
+    fb.verify_block_size()?;
+    assert_eq!(fb.block_size(), BLOCK_SIZE);
+
+    Ok(())
+}
+
+#[cfg(not(feature = "physical64"))]
+#[test]
+fn test_free_extents() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/free_extents.bin"), BLOCK_SIZE)?;
+    for _ in 0..6 {
+        let block = fb.alloc(BlockType::User1)?;
+        block.set_dirty(true);
+    }
+    fb.store()?;
+    assert_eq!(fb.free_extents(), Vec::new());
+
+    // [5],[6] -> *2,*3 and [9] -> *6
+    fb.free(LogicalNr(5))?;
+    fb.free(LogicalNr(6))?;
+    fb.free(LogicalNr(9))?;
+    fb.store()?;
+
+    // old types *7 and physical *8 are free too.
+    assert_eq!(
+        fb.free_extents(),
+        vec![(PhysicalNr(2), 2), (PhysicalNr(6), 3)]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_free_beside_unstored() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    let stored = fb.alloc(BlockType::User1)?.block_nr();
+    fb.get_mut(stored)?.data[0] = 1;
+    // never written, keeps physical 0.
+    let unstored = fb.alloc(BlockType::User1)?.block_nr();
+    fb.store()?;
+    assert_eq!(fb.physical_nr(unstored)?, PhysicalNr(0));
+
+    // clearing the mapping to 0 is no double assignment.
+    fb.free(stored)?;
+    fb.store()?;
+    assert_eq!(fb.physical_nr(stored)?, PhysicalNr(0));
+
+    Ok(())
+}
+
+#[cfg(not(feature = "physical64"))]
+#[test]
+fn test_map_generations() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/map_generations.bin"), BLOCK_SIZE)?;
+    fb.store()?;
+
+    let block = fb.alloc(BlockType::User1)?;
+    block.set_dirty(true);
+    fb.store()?;
+
+    let generations = fb.map_generations();
+    assert_eq!(generations.generation, fb.generation());
+    assert_eq!(generations.physical.len(), 1);
+    assert_eq!(generations.physical[0].block_nr, LogicalNr(2));
+    assert_eq!(generations.physical[0].generation, fb.generation());
+    assert!(generations.physical[0].current);
+
+    Ok(())
+}
+
+#[cfg(debug_assertions)]
+#[test]
+fn test_check_double_assign() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/double_assign.bin"), BLOCK_SIZE)?;
+    fb.set_check_double_assign(false);
+    for i in 0..1000u32 {
+        let block = fb.alloc(BlockType::User1)?;
+        block.data[0..4].copy_from_slice(&i.to_ne_bytes());
+        block.set_dirty(true);
+    }
+    fb.store()?;
+    drop(fb);
+
+    // load verifies there are no double assigned blocks.
+    let mut fb = BasicFileBlocks::load(Path::new("tmp/double_assign.bin"), BLOCK_SIZE)?;
+    let block_nrs: Vec<_> = fb
+        .iter_metadata_filter(|_nr, ty| ty == BlockType::User1)
+        .map(|(nr, _ty)| nr)
+        .collect();
+    assert_eq!(block_nrs.len(), 1000);
+    for (i, nr) in block_nrs.into_iter().enumerate() {
+        let block = fb.get(nr)?;
+        assert_eq!(block.data[0..4], (i as u32).to_ne_bytes());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_load_all() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/load_all.bin"), BLOCK_SIZE)?;
+    for _ in 0..5 {
+        let block = fb.alloc(BlockType::User1)?;
+        block.set_dirty(true);
+    }
+    // never stored
+    fb.alloc(BlockType::User2)?;
+    fb.store()?;
+    drop(fb);
+
+    let mut fb = BasicFileBlocks::load(Path::new("tmp/load_all.bin"), BLOCK_SIZE)?;
+    assert_eq!(fb.iter_blocks().count(), 0);
+    assert_eq!(fb.load_all()?, 5);
+    assert_eq!(fb.iter_blocks().count(), 5);
+    for block in fb.iter_blocks() {
+        assert_eq!(block.block_type(), BlockType::User1);
+    }
+    assert_eq!(fb.load_all()?, 0);
+    drop(fb);
+
+    // 5 blocks don't fit into a cache for 4.
+    let mut fb = BasicFileBlocks::load(Path::new("tmp/load_all.bin"), BLOCK_SIZE)?;
+    fb.set_cache_limit(Some(4));
+    assert_eq!(
+        fb.load_all().expect_err("limit").kind,
+        FBErrorKind::CacheLimit(5)
+    );
+    assert_eq!(fb.iter_blocks().count(), 0);
+    fb.set_cache_limit(Some(5));
+    assert_eq!(fb.load_all()?, 5);
+
+    Ok(())
+}
+
+#[test]
+fn test_mixed_stream_usage() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/mixed_stream.bin"), BLOCK_SIZE)?;
+    let block = fb.alloc(BlockType::User1)?;
+    block.set_dirty(true);
+
+    let r = fb.append_stream(BlockType::User1).map(|_| ());
+    assert_eq!(
+        r.expect_err("error").kind,
+        FBErrorKind::MixedStreamUsage(BlockType::User1)
+    );
+
+    // an empty stream is ok too.
+    drop(fb.append_stream(BlockType::User2)?);
+    let mut ws = fb.append_stream(BlockType::User2)?;
+    ws.write_all(b"text").expect("write");
+
+    Ok(())
+}
+
+#[test]
+fn test_unregistered_empty_tail() -> Result<(), Error> {
+    // older versions stored the empty tail without registering the stream.
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    let block = fb.alloc(BlockType::User1)?;
+    block.set_dirty(true);
+    fb.store()?;
+    assert!(!fb.streams().is_stream(BlockType::User1));
+
+    let mut fb = BasicFileBlocks::load_in_memory(fb.into_bytes()?, BLOCK_SIZE)?;
+    {
+        let mut w = fb.append_stream(BlockType::User1)?;
+        w.write_all(b"text").expect("write");
+    }
+    assert!(fb.streams().is_stream(BlockType::User1));
+    assert_eq!(fb.stream_len(BlockType::User1), 4);
+
+    // with data it is a normal block.
+    let block = fb.alloc(BlockType::User2)?;
+    block.data[0] = 1;
+    block.set_dirty(true);
+    fb.store()?;
+    let r = fb.append_stream(BlockType::User2).map(|_| ());
+    assert_eq!(
+        r.expect_err("error").kind,
+        FBErrorKind::MixedStreamUsage(BlockType::User2)
+    );
+
+    Ok(())
+}
+
+#[cfg(not(feature = "physical64"))]
+#[test]
+fn test_header_overhead_blocks() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/header_overhead.bin"), BLOCK_SIZE)?;
+    assert_eq!(fb.header_overhead_blocks(), 3);
+
+    // grow the maps
+    for _ in 0..30 {
+        fb.alloc(BlockType::User1)?;
+    }
+    assert_eq!(fb.header_overhead_blocks(), 5);
+
+    Ok(())
+}
+
+#[cfg(debug_assertions)]
+#[test]
+fn test_clear_store_panic() -> Result<(), Error> {
+    let f = File::create("tmp/clear_store_panic.bin").expect("file");
+    let mut alloc = Alloc::init(f, BLOCK_SIZE);
+    assert_eq!(alloc.store_panic_step(), 0);
+
+    alloc.set_store_panic(1);
+    assert_eq!(alloc.store_panic_step(), 1);
+    let r = catch_unwind(AssertUnwindSafe(|| alloc.store()));
+    assert!(r.is_err());
+
+    alloc.clear_store_panic();
+    assert_eq!(alloc.store_panic_step(), 0);
+    alloc.store()?;
+
+    let f = File::open("tmp/clear_store_panic.bin").expect("file");
+    let alloc = Alloc::load(f, BLOCK_SIZE)?;
+    assert_eq!(alloc.header().state(), State::Low);
+
+    Ok(())
+}
+
+#[cfg(not(feature = "physical64"))]
+#[test]
+fn test_find_blocks() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/find_blocks.bin"), BLOCK_SIZE)?;
+    for i in 0..5 {
+        let block = fb.alloc(BlockType::User1)?;
+        if i == 3 {
+            block.data[10..14].copy_from_slice(b"MAGC");
+        }
+        block.set_dirty(true);
+    }
+    fb.store()?;
+    drop(fb);
+
+    let mut fb = BasicFileBlocks::load(Path::new("tmp/find_blocks.bin"), BLOCK_SIZE)?;
+    let found = fb.find_blocks(|_nr, data| &data[10..14] == b"MAGC")?;
+    assert_eq!(found, vec![LogicalNr(7)]);
+    assert_eq!(fb.iter_blocks().count(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn test_stream_zero_length() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/stream_zero.bin"), BLOCK_SIZE)?;
+    let mut ws = fb.append_stream(BlockType::User1)?;
+    ws.write_all(&[1u8; BLOCK_SIZE]).expect("write");
+    drop(ws);
+    fb.store()?;
+    drop(fb);
+
+    let mut fb = BasicFileBlocks::load(Path::new("tmp/stream_zero.bin"), BLOCK_SIZE)?;
+    let mut ws = fb.append_stream(BlockType::User1)?;
+    let block_nr = ws.block_nr();
+    assert_eq!(ws.write(&[]).expect("write"), 0);
+    assert_eq!(ws.block_nr(), block_nr);
+    assert_eq!(ws.idx(), BLOCK_SIZE);
+    drop(ws);
+    assert!(!fb.streams().is_dirty());
+    assert_eq!(fb.streams().head_idx(BlockType::User1), BLOCK_SIZE);
+
+    let mut rd = fb.read_stream(BlockType::User1)?;
+    let mut buf = [0u8; BLOCK_SIZE];
+    rd.read_exact(&mut buf).expect("read");
+    let block_nr = rd.block_nr();
+    assert_eq!(rd.read(&mut []).expect("read"), 0);
+    assert_eq!(rd.block_nr(), block_nr);
+    assert_eq!(rd.idx(), BLOCK_SIZE);
+    assert_eq!(rd.read(&mut buf).expect("read"), 0);
+
+    Ok(())
+}
+
+#[test]
+fn test_physical_range() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/physical_range.bin"), BLOCK_SIZE)?;
+    for _ in 0..40 {
+        let block = fb.alloc(BlockType::User1)?;
+        block.set_dirty(true);
+    }
+    fb.store()?;
+
+    // first map covers 0..30
+    let range = fb.physical_range(LogicalNr(25), 10)?;
+    assert_eq!(range.len(), 10);
+    for (i, pnr) in range.into_iter().enumerate() {
+        assert_eq!(fb.physical_nr(LogicalNr(25 + i as u32))?, pnr);
+    }
+
+    let r = fb.physical_range(LogicalNr(55), 10);
+    assert_eq!(
+        r.expect_err("error").kind,
+        FBErrorKind::InvalidBlock(LogicalNr(60))
+    );
+    let r = fb.physical_range(LogicalNr(25), u32::MAX);
+    assert_eq!(
+        r.expect_err("error").kind,
+        FBErrorKind::InvalidBlock(LogicalNr(25))
+    );
+
+    Ok(())
+}
+
+#[cfg(debug_assertions)]
+#[test]
+fn test_verify_streams() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/verify_streams.bin"), BLOCK_SIZE)?;
+    let mut ws = fb.append_stream(BlockType::User1)?;
+    ws.write_all(&[1u8; 100]).expect("write");
+    drop(ws);
+    fb.store()?;
+    fb.verify_streams()?;
+
+    let mut ws = fb.append_stream(BlockType::User1)?;
+    ws.write_all(&[2u8; 100]).expect("write");
+    drop(ws);
+    fb.verify_streams()?;
+
+    // user blocks are written, the streams block is not.
+    fb.set_store_panic(2);
+    let r = catch_unwind(AssertUnwindSafe(|| fb.store()));
+    assert!(r.is_err());
+    fb.clear_store_panic();
+
+    assert_eq!(
+        fb.verify_streams().expect_err("stale").kind,
+        FBErrorKind::StreamIndexStale(BlockType::User1)
+    );
+
+    fb.store()?;
+    fb.verify_streams()?;
+
+    Ok(())
+}
+
+#[test]
+fn test_verify_streams_reload() -> Result<(), Error> {
+    let mut alloc = Alloc::init(Cursor::new(Vec::new()), BLOCK_SIZE);
+    {
+        let mut w = alloc.append_stream(BlockType::User1, 1)?;
+        w.write_all(&[1u8; 100]).expect("write");
+    }
+    alloc.store()?;
+
+    let mut alloc = Alloc::load(Cursor::new(alloc.storage_bytes()?), BLOCK_SIZE)?;
+    alloc.verify_streams()?;
+
+    // the head-idx lags behind the data.
+    alloc.set_stream_head_idx(BlockType::User1, 50)?;
+    alloc.store()?;
+
+    let mut alloc = Alloc::load(Cursor::new(alloc.storage_bytes()?), BLOCK_SIZE)?;
+    let (tail, _) = alloc
+        .iter_metadata(&|_nr, ty| ty == BlockType::User1)
+        .next()
+        .expect("tail");
+    assert!(!alloc.is_cached(tail));
+    assert_eq!(
+        alloc.verify_streams().expect_err("stale").kind,
+        FBErrorKind::StreamIndexStale(BlockType::User1)
+    );
+    assert!(!alloc.is_cached(tail));
+
+    // truncating clears the rest of the tail.
+    alloc.set_stream_head_idx(BlockType::User1, 100)?;
+    alloc.truncate_stream(BlockType::User1, 50, 1)?;
+    alloc.verify_streams()?;
+
+    Ok(())
+}
+
+#[test]
+fn test_alloc_from() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/alloc_from.bin"), BLOCK_SIZE)?;
+    let block_nr = fb.alloc_from(BlockType::User1, b"record-data")?;
+    assert!(fb.get(block_nr)?.is_dirty());
+
+    let r = fb.alloc_from(BlockType::User1, &[0u8; BLOCK_SIZE + 1]);
+    assert_eq!(
+        r.expect_err("too large").kind,
+        FBErrorKind::DataTooLarge(BLOCK_SIZE + 1)
+    );
+
+    fb.store()?;
+    drop(fb);
+
+    let mut fb = BasicFileBlocks::load(Path::new("tmp/alloc_from.bin"), BLOCK_SIZE)?;
+    let block = fb.get(block_nr)?;
+    assert_eq!(&block.data[..11], b"record-data");
+    assert!(block.data[11..].iter().all(|v| *v == 0));
+
+    Ok(())
+}
+
+#[test]
+fn test_swap_block_contents() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/swap_contents.bin"), BLOCK_SIZE)?;
+    let a = fb.alloc_from(BlockType::User1, b"aaaa")?;
+    let b = fb.alloc_from(BlockType::User2, b"bbbb")?;
+    fb.store()?;
+
+    fb.swap_block_contents(a, b)?;
+    assert!(fb.get(a)?.is_dirty());
+    assert!(fb.get(b)?.is_dirty());
+    fb.store()?;
+    drop(fb);
+
+    let mut fb = BasicFileBlocks::load(Path::new("tmp/swap_contents.bin"), BLOCK_SIZE)?;
+    assert_eq!(fb.block_type(a)?, BlockType::User1);
+    assert_eq!(fb.block_type(b)?, BlockType::User2);
+    assert_eq!(&fb.get(a)?.data[..4], b"bbbb");
+    assert_eq!(&fb.get(b)?.data[..4], b"aaaa");
+
+    Ok(())
+}
+
+#[cfg(not(feature = "physical64"))]
+#[test]
+fn test_export_type_map() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/export_type_map.bin"), BLOCK_SIZE)?;
+    for _ in 0..3 {
+        fb.alloc_from(BlockType::User1, b"one")?;
+        fb.alloc_from(BlockType::User2, b"two")?;
+    }
+    fb.free(LogicalNr(5))?;
+    fb.store()?;
+
+    let map = fb.export_type_map();
+    assert_eq!(map.len(), 30);
+    assert_eq!(map[0], (LogicalNr(0), BlockType::Header));
+    assert_eq!(map[1], (LogicalNr(1), BlockType::Types));
+    assert_eq!(map[5], (LogicalNr(5), BlockType::Free));
+    assert_eq!(map[6], (LogicalNr(6), BlockType::User1));
+
+    let allocated: Vec<_> = map
+        .into_iter()
+        .filter(|(_nr, ty)| *ty != BlockType::Free)
+        .collect();
+    let metadata: Vec<_> = fb
+        .iter_metadata()
+        .filter(|(_nr, ty)| *ty != BlockType::Free)
+        .collect();
+    assert_eq!(allocated, metadata);
+
+    Ok(())
+}
+
+#[test]
+fn test_can_rollback() -> Result<(), Error> {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    // Fails all writes to the header block.
+    #[derive(Debug)]
+    struct FailHeader(Cursor<Vec<u8>>, Arc<AtomicBool>);
+
+    impl BlockStorage for FailHeader {
+        fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+            self.0.read_at(offset, buf)
+        }
+
+        fn write_at(&mut self, offset: u64, buf: &[u8]) -> std::io::Result<()> {
+            if self.1.load(Ordering::Relaxed) && offset < BLOCK_SIZE as u64 {
+                return Err(std::io::Error::other("header"));
+            }
+            self.0.write_at(offset, buf)
+        }
+
+        fn sync(&mut self) -> std::io::Result<()> {
+            self.0.sync()
+        }
+
+        fn len(&mut self) -> std::io::Result<u64> {
+            self.0.len()
+        }
+    }
+
+    let fail = Arc::new(AtomicBool::new(false));
+    let mut alloc = Alloc::init(
+        FailHeader(Cursor::new(Vec::new()), fail.clone()),
+        BLOCK_SIZE,
+    );
+    let a = alloc.alloc_block(BlockType::User1, 1)?;
+    let b = alloc.alloc_block(BlockType::User1, 1)?;
+    alloc.store()?;
+    // nothing to go back to.
+    assert!(!alloc.can_rollback());
+
+    alloc.block_mut(a, 1)?.set_dirty(true);
+    alloc.store()?;
+    assert!(alloc.can_rollback());
+
+    // the user blocks of this store take the free blocks of the previous
+    // state, but the header is not written.
+    alloc.block_mut(a, 1)?.set_dirty(true);
+    alloc.block_mut(b, 1)?.set_dirty(true);
+    fail.store(true, Ordering::Relaxed);
+    assert!(alloc.store().is_err());
+    assert!(!alloc.can_rollback());
+
+    fail.store(false, Ordering::Relaxed);
+    alloc.block_mut(a, 1)?.set_dirty(true);
+    alloc.store()?;
+    assert!(alloc.can_rollback());
+
+    Ok(())
+}
+
+#[test]
+fn test_store_and_report() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/store_and_report.bin"), BLOCK_SIZE)?;
+    let a = fb.alloc_from(BlockType::User1, b"one")?;
+    let b = fb.alloc_from(BlockType::User1, b"two")?;
+    let c = fb.alloc_from(BlockType::User1, b"three")?;
+    fb.store()?;
+
+    fb.get_mut(a)?.set_dirty(true);
+    fb.get_mut(c)?.set_dirty(true);
+    let report = fb.store_and_report()?;
+
+    let block_nrs: Vec<_> = report.iter().map(|(nr, _pnr)| *nr).collect();
+    assert!(block_nrs.contains(&a));
+    assert!(!block_nrs.contains(&b));
+    assert!(block_nrs.contains(&c));
+    assert!(!block_nrs.contains(&LogicalNr(1)));
+    assert!(block_nrs.contains(&LogicalNr(2)));
+
+    for (nr, pnr) in report {
+        assert_ne!(pnr, PhysicalNr(0));
+        assert_eq!(fb.physical_nr(nr)?, pnr);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_read_stream_strict() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/read_strict.bin"), BLOCK_SIZE)?;
+    let mut ws = fb.append_stream(BlockType::User1)?;
+    ws.write_all(&[7u8; 200]).expect("write");
+    drop(ws);
+    fb.store()?;
+
+    let mut rd = fb.read_stream(BlockType::User1)?;
+    let mut buf = [0u8; 200];
+    rd.read_exact(&mut buf).expect("read");
+    assert_eq!(rd.read(&mut [0u8; 1]).expect("eof"), 0);
+    drop(rd);
+
+    let mut rd = fb.read_stream_strict(BlockType::User1)?;
+    let mut buf = [0u8; 200];
+    rd.read_exact(&mut buf).expect("read");
+    assert!(buf.iter().all(|v| *v == 7));
+    let err = rd.read(&mut [0u8; 1]).expect_err("eof");
+    assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+
+    Ok(())
+}
+
+#[test]
+fn test_total_stores() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/total_stores.bin"), BLOCK_SIZE)?;
+    assert_eq!(fb.total_stores(), 0);
+    fb.alloc_from(BlockType::User1, b"one")?;
+    fb.store()?;
+    fb.alloc_from(BlockType::User1, b"two")?;
+    fb.store()?;
+    assert_eq!(fb.total_stores(), 2);
+    drop(fb);
+
+    let mut fb = BasicFileBlocks::load(Path::new("tmp/total_stores.bin"), BLOCK_SIZE)?;
+    assert_eq!(fb.total_stores(), 2);
+    assert_eq!(fb.generation(), 0);
+    fb.alloc_from(BlockType::User1, b"three")?;
+    fb.store()?;
+    assert_eq!(fb.total_stores(), 3);
+    assert_eq!(fb.generation(), 1);
+    drop(fb);
+
+    let fb = BasicFileBlocks::load(Path::new("tmp/total_stores.bin"), BLOCK_SIZE)?;
+    assert_eq!(fb.total_stores(), 3);
+
+    Ok(())
+}
+
+#[cfg(not(feature = "physical64"))]
+#[test]
+fn test_check_alignments() -> Result<(), Error> {
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Aligned {
+        Wide,
+    }
+
+    impl UserBlockType for Aligned {
+        fn block_type(self) -> BlockType {
+            BlockType::User1
+        }
+
+        fn user_type(block_type: BlockType) -> Option<Self> {
+            match block_type {
+                BlockType::User1 => Some(Aligned::Wide),
+                _ => None,
+            }
+        }
+
+        fn align(self) -> usize {
+            64
+        }
+    }
+
+    let mut fb = FileBlocks::<Aligned>::create(Path::new("tmp/check_alignments.bin"), BLOCK_SIZE)?;
+    for _ in 0..4 {
+        let block = fb.alloc(Aligned::Wide)?;
+        assert!(block.block_align() >= 64);
+        block.set_dirty(true);
+    }
+    fb.check_alignments()?;
+    fb.store()?;
+    drop(fb);
+
+    let mut fb = FileBlocks::<Aligned>::load(Path::new("tmp/check_alignments.bin"), BLOCK_SIZE)?;
+    fb.get(LogicalNr(4))?;
+    fb.get(LogicalNr(7))?;
+    fb.check_alignments()?;
+
+    Ok(())
+}
+
+#[cfg(not(feature = "physical64"))]
+#[test]
+fn test_iter_physical_ranges() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/physical_ranges.bin"), BLOCK_SIZE)?;
+    for _ in 0..70 {
+        fb.alloc(BlockType::User1)?;
+    }
+
+    let ranges: Vec<_> = fb.iter_physical_ranges().collect();
+    assert_eq!(ranges.len(), 3);
+    assert_eq!(ranges[0], (LogicalNr(2), LogicalNr(0), LogicalNr(30)));
+    for w in ranges.windows(2) {
+        assert_eq!(w[0].2, w[1].1);
+    }
+    for (map_nr, _start, _end) in ranges {
+        assert_eq!(fb.block_type(map_nr)?, BlockType::Physical);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_alloc_scratch() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/alloc_scratch.bin"), BLOCK_SIZE)?;
+    fb.alloc_from(BlockType::User1, b"one")?;
+    fb.store()?;
+    let len = File::open("tmp/alloc_scratch.bin")
+        .expect("file")
+        .metadata()
+        .expect("metadata")
+        .len();
+
+    let scratch = fb.alloc_scratch();
+    assert!(scratch.is_scratch());
+    assert_eq!(scratch.block_size(), BLOCK_SIZE);
+    scratch.data.fill(0xff);
+    scratch.set_dirty(true);
+    assert_eq!(fb.scratch_len(), 1);
+    assert_eq!(fb.iter_blocks().count(), 1);
+
+    fb.store()?;
+    assert_eq!(fb.scratch_len(), 0);
+    let len2 = File::open("tmp/alloc_scratch.bin")
+        .expect("file")
+        .metadata()
+        .expect("metadata")
+        .len();
+    assert_eq!(len, len2);
+
+    Ok(())
+}
+
+#[test]
+fn test_orphan_streams() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/orphan_streams.bin"), BLOCK_SIZE)?;
+    let mut ws = fb.append_stream(BlockType::User1)?;
+    ws.write_all(&[1u8; 200]).expect("write");
+    drop(ws);
+    let mut ws = fb.append_stream(BlockType::User2)?;
+    ws.write_all(&[2u8; 20]).expect("write");
+    drop(ws);
+    fb.store()?;
+    assert!(fb.find_orphan_streams().is_empty());
+
+    let block_nrs: Vec<_> = fb
+        .iter_metadata_filter(|_nr, ty| ty == BlockType::User1)
+        .map(|(nr, _ty)| nr)
+        .collect();
+    for block_nr in block_nrs {
+        fb.free(block_nr)?;
+    }
+    assert_eq!(fb.find_orphan_streams(), vec![BlockType::User1]);
+
+    fb.clear_orphan_streams();
+    assert!(fb.find_orphan_streams().is_empty());
+    assert!(!fb.streams().is_stream(BlockType::User1));
+    assert!(fb.streams().is_stream(BlockType::User2));
+    assert_eq!(fb.streams().head_idx(BlockType::User2), 20);
+    fb.store()?;
+
+    Ok(())
+}
+
+#[test]
+fn test_compact_to() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/compact.bin"), BLOCK_SIZE)?;
+    let mut ws = fb.append_stream(BlockType::User2)?;
+    ws.write_all(&[9u8; 150]).expect("write");
+    drop(ws);
+    fb.store()?;
+
+    for round in 0..5u8 {
+        let mut block_nrs = Vec::new();
+        for i in 0..20u8 {
+            block_nrs.push(fb.alloc_from(BlockType::User1, &[round, i])?);
+        }
+        fb.store()?;
+        // keep every fourth
+        for (i, block_nr) in block_nrs.into_iter().enumerate() {
+            if i % 4 != 0 {
+                fb.free(block_nr)?;
+            }
+        }
+        fb.store()?;
+    }
+    fb.compact_to(Path::new("tmp/compact_copy.bin"))?;
+    assert!(!Path::new("tmp/compact_copy.bin.compact").exists());
+
+    // not onto itself.
+    assert_eq!(
+        fb.compact_to(Path::new("tmp/compact.bin"))
+            .expect_err("same")
+            .kind,
+        FBErrorKind::SameFile
+    );
+    assert_eq!(
+        fb.compact_to(Path::new("tmp/../tmp/./compact.bin"))
+            .expect_err("same")
+            .kind,
+        FBErrorKind::SameFile
+    );
+
+    let size = |path: &str| {
+        File::open(path)
+            .expect("file")
+            .metadata()
+            .expect("meta")
+            .len()
+    };
+    assert!(size("tmp/compact_copy.bin") < size("tmp/compact.bin"));
+
+    let mut copy = BasicFileBlocks::load(Path::new("tmp/compact_copy.bin"), BLOCK_SIZE)?;
+    let orig_meta: Vec<_> = fb.iter_metadata().collect();
+    let copy_meta: Vec<_> = copy.iter_metadata().collect();
+    assert_eq!(orig_meta, copy_meta);
+
+    for (block_nr, ty) in orig_meta {
+        if ty != BlockType::User1 {
+            continue;
+        }
+        let data = fb.get(block_nr)?.data.to_vec();
+        assert_eq!(copy.get(block_nr)?.data.as_ref(), data.as_slice());
+    }
+
+    let mut rd = copy.read_stream(BlockType::User2)?;
+    let mut buf = Vec::new();
+    rd.read_to_end(&mut buf).expect("read");
+    assert_eq!(buf, vec![9u8; 150]);
+
+    Ok(())
+}
+
+#[cfg(not(feature = "physical64"))]
+#[cfg(not(feature = "physical64"))]
+#[test]
+fn test_usable_bytes_for() -> Result<(), Error> {
+    let fb = BasicFileBlocks::create(Path::new("tmp/usable_bytes.bin"), BLOCK_SIZE)?;
+    // (128 - 8) / 4 = 30 block-nrs per map block.
+    // header + streams + 4 types + 4 physical.
+    assert_eq!(fb.usable_bytes_for(100), 90 * 128);
+    // header + streams + 1 types + 1 physical.
+    assert_eq!(fb.usable_bytes_for(30), 26 * 128);
+    // header + streams + 2 types + 2 physical.
+    assert_eq!(fb.usable_bytes_for(31), 25 * 128);
+    assert_eq!(fb.usable_bytes_for(4), 0);
+    assert_eq!(fb.usable_bytes_for(0), 0);
+    drop(fb);
+
+    // the checksum takes 4 bytes of each block.
+    // (124 - 8) / 4 = 29 block-nrs per map block.
+    let fb = BasicFileBlocks::create_with_checksums(Path::new("tmp/usable_bytes.bin"), BLOCK_SIZE)?;
+    assert_eq!(fb.usable_bytes_for(100), 90 * 124);
+    assert_eq!(fb.usable_bytes_for(30), 24 * 124);
+
+    Ok(())
+}
+
+#[test]
+fn test_reopen_writable() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/reopen_writable.bin"), BLOCK_SIZE)?;
+    fb.alloc_from(BlockType::User1, b"one")?;
+    fb.store()?;
+    drop(fb);
+
+    let mut fb = BasicFileBlocks::load_read_only(Path::new("tmp/reopen_writable.bin"), BLOCK_SIZE)?;
+    assert!(fb.is_read_only());
+    let block_nr = fb.alloc_from(BlockType::User1, b"two")?;
+    assert_eq!(
+        fb.store().expect_err("read-only").kind,
+        FBErrorKind::ReadOnly
+    );
+
+    fb.reopen_writable()?;
+    assert!(!fb.is_read_only());
+    fb.store()?;
+    drop(fb);
+
+    let mut fb = BasicFileBlocks::load(Path::new("tmp/reopen_writable.bin"), BLOCK_SIZE)?;
+    assert_eq!(&fb.get(block_nr)?.data[..3], b"two");
+
+    // opened elsewhere in the meantime.
+    let mut ro = BasicFileBlocks::load_read_only(Path::new("tmp/reopen_writable.bin"), BLOCK_SIZE)?;
+    assert_eq!(
+        ro.reopen_writable().expect_err("in use").kind,
+        FBErrorKind::PossiblyInUse
+    );
+    assert!(ro.is_read_only());
+    drop(fb);
+    ro.reopen_writable()?;
+    assert!(!ro.is_read_only());
+    drop(ro);
+
+    // a stale marker after a crash.
+    let mut ro = BasicFileBlocks::load_read_only(Path::new("tmp/reopen_writable.bin"), BLOCK_SIZE)?;
+    let fb = BasicFileBlocks::load(Path::new("tmp/reopen_writable.bin"), BLOCK_SIZE)?;
+    std::mem::forget(fb);
+    assert!(ro.reopen_writable().is_err());
+    ro.reopen_writable_force()?;
+
+    Ok(())
+}
+
+#[test]
+fn test_stream_len() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/stream_len.bin"), BLOCK_SIZE)?;
+    assert_eq!(fb.stream_len(BlockType::User1), 0);
+
+    let mut ws = fb.append_stream(BlockType::User1)?;
+    ws.write_all(&[1u8; 300]).expect("write");
+    drop(ws);
+    assert_eq!(fb.stream_len(BlockType::User1), 300);
+    fb.store()?;
+    drop(fb);
+
+    let mut fb = BasicFileBlocks::load(Path::new("tmp/stream_len.bin"), BLOCK_SIZE)?;
+    assert_eq!(fb.stream_len(BlockType::User1), 300);
+    let mut ws = fb.append_stream(BlockType::User1)?;
+    ws.write_all(&[2u8; 10]).expect("write");
+    drop(ws);
+    assert_eq!(fb.stream_len(BlockType::User1), 310);
+
+    Ok(())
+}
+
+#[test]
+fn test_checksums() -> Result<(), Error> {
+    let mut fb =
+        BasicFileBlocks::create_with_checksums(Path::new("tmp/checksums.bin"), BLOCK_SIZE)?;
+    assert!(fb.is_checksummed());
+    assert_eq!(fb.data_size(), BLOCK_SIZE - 4);
+    let mut block_nrs = Vec::new();
+    for i in 0..40u8 {
+        block_nrs.push(fb.alloc_from(BlockType::User1, &[i; BLOCK_SIZE - 4])?);
+    }
+    fb.store()?;
+    let pnr = fb.physical_nr(block_nrs[3])?;
+    drop(fb);
+
+    let mut fb = BasicFileBlocks::load(Path::new("tmp/checksums.bin"), BLOCK_SIZE)?;
+    assert!(fb.is_checksummed());
+    for (i, block_nr) in block_nrs.iter().enumerate() {
+        assert_eq!(fb.get(*block_nr)?.data.as_ref(), &[i as u8; BLOCK_SIZE - 4]);
+    }
+    drop(fb);
+
+    // flip a byte
+    {
+        let mut f = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("tmp/checksums.bin")
+            .expect("file");
+        f.seek(SeekFrom::Start(pnr.as_u64() * BLOCK_SIZE as u64 + 10))
+            .expect("seek");
+        f.write_all(&[0xff]).expect("write");
+    }
+
+    let mut fb = BasicFileBlocks::load(Path::new("tmp/checksums.bin"), BLOCK_SIZE)?;
+    assert_eq!(
+        fb.get(block_nrs[3]).expect_err("corrupt").kind,
+        FBErrorKind::ChecksumMismatch(block_nrs[3], pnr)
+    );
+    assert!(fb.get(block_nrs[4]).is_ok());
+
+    Ok(())
+}
+
+#[test]
+fn test_path() -> Result<(), Error> {
+    let fb = BasicFileBlocks::create(Path::new("tmp/path.bin"), BLOCK_SIZE)?;
+    assert_eq!(fb.path(), Path::new("tmp/path.bin"));
+    drop(fb);
+
+    let fb = BasicFileBlocks::load(Path::new("tmp/path.bin"), BLOCK_SIZE)?;
+    assert_eq!(fb.path(), Path::new("tmp/path.bin"));
+
+    Ok(())
+}
+
+#[test]
+fn test_streams_compact() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/streams_compact.bin"), BLOCK_SIZE)?;
+    for (ty, len) in [
+        (BlockType::User1, 10),
+        (BlockType::User2, 20),
+        (BlockType::User3, 30),
+    ] {
+        let mut ws = fb.append_stream(ty)?;
+        ws.write_all(&vec![1u8; len]).expect("write");
+    }
+    fb.store()?;
+    let streams_pnr = match fb.state() {
+        State::Low => fb.header().low_streams(),
+        State::High => fb.header().high_streams(),
+    };
+    drop(fb);
+
+    // clear the first entry.
+    {
+        let mut f = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("tmp/streams_compact.bin")
+            .expect("file");
+        f.seek(SeekFrom::Start(streams_pnr.as_u64() * BLOCK_SIZE as u64))
+            .expect("seek");
+        f.write_all(&[0u8; 8]).expect("write");
+    }
+
+    let fb = BasicFileBlocks::load(Path::new("tmp/streams_compact.bin"), BLOCK_SIZE)?;
+    assert!(!fb.streams().is_stream(BlockType::User1));
+    assert_eq!(fb.streams().head_idx(BlockType::User2), 20);
+    assert_eq!(fb.streams().head_idx(BlockType::User3), 30);
+    assert!(fb.streams().is_dirty());
+
+    Ok(())
+}
+
+#[test]
+fn test_cursor_storage() -> Result<(), Error> {
+    let mut alloc = Alloc::init(Cursor::new(Vec::new()), BLOCK_SIZE);
+    let nr = alloc.alloc_block(BlockType::User1, align_of::<u8>())?;
+    let block = alloc.block_mut(nr, align_of::<u8>())?;
+    block.data[0] = 42;
+    block.set_dirty(true);
+    alloc.store()?;
+
+    assert_ne!(alloc.physical_nr(nr)?, PhysicalNr(0));
+
+    alloc.discard_block(nr);
+    alloc.retain_blocks(|_k, v| !v.is_discard());
+    assert!(!alloc.is_cached(nr));
+
+    let block = alloc.block(nr, align_of::<u8>())?;
+    assert_eq!(block.data[0], 42);
+
+    Ok(())
+}
+
+#[test]
+fn test_immediate_free_capacity() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/immediate_free.bin"), BLOCK_SIZE)?;
+    let free = fb.immediate_free_capacity();
+    for _ in 0..free - 2 {
+        fb.alloc(BlockType::User1)?;
+    }
+    assert_eq!(fb.immediate_free_capacity(), 2);
+    assert_eq!(fb.iter_types().count(), 1);
+
+    // grows the chain.
+    fb.alloc(BlockType::User1)?;
+    assert_eq!(fb.iter_types().count(), 2);
+    assert!(fb.immediate_free_capacity() > 2);
+
+    Ok(())
+}
+
+#[test]
+fn test_in_memory() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    let nr = fb.alloc_from(BlockType::User1, b"in memory")?;
+    {
+        let mut w = fb.append_stream(BlockType::User2)?;
+        w.write_all(b"stream data").expect("write");
+    }
+    fb.store()?;
+    assert_eq!(fb.path(), Path::new(""));
+
+    let data = fb.into_bytes()?;
+    assert_eq!(data.len() % BLOCK_SIZE, 0);
+
+    let mut fb = BasicFileBlocks::load_in_memory(data, BLOCK_SIZE)?;
+    assert_eq!(fb.block_type(nr)?, BlockType::User1);
+    assert_eq!(&fb.get(nr)?.data[..9], b"in memory");
+
+    let mut buf = Vec::new();
+    fb.read_stream(BlockType::User2)?
+        .read_to_end(&mut buf)
+        .expect("read");
+    assert_eq!(buf.as_slice(), b"stream data");
+
+    Ok(())
+}
+
+#[test]
+fn test_prepare_commit() -> Result<(), Error> {
+    let path = Path::new("tmp/prepare_commit.bin");
+    let mut fb = BasicFileBlocks::create(path, BLOCK_SIZE)?;
+    let nr = fb.alloc_from(BlockType::User1, b"old")?;
+    fb.store()?;
+
+    let r = fb.commit();
+    assert_eq!(r.expect_err("error").kind, FBErrorKind::NotPrepared);
+
+    let mut block = fb.get_mut(nr)?;
+    block.data[..3].copy_from_slice(b"new");
+    block.set_dirty(true);
+    fb.prepare()?;
+
+    {
+        let mut old = BasicFileBlocks::load_read_only(path, BLOCK_SIZE)?;
+        assert_eq!(&old.get(nr)?.data[..3], b"old");
+    }
+
+    fb.commit()?;
+
+    let mut new = BasicFileBlocks::load_read_only(path, BLOCK_SIZE)?;
+    assert_eq!(&new.get(nr)?.data[..3], b"new");
+
+    Ok(())
+}
+
+#[test]
+fn test_copy_block() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/copy_block.bin"), BLOCK_SIZE)?;
+    let nr = fb.alloc_from(BlockType::User1, b"copy me")?;
+    fb.store()?;
+    fb.retain(|_k, _v| false);
+
+    let copy = fb.copy_block(nr)?;
+    assert_ne!(copy, nr);
+    assert_eq!(fb.block_type(copy)?, BlockType::User1);
+    assert!(fb.get(copy)?.is_dirty());
+    assert_eq!(&fb.get(copy)?.data[..7], b"copy me");
+
+    let r = fb.copy_block(LogicalNr(1));
+    assert_eq!(
+        r.expect_err("error").kind,
+        FBErrorKind::AccessDenied(LogicalNr(1))
+    );
+
+    fb.free(nr)?;
+    let r = fb.copy_block(nr);
+    assert_eq!(r.expect_err("error").kind, FBErrorKind::NotAllocated(nr));
+
+    Ok(())
+}
+
+#[test]
+fn test_iter_type() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    let mut user1 = Vec::new();
+    for _ in 0..3 {
+        user1.push(fb.alloc(BlockType::User1)?.block_nr());
+        fb.alloc(BlockType::User2)?;
+    }
+    fb.free(fb.iter_type(BlockType::User2).next().expect("user2"))?;
+
+    user1.sort();
+    assert_eq!(fb.iter_type(BlockType::User1).collect::<Vec<_>>(), user1);
+    assert_eq!(fb.iter_type(BlockType::User2).count(), 2);
+
+    Ok(())
+}
+
+#[cfg(not(feature = "physical64"))]
+#[test]
+fn test_block_stats() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    let start = fb.stats();
+    assert_eq!(start.allocated, 0);
+    assert_eq!(start.physical_file_blocks, 0);
+
+    let mut nrs = Vec::new();
+    for _ in 0..5 {
+        nrs.push(fb.alloc_from(BlockType::User1, b"x")?);
+    }
+    fb.free(nrs[0])?;
+    fb.free(nrs[1])?;
+
+    let stats = fb.stats();
+    assert_eq!(stats.allocated, 3);
+    assert_eq!(stats.free, start.free - 3);
+
+    fb.store()?;
+    let stats = fb.stats();
+    // header, types, physical + 3 user blocks. no streams written.
+    assert_eq!(stats.physical_file_blocks, 6);
+    assert_eq!(stats.wasted, 0);
+    assert_eq!(fb.file_block_count()?, 6);
+
+    fb.free(nrs[2])?;
+    fb.store()?;
+    let stats = fb.stats();
+    assert_eq!(stats.allocated, 2);
+    assert!(stats.wasted > 0);
+    assert_eq!(fb.file_block_count()?, stats.physical_file_blocks as u64);
+
+    Ok(())
+}
+
+#[test]
+fn test_next_sequence() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    assert_eq!(fb.next_sequence()?, 1);
+    assert_eq!(fb.next_sequence()?, 2);
+    fb.store()?;
+    assert_eq!(fb.next_sequence()?, 3);
+    fb.store()?;
+
+    let mut fb = BasicFileBlocks::load_in_memory(fb.into_bytes()?, BLOCK_SIZE)?;
+    assert_eq!(fb.next_sequence()?, 4);
+    fb.store()?;
+    assert_eq!(fb.next_sequence()?, 5);
+    fb.store()?;
+
+    let mut fb = BasicFileBlocks::load_in_memory(fb.into_bytes()?, BLOCK_SIZE)?;
+    assert_eq!(fb.next_sequence()?, 6);
+    // not stored, goes back to the last stored state.
+    let mut fb = BasicFileBlocks::load_in_memory(fb.into_bytes()?, BLOCK_SIZE)?;
+    assert_eq!(fb.next_sequence()?, 6);
+    drop(fb);
+
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/next_sequence.bin"), BLOCK_SIZE)?;
+    fb.store()?;
+    assert_eq!(fb.next_sequence()?, 1);
+    fb.store()?;
+    drop(fb);
+    let mut fb = BasicFileBlocks::load(Path::new("tmp/next_sequence.bin"), BLOCK_SIZE)?;
+    assert_eq!(fb.next_sequence()?, 2);
+    drop(fb);
+
+    // the counter makes the header dirty.
+    let mut alloc = Alloc::init(Cursor::new(Vec::new()), BLOCK_SIZE);
+    alloc.store()?;
+    assert!(!alloc.is_dirty());
+    assert_eq!(alloc.next_sequence(), 1);
+    assert!(alloc.is_dirty());
+    alloc.store()?;
+    assert!(!alloc.is_dirty());
+    assert_eq!(alloc.header().sequence(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn test_retype_block() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    let nr = fb.alloc_from(BlockType::User1, b"retype")?;
+    fb.store()?;
+    let pnr = fb.physical_nr(nr)?;
+
+    fb.retype_block(nr, BlockType::User2)?;
+    assert_eq!(fb.get(nr)?.block_type(), BlockType::User2);
+    fb.store()?;
+
+    let mut fb = BasicFileBlocks::load_in_memory(fb.into_bytes()?, BLOCK_SIZE)?;
+    assert_eq!(fb.block_type(nr)?, BlockType::User2);
+    assert_eq!(fb.physical_nr(nr)?, pnr);
+    assert_eq!(&fb.get(nr)?.data[..6], b"retype");
+
+    let r = fb.retype_block(nr, BlockType::Types);
+    assert_eq!(r.expect_err("error").kind, FBErrorKind::AccessDenied(nr));
+    let r = fb.retype_block(LogicalNr(2), BlockType::User1);
+    assert_eq!(
+        r.expect_err("error").kind,
+        FBErrorKind::AccessDenied(LogicalNr(2))
+    );
+    fb.free(nr)?;
+    let r = fb.retype_block(nr, BlockType::User1);
+    assert_eq!(r.expect_err("error").kind, FBErrorKind::NotAllocated(nr));
+
+    Ok(())
+}
+
+#[cfg(not(feature = "physical64"))]
+#[test]
+fn test_physical_out_of_range() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    let nr = fb.alloc_from(BlockType::User1, b"x")?;
+    fb.store()?;
+    let map_pnr = fb.header().low_physical();
+    let mut data = fb.into_bytes()?;
+
+    // entry for nr in the first physical map, after start_nr and next_nr.
+    let offset = map_pnr.as_usize() * BLOCK_SIZE + 2 * size_of::<u32>() + nr.as_usize() * 4;
+    data[offset..offset + 4].copy_from_slice(&9999u32.to_ne_bytes());
+
+    let mut fb = BasicFileBlocks::load_in_memory(data, BLOCK_SIZE)?;
+    let r = fb.get(nr).map(|_| ());
+    assert_eq!(
+        r.expect_err("error").kind,
+        FBErrorKind::PhysicalOutOfRange(nr, PhysicalNr(9999))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_iter_free() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    let free: Vec<_> = fb.iter_free().collect();
+    assert_eq!(free.len(), fb.immediate_free_capacity());
+    assert!(free.windows(2).all(|v| v[0] < v[1]));
+
+    let nr = fb.alloc(BlockType::User1)?.block_nr();
+    assert!(free.contains(&nr));
+    assert!(!fb.iter_free().any(|v| v == nr));
+
+    fb.free(nr)?;
+    assert!(fb.iter_free().any(|v| v == nr));
+
+    Ok(())
+}
+
+#[test]
+fn test_bulk_write() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/bulk_write.bin"), BLOCK_SIZE)?;
+    let data: Vec<_> = (0..100u32).map(|v| v.to_ne_bytes()).collect();
+    let nrs = fb.bulk_write(BlockType::User1, data.iter().map(|v| v.as_slice()))?;
+    assert_eq!(nrs.len(), 100);
+    assert_eq!(fb.iter_blocks().count(), 0);
+    assert_eq!(fb.total_stores(), 1);
+
+    drop(fb);
+    let mut fb = BasicFileBlocks::load(Path::new("tmp/bulk_write.bin"), BLOCK_SIZE)?;
+    for (i, nr) in nrs.into_iter().enumerate() {
+        let block = fb.get(nr)?;
+        assert_eq!(block.block_type(), BlockType::User1);
+        assert_eq!(block.data[..4], (i as u32).to_ne_bytes());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_alloc_many() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    let nrs = fb.alloc_many(BlockType::User1, 100)?;
+    assert_eq!(nrs.len(), 100);
+    assert_eq!(fb.iter_type(BlockType::User1).count(), 100);
+    assert!(fb.iter_types().count() >= 4);
+    assert!(fb.immediate_free_capacity() >= 2);
+
+    for nr in nrs {
+        fb.get_mut(nr)?.set_dirty(true);
+    }
+    fb.store()?;
+
+    Ok(())
+}
+
+#[cfg(feature = "sha2")]
+#[test]
+fn test_file_digest() -> Result<(), Error> {
+    fn make(path: &Path) -> Result<[u8; 32], Error> {
+        let mut fb = BasicFileBlocks::create(path, BLOCK_SIZE)?;
+        for i in 0..10u32 {
+            fb.alloc_from(BlockType::User1, &i.to_ne_bytes())?;
+        }
+        fb.store()?;
+        let nr = fb.iter_type(BlockType::User1).next().expect("block");
+        fb.free(nr)?;
+        fb.store()?;
+        fb.file_digest()
+    }
+
+    let d1 = make(Path::new("tmp/digest1.bin"))?;
+    let d2 = make(Path::new("tmp/digest2.bin"))?;
+    assert_eq!(d1, d2);
+
+    let mut fb = BasicFileBlocks::load(Path::new("tmp/digest1.bin"), BLOCK_SIZE)?;
+    fb.alloc_from(BlockType::User1, b"x")?;
+    fb.store()?;
+    assert_ne!(fb.file_digest()?, d1);
+
+    Ok(())
+}
+
+#[test]
+fn test_truncate_stream() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    let data: Vec<u8> = (0..3 * BLOCK_SIZE).map(|v| v as u8).collect();
+    {
+        let mut w = fb.append_stream(BlockType::User1)?;
+        w.write_all(&data).expect("write");
+    }
+    fb.store()?;
+    assert_eq!(fb.iter_type(BlockType::User1).count(), 3);
+
+    let new_len = BLOCK_SIZE + BLOCK_SIZE / 2;
+    fb.truncate_stream(BlockType::User1, new_len as u64)?;
+    assert_eq!(fb.iter_type(BlockType::User1).count(), 2);
+    assert_eq!(fb.stream_len(BlockType::User1), new_len as u64);
+    fb.store()?;
+
+    let mut buf = Vec::new();
+    fb.read_stream(BlockType::User1)?
+        .read_to_end(&mut buf)
+        .expect("read");
+    assert_eq!(buf.as_slice(), &data[..new_len]);
+
+    // appends after the cut.
+    {
+        let mut w = fb.append_stream(BlockType::User1)?;
+        w.write_all(b"more").expect("write");
+    }
+    assert_eq!(fb.stream_len(BlockType::User1), new_len as u64 + 4);
+
+    fb.truncate_stream(BlockType::User1, 0)?;
+    assert_eq!(fb.iter_type(BlockType::User1).count(), 1);
+    assert_eq!(fb.stream_len(BlockType::User1), 0);
+
+    Ok(())
+}
+
+#[test]
+fn test_present_types() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    assert!(fb.present_types().is_empty());
+
+    fb.alloc(BlockType::User5)?;
+    fb.alloc(BlockType::User1)?;
+    fb.alloc(BlockType::User5)?;
+    let nr = fb.alloc(BlockType::User3)?.block_nr();
+    fb.free(nr)?;
+
+    assert_eq!(fb.present_types(), vec![BlockType::User1, BlockType::User5]);
+
+    Ok(())
+}
+
+#[test]
+fn test_clear_stream() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    {
+        let mut w = fb.append_stream(BlockType::User1)?;
+        w.write_all(&[1u8; 3 * BLOCK_SIZE]).expect("write");
+    }
+    fb.store()?;
+
+    fb.clear_stream(BlockType::User1)?;
+    assert_eq!(fb.iter_type(BlockType::User1).count(), 0);
+    assert_eq!(fb.stream_len(BlockType::User1), 0);
+    assert!(fb.find_orphan_streams().is_empty());
+    fb.store()?;
+
+    {
+        let mut w = fb.append_stream(BlockType::User1)?;
+        w.write_all(b"fresh").expect("write");
+    }
+    let mut buf = Vec::new();
+    fb.read_stream(BlockType::User1)?
+        .read_to_end(&mut buf)
+        .expect("read");
+    assert_eq!(buf.as_slice(), b"fresh");
+
+    Ok(())
+}
+
+#[cfg(debug_assertions)]
+#[test]
+fn test_verify_no_double_mapping() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    let a = fb.alloc_from(BlockType::User1, b"a")?;
+    let b = fb.alloc_from(BlockType::User1, b"b")?;
+    fb.store()?;
+    fb.verify_no_double_mapping()?;
+
+    let (lo, hi) = (a.min(b), a.max(b));
+    let pnr = fb.physical_nr(lo)?;
+    fb.set_physical_nr_unchecked(hi, pnr)?;
+
+    let r = fb.verify_no_double_mapping();
+    assert_eq!(
+        r.expect_err("error").kind,
+        FBErrorKind::DoubleAssignedPhysicalBlock(lo, hi)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_stream_small_writes() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    let mut expect = Vec::new();
+    {
+        let mut w = fb.append_stream(BlockType::User1)?;
+        let first = w.block_nr();
+        for i in 0..1000u32 {
+            let v = (i as u16).to_ne_bytes();
+            w.write_all(&v[..1 + (i % 2) as usize]).expect("write");
+            expect.extend_from_slice(&v[..1 + (i % 2) as usize]);
+            assert_eq!(
+                w.idx(),
+                (expect.len() - 1) % BLOCK_SIZE + 1,
+                "idx after {} bytes",
+                expect.len()
+            );
+        }
+        assert_ne!(w.block_nr(), first);
+        w.flush().expect("flush");
+    }
+    assert_eq!(fb.stream_len(BlockType::User1), expect.len() as u64);
+    fb.store()?;
+
+    let mut fb = BasicFileBlocks::load_in_memory(fb.into_bytes()?, BLOCK_SIZE)?;
+    let mut buf = Vec::new();
+    fb.read_stream(BlockType::User1)?
+        .read_to_end(&mut buf)
+        .expect("read");
+    assert_eq!(buf, expect);
+
+    Ok(())
+}
+
+#[test]
+fn test_stream_position() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    {
+        let mut w = fb.append_stream(BlockType::User1)?;
+        w.write_all(b"first").expect("write");
+    }
+    let pos = fb.stream_position(BlockType::User1);
+    assert_eq!(pos.head_idx, 5);
+
+    {
+        let mut w = fb.append_stream_resume(pos)?;
+        assert_eq!(w.idx(), 5);
+        w.write_all(b" second").expect("write");
+    }
+
+    // changed by the resumed writer.
+    let r = fb.append_stream_resume(pos).map(|_| ());
+    assert_eq!(
+        r.expect_err("error").kind,
+        FBErrorKind::StreamPositionMismatch(BlockType::User1)
+    );
+
+    let pos = fb.stream_position(BlockType::User1);
+    {
+        let mut w = fb.append_stream(BlockType::User1)?;
+        w.write_all(b" elsewhere").expect("write");
+    }
+    let r = fb.append_stream_resume(pos).map(|_| ());
+    assert_eq!(
+        r.expect_err("error").kind,
+        FBErrorKind::StreamPositionMismatch(BlockType::User1)
+    );
+
+    let mut buf = Vec::new();
+    fb.read_stream(BlockType::User1)?
+        .read_to_end(&mut buf)
+        .expect("read");
+    assert_eq!(buf.as_slice(), b"first second elsewhere");
+
+    Ok(())
+}
+
+#[test]
+fn test_cache_memory_bytes() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    assert_eq!(fb.cache_memory_bytes(), 0);
+
+    let nrs = fb.alloc_many(BlockType::User1, 10)?;
+    for nr in &nrs {
+        fb.get_mut(*nr)?.set_dirty(true);
+    }
+    fb.store()?;
+    fb.retain(|_k, _v| false);
+    assert_eq!(fb.cache_memory_bytes(), 0);
+
+    for nr in &nrs[..7] {
+        fb.get(*nr)?;
+    }
+    let mem = fb.cache_memory_bytes();
+    assert!(mem >= 7 * BLOCK_SIZE);
+    assert!(mem <= 7 * (BLOCK_SIZE + 64));
+
+    Ok(())
+}
+
+#[test]
+fn test_rename_type() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    let nrs = [
+        fb.alloc_from(BlockType::User1, b"1")?,
+        fb.alloc_from(BlockType::User1, b"2")?,
+    ];
+    {
+        let mut w = fb.append_stream(BlockType::User3)?;
+        w.write_all(b"stream").expect("write");
+    }
+    fb.store()?;
+
+    assert_eq!(fb.rename_type(BlockType::User1, BlockType::User2)?, 2);
+    assert_eq!(fb.rename_type(BlockType::User3, BlockType::User4)?, 1);
+    let r = fb.rename_type(BlockType::User4, BlockType::User2);
+    assert_eq!(
+        r.expect_err("error").kind,
+        FBErrorKind::MixedStreamUsage(BlockType::User2)
+    );
+    // no merge into existing blocks.
+    let r = fb.rename_type(BlockType::User5, BlockType::User2);
+    assert_eq!(
+        r.expect_err("error").kind,
+        FBErrorKind::TypeInUse(BlockType::User2)
+    );
+    // no merge into a registered stream.
+    let mut w = fb.append_stream(BlockType::User6)?;
+    w.flush().expect("flush");
+    drop(w);
+    let r = fb.rename_type(BlockType::User2, BlockType::User6);
+    assert_eq!(
+        r.expect_err("error").kind,
+        FBErrorKind::MixedStreamUsage(BlockType::User6)
+    );
+    let r = fb.rename_type(BlockType::User2, BlockType::Types);
+    assert_eq!(
+        r.expect_err("error").kind,
+        FBErrorKind::NoUserBlockType(BlockType::Types)
+    );
+    fb.store()?;
+
+    let mut fb = BasicFileBlocks::load_in_memory(fb.into_bytes()?, BLOCK_SIZE)?;
+    for nr in nrs {
+        assert_eq!(fb.block_type(nr)?, BlockType::User2);
+    }
+    assert_eq!(fb.iter_type(BlockType::User1).count(), 0);
+    assert_eq!(fb.stream_len(BlockType::User4), 6);
+    let mut buf = Vec::new();
+    fb.read_stream(BlockType::User4)?
+        .read_to_end(&mut buf)
+        .expect("read");
+    assert_eq!(buf.as_slice(), b"stream");
+
+    Ok(())
+}
+
+#[cfg(debug_assertions)]
+#[test]
+fn test_store_steps() -> Result<(), Error> {
+    static STEPS: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+    let path = Path::new("tmp/store_steps.bin");
+    let mut fb = BasicFileBlocks::create(path, BLOCK_SIZE)?;
+    fb.set_store_hook(Some(|step| STEPS.lock().expect("lock").push(step)));
+    fb.store()?;
+    drop(fb);
+    let steps = STEPS.lock().expect("lock").clone();
+    assert_eq!(steps, vec![1, 2, 3, 4, 5, 6, 7, 100]);
+
+    for step in steps {
+        let mut fb = BasicFileBlocks::create(path, BLOCK_SIZE)?;
+        let nr = fb.alloc_from(BlockType::User1, &[1])?;
+        fb.store()?;
+        let mut block = fb.get_mut(nr)?;
+        block.data[0] = 2;
+        block.set_dirty(true);
+        fb.set_store_panic(step);
+        _ = catch_unwind(AssertUnwindSafe(|| {
+            let _ = fb.store();
+        }));
+        drop(fb);
+
+        let mut fb = BasicFileBlocks::load(path, BLOCK_SIZE)?;
+        let recovered = fb.get(nr)?.data[0];
+        let expect = if BasicFileBlocks::step_recovers_new_generation(step) {
+            2
+        } else {
+            1
+        };
+        assert_eq!(recovered, expect, "step {}", step);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_alloc_verify() -> Result<(), Error> {
+    let mut alloc = Alloc::init(Cursor::new(Vec::new()), BLOCK_SIZE);
+    alloc.verify()?;
+
+    let mut nrs = Vec::new();
+    for i in 0..100 {
+        nrs.push(alloc.alloc_block(BlockType::User1, 1)?);
+        if i % 3 == 0 {
+            alloc.free_block(nrs.remove(0))?;
+        }
+    }
+    for nr in &nrs {
+        alloc.block_mut(*nr, 1)?.set_dirty(true);
+    }
+    alloc.verify()?;
+    alloc.store()?;
+    alloc.verify()?;
+
+    #[cfg(debug_assertions)]
+    {
+        let pnr = alloc.physical_nr(nrs[0])?;
+        alloc.set_physical_nr_unchecked(nrs[1], pnr)?;
+        assert!(alloc.verify().is_err());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_slab() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+
+    let mut refs = Vec::new();
+    for i in 0..30u8 {
+        let r = fb.slab_alloc(BlockType::User1, 10)?;
+        fb.slab_get_mut(r)?.fill(i);
+        refs.push(r);
+    }
+    assert_eq!(fb.iter_type(BlockType::User1).count(), 3);
+
+    let freed: Vec<_> = refs.iter().copied().step_by(3).collect();
+    for r in &freed {
+        fb.slab_free(*r)?;
+    }
+    assert_eq!(
+        fb.slab_get(freed[0]).expect_err("error").kind,
+        FBErrorKind::InvalidSlot(freed[0].block_nr, freed[0].slot)
+    );
+
+    let mut reused = Vec::new();
+    for _ in 0..freed.len() {
+        let r = fb.slab_alloc(BlockType::User1, 10)?;
+        assert_eq!(fb.slab_get(r)?, &[0u8; 10]);
+        fb.slab_get_mut(r)?.fill(0xff);
+        reused.push(r);
+    }
+    reused.sort();
+    assert_eq!(reused, freed);
+    assert_eq!(fb.iter_type(BlockType::User1).count(), 3);
+
+    let r = fb.slab_alloc(BlockType::User1, BLOCK_SIZE);
+    assert_eq!(
+        r.expect_err("error").kind,
+        FBErrorKind::DataTooLarge(BLOCK_SIZE)
+    );
+    fb.store()?;
+
+    let mut fb = BasicFileBlocks::load_in_memory(fb.into_bytes()?, BLOCK_SIZE)?;
+    for (i, r) in refs.iter().enumerate() {
+        let expect = if i % 3 == 0 { 0xff } else { i as u8 };
+        assert_eq!(fb.slab_get(*r)?, &[expect; 10]);
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "bytemuck")]
+#[test]
+fn test_try_cast() -> Result<(), Error> {
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Words {
+        Data,
+    }
+
+    impl UserBlockType for Words {
+        fn block_type(self) -> BlockType {
+            BlockType::User1
+        }
+
+        fn user_type(block_type: BlockType) -> Option<Self> {
+            match block_type {
+                BlockType::User1 => Some(Words::Data),
+                _ => None,
+            }
+        }
+
+        fn align(self) -> usize {
+            align_of::<u64>()
+        }
+    }
+
+    let mut fb = FileBlocks::<Words>::in_memory(BLOCK_SIZE)?;
+    let block = fb.alloc(Words::Data)?;
+    let nr = block.block_nr();
+    *block.try_cast_mut::<[u32; 2]>().expect("cast") = [1, 2];
+    block.try_cast_slice_mut::<u64>().expect("cast")[15] = 42;
+    block.set_dirty(true);
+    assert!(block.try_cast::<[[u64; 16]; 2]>().is_none());
+    fb.store()?;
+
+    let mut fb = FileBlocks::<Words>::load_in_memory(fb.into_bytes()?, BLOCK_SIZE)?;
+    let block = fb.get(nr)?;
+    assert_eq!(block.try_cast::<[u32; 2]>(), Some(&[1, 2]));
+    let words = block.try_cast_slice::<u64>().expect("cast");
+    assert_eq!(words.len(), BLOCK_SIZE / 8);
+    assert_eq!(words[15], 42);
+    assert!(block.try_cast_slice::<()>().is_none());
+
+    Ok(())
+}
+
+#[cfg(feature = "bytemuck")]
+#[test]
+fn test_header_array() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    let block = fb.alloc(BlockType::User1)?;
+    let nr = block.block_nr();
+    {
+        let view = block.header_array_mut::<[u8; 3], u32>().expect("view");
+        *view.header = [1, 2, 3];
+        assert_eq!(view.array.len(), (BLOCK_SIZE - 4) / 4);
+        view.array[0] = 42;
+    }
+    block.set_dirty(true);
+    assert!(block.header_array::<[[u64; 16]; 2], u32>().is_none());
+    assert!(block.header_array::<(), u32>().is_none());
+    fb.store()?;
+
+    let mut fb = BasicFileBlocks::load_in_memory(fb.into_bytes()?, BLOCK_SIZE)?;
+    let block = fb.get(nr)?;
+    let view = block.header_array::<[u8; 3], u32>().expect("view");
+    assert_eq!(*view.header, [1, 2, 3]);
+    assert_eq!(view.array[0], 42);
+    assert_eq!(&block.data[..8], &[1, 2, 3, 0, 42, 0, 0, 0]);
+
+    Ok(())
+}
+
+#[test]
+fn test_verify_header_states() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    fb.alloc_from(BlockType::User1, b"a")?;
+    fb.store()?;
+    fb.verify_header_states()?;
+
+    fb.alloc_from(BlockType::User1, b"b")?;
+    {
+        let mut w = fb.append_stream(BlockType::User2)?;
+        w.write_all(b"stream").expect("write");
+    }
+    fb.store()?;
+    fb.verify_header_states()?;
+
+    let header = fb.header();
+    assert_ne!(header.low_types(), header.high_types());
+    assert_ne!(header.low_physical(), header.high_physical());
+
+    Ok(())
+}
+
+#[cfg(not(feature = "physical64"))]
+#[test]
+fn test_write_buffer() -> Result<(), Error> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[derive(Debug)]
+    struct Counting(Cursor<Vec<u8>>, Arc<AtomicUsize>);
+
+    impl BlockStorage for Counting {
+        fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+            self.0.read_at(offset, buf)
+        }
+
+        fn write_at(&mut self, offset: u64, buf: &[u8]) -> std::io::Result<()> {
+            self.1.fetch_add(1, Ordering::Relaxed);
+            self.0.write_at(offset, buf)
+        }
+
+        fn sync(&mut self) -> std::io::Result<()> {
+            self.0.sync()
+        }
+
+        fn len(&mut self) -> std::io::Result<u64> {
+            self.0.len()
+        }
+    }
+
+    fn make(buffer: usize) -> Result<(usize, Vec<u8>), Error> {
+        let writes = Arc::new(AtomicUsize::new(0));
+        let mut alloc = Alloc::init(
+            Counting(Cursor::new(Vec::new()), writes.clone()),
+            BLOCK_SIZE,
+        );
+        alloc.set_write_buffer_size(buffer);
+        for i in 0..20u8 {
+            let nr = alloc.alloc_block(BlockType::User1, align_of::<u8>())?;
+            let block = alloc.block_mut(nr, align_of::<u8>())?;
+            block.data.fill(i);
+            block.set_dirty(true);
+        }
+        alloc.store()?;
+        Ok((writes.load(Ordering::Relaxed), alloc.storage_bytes()?))
+    }
+
+    let (unbuffered, data0) = make(0)?;
+    let (buffered, data1) = make(16 * BLOCK_SIZE)?;
+    assert!(buffered < unbuffered / 2);
+    assert_eq!(data0, data1);
+
+    let mut alloc = Alloc::load(Cursor::new(data1), BLOCK_SIZE)?;
+    for (i, nr) in (4..24).map(LogicalNr).enumerate() {
+        let block = alloc.block(nr, align_of::<u8>())?;
+        assert!(block.data.iter().all(|v| *v == i as u8));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_write_buffer_error() -> Result<(), Error> {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    // Fails the next write of user data.
+    #[derive(Debug)]
+    struct FailOnce(Cursor<Vec<u8>>, Arc<AtomicBool>);
+
+    impl BlockStorage for FailOnce {
+        fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+            self.0.read_at(offset, buf)
+        }
+
+        fn write_at(&mut self, offset: u64, buf: &[u8]) -> std::io::Result<()> {
+            if offset >= BLOCK_SIZE as u64 && self.1.swap(false, Ordering::Relaxed) {
+                return Err(std::io::Error::other("once"));
+            }
+            self.0.write_at(offset, buf)
+        }
+
+        fn sync(&mut self) -> std::io::Result<()> {
+            self.0.sync()
+        }
+
+        fn len(&mut self) -> std::io::Result<u64> {
+            self.0.len()
+        }
+    }
+
+    let fail = Arc::new(AtomicBool::new(false));
+    let mut alloc = Alloc::init(FailOnce(Cursor::new(Vec::new()), fail.clone()), BLOCK_SIZE);
+    alloc.set_write_buffer_size(16 * BLOCK_SIZE);
+    alloc.store()?;
+
+    let mut nrs = Vec::new();
+    for i in 0..20u8 {
+        let nr = alloc.alloc_block(BlockType::User1, align_of::<u8>())?;
+        let block = alloc.block_mut(nr, align_of::<u8>())?;
+        block.data.fill(i);
+        block.set_dirty(true);
+        nrs.push(nr);
+    }
+    fail.store(true, Ordering::Relaxed);
+    assert!(alloc.store().is_err());
+    // the buffer is empty again.
+    alloc.set_write_buffer_size(4 * BLOCK_SIZE);
+    // the buffered blocks are written with the next store.
+    assert!(alloc.is_dirty());
+    alloc.store()?;
+
+    let mut alloc = Alloc::load(Cursor::new(alloc.storage_bytes()?), BLOCK_SIZE)?;
+    alloc.verify()?;
+    for (i, nr) in nrs.into_iter().enumerate() {
+        let block = alloc.block(nr, align_of::<u8>())?;
+        assert!(block.data.iter().all(|v| *v == i as u8));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_user_meta() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    let len = fb.user_meta().len();
+    assert!(len > 0);
+    assert!(fb.user_meta().iter().all(|v| *v == 0));
+
+    fb.set_user_meta(b"version 1")?;
+    fb.store()?;
+    let mut fb = BasicFileBlocks::load_in_memory(fb.into_bytes()?, BLOCK_SIZE)?;
+    assert_eq!(&fb.user_meta()[..9], b"version 1");
+
+    fb.set_user_meta(b"v2")?;
+    let r = fb.set_user_meta(&vec![1u8; len + 1]);
+    assert_eq!(
+        r.expect_err("error").kind,
+        FBErrorKind::DataTooLarge(len + 1)
+    );
+    fb.store()?;
+    let fb = BasicFileBlocks::load_in_memory(fb.into_bytes()?, BLOCK_SIZE)?;
+    assert_eq!(&fb.user_meta()[..3], b"v2\0");
+    assert_eq!(fb.user_meta().len(), len);
+
+    Ok(())
+}
+
+#[test]
+fn test_largest_free_extent() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    let nrs: Vec<_> = (0..20u8)
+        .map(|i| fb.alloc_from(BlockType::User1, &[i]))
+        .collect::<Result<_, _>>()?;
+    fb.store()?;
+    assert_eq!(fb.largest_free_extent(), (PhysicalNr(0), 0));
+
+    // holes of 1, 3 and 2 blocks.
+    for i in [2, 6, 7, 8, 12, 13] {
+        fb.free(nrs[i])?;
+    }
+    fb.store()?;
+
+    let pnr = fb.physical_nr(nrs[5])?;
+    let extents = fb.free_extents();
+    let largest = fb.largest_free_extent();
+    assert!(extents.len() >= 3);
+    assert!(extents.iter().all(|v| v.1 <= largest.1));
+    assert!(extents.contains(&largest));
+    assert_eq!(largest, (pnr + 1, 3));
+
+    Ok(())
+}
+
+#[cfg(not(feature = "physical64"))]
+#[test]
+fn test_magic() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    fb.alloc_from(BlockType::User1, b"x")?;
+    fb.store()?;
+    assert_eq!(&fb.header().magic(), b"BKF2");
+    assert_eq!(fb.header().format_version(), 1);
+    let data = fb.into_bytes()?;
+
+    // magic at 36, version at 52.
+    assert_eq!(&data[36..40], b"BKF2");
+    BasicFileBlocks::load_in_memory(data.clone(), BLOCK_SIZE)?;
+
+    let mut bad = data.clone();
+    bad[36..40].copy_from_slice(b"ABCD");
+    let r = BasicFileBlocks::load_in_memory(bad, BLOCK_SIZE);
+    assert_eq!(r.expect_err("error").kind, FBErrorKind::BadMagic);
+
+    let mut bad = data.clone();
+    bad[52..54].copy_from_slice(&7u16.to_ne_bytes());
+    let r = BasicFileBlocks::load_in_memory(bad, BLOCK_SIZE);
+    assert_eq!(
+        r.expect_err("error").kind,
+        FBErrorKind::UnsupportedFormatVersion(7)
+    );
+
+    // legacy files have no magic.
+    let mut legacy = data;
+    legacy[36..40].fill(0);
+    legacy[52..54].fill(0);
+    let fb = BasicFileBlocks::load_in_memory(legacy, BLOCK_SIZE)?;
+    assert_eq!(fb.header().format_version(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn test_alloc_contiguous() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    let nrs: Vec<_> = (0..20u8)
+        .map(|i| fb.alloc_from(BlockType::User1, &[i]))
+        .collect::<Result<_, _>>()?;
+    fb.store()?;
+    // fragment the free space.
+    for i in (0..20).step_by(2) {
+        fb.free(nrs[i])?;
+    }
+    fb.store()?;
+
+    let block_nrs = fb.alloc_contiguous(BlockType::User2, 8)?;
+    for (i, nr) in block_nrs.iter().enumerate() {
+        fb.get_mut(*nr)?.data[0] = i as u8;
+    }
+    fb.alloc_from(BlockType::User1, b"other")?;
+    fb.store()?;
+
+    let first = fb.physical_nr(block_nrs[0])?;
+    for (i, nr) in block_nrs.iter().enumerate() {
+        assert_eq!(fb.physical_nr(*nr)?, first + i as u32);
+    }
+    fb.verify_no_double_mapping()?;
+
+    let mut fb = BasicFileBlocks::load_in_memory(fb.into_bytes()?, BLOCK_SIZE)?;
+    for (i, nr) in block_nrs.iter().enumerate() {
+        assert_eq!(fb.get(*nr)?.data[0], i as u8);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_alloc_contiguous_run() -> Result<(), Error> {
+    use std::sync::Arc;
+
+    // Records offset and length of each write.
+    #[derive(Debug)]
+    struct Recording(Cursor<Vec<u8>>, Arc<Mutex<Vec<(u64, usize)>>>);
+
+    impl BlockStorage for Recording {
+        fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+            self.0.read_at(offset, buf)
+        }
+
+        fn write_at(&mut self, offset: u64, buf: &[u8]) -> std::io::Result<()> {
+            self.1.lock().expect("lock").push((offset, buf.len()));
+            self.0.write_at(offset, buf)
+        }
+
+        fn sync(&mut self) -> std::io::Result<()> {
+            self.0.sync()
+        }
+
+        fn len(&mut self) -> std::io::Result<u64> {
+            self.0.len()
+        }
+    }
+
+    let writes = Arc::new(Mutex::new(Vec::new()));
+    let mut alloc = Alloc::init(
+        Recording(Cursor::new(Vec::new()), writes.clone()),
+        BLOCK_SIZE,
+    );
+    let nrs = alloc.alloc_blocks(BlockType::User1, 1, 20)?;
+    alloc.store()?;
+    // the free block-nrs are handed out in a different order.
+    for nr in &nrs[4..12] {
+        alloc.free_block(*nr)?;
+    }
+    alloc.store()?;
+
+    let block_nrs = alloc.alloc_contiguous(BlockType::User2, 1, 8)?;
+    assert!(block_nrs.windows(2).all(|v| v[0] < v[1]));
+    writes.lock().expect("lock").clear();
+    alloc.store()?;
+
+    let first = alloc.physical_nr(block_nrs[0])?;
+    for (i, nr) in block_nrs.iter().enumerate() {
+        assert_eq!(alloc.physical_nr(*nr)?, first + i as u32);
+    }
+    let run = (first.as_u64() * BLOCK_SIZE as u64, 8 * BLOCK_SIZE);
+    assert!(writes.lock().expect("lock").contains(&run));
+
+    Ok(())
+}
+
+#[test]
+fn test_init_with_state() -> Result<(), Error> {
+    for (start, next) in [(State::Low, State::High), (State::High, State::Low)] {
+        let mut alloc = Alloc::init_with_state(Cursor::new(Vec::new()), BLOCK_SIZE, start);
+        assert_eq!(alloc.header().state(), start);
+        let nr = alloc.alloc_block(BlockType::User1, align_of::<u8>())?;
+        let block = alloc.block_mut(nr, align_of::<u8>())?;
+        block.data[0] = 42;
+        block.set_dirty(true);
+        alloc.store()?;
+        assert_eq!(alloc.header().state(), next);
+
+        let mut alloc = Alloc::load(Cursor::new(alloc.storage_bytes()?), BLOCK_SIZE)?;
+        assert_eq!(alloc.header().state(), next);
+        assert_eq!(alloc.block(nr, align_of::<u8>())?.data[0], 42);
+        alloc.store()?;
+        assert_eq!(alloc.header().state(), start);
+    }
+
+    Ok(())
+}
+
+#[cfg(not(feature = "physical64"))]
+#[test]
+fn test_reset() -> Result<(), Error> {
+    let path = Path::new("tmp/reset.bin");
+    let mut fb = BasicFileBlocks::create(path, BLOCK_SIZE)?;
+    for i in 0..50u8 {
+        fb.alloc_from(BlockType::User1, &[i])?;
+    }
+    {
+        let mut w = fb.append_stream(BlockType::User2)?;
+        w.write_all(&[1u8; 3 * BLOCK_SIZE]).expect("write");
+    }
+    fb.set_user_meta(b"meta")?;
+    assert_eq!(fb.next_sequence()?, 1);
+    fb.store()?;
+    assert!(std::fs::metadata(path).expect("len").len() > 50 * BLOCK_SIZE as u64);
+
+    fb.reset()?;
+    assert_eq!(&fb.user_meta()[..4], b"meta");
+    assert_eq!(fb.stats().allocated, 0);
+    assert_eq!(fb.stream_len(BlockType::User2), 0);
+    assert_eq!(fb.file_block_count()?, 3);
+    drop(fb);
+
+    let mut fb = BasicFileBlocks::load(path, BLOCK_SIZE)?;
+    assert_eq!(fb.stats().allocated, 0);
+    assert_eq!(fb.iter_type(BlockType::User1).count(), 0);
+    assert_eq!(fb.header().magic(), *b"BKF2");
+    assert_eq!(&fb.user_meta()[..4], b"meta");
+    assert_eq!(fb.next_sequence()?, 2);
+    let nr = fb.alloc_from(BlockType::User1, b"new")?;
+    assert_eq!(nr, LogicalNr(4));
+    fb.store()?;
+
+    Ok(())
+}
+
+#[test]
+fn test_reset_no_set_len() -> Result<(), Error> {
+    #[derive(Debug)]
+    struct NoSetLen(Cursor<Vec<u8>>);
+
+    impl BlockStorage for NoSetLen {
+        fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+            self.0.read_at(offset, buf)
+        }
+
+        fn write_at(&mut self, offset: u64, buf: &[u8]) -> std::io::Result<()> {
+            self.0.write_at(offset, buf)
+        }
+
+        fn sync(&mut self) -> std::io::Result<()> {
+            self.0.sync()
+        }
+
+        fn len(&mut self) -> std::io::Result<u64> {
+            self.0.len()
+        }
+    }
+
+    let mut alloc = Alloc::init(NoSetLen(Cursor::new(Vec::new())), BLOCK_SIZE);
+    alloc.store()?;
+    assert_eq!(
+        alloc.reset().expect_err("set_len").kind,
+        FBErrorKind::Truncate(std::io::Error::from(std::io::ErrorKind::Unsupported))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_store_no_sync() -> Result<(), Error> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[derive(Debug)]
+    struct SyncCounting(Cursor<Vec<u8>>, Arc<AtomicUsize>);
+
+    impl BlockStorage for SyncCounting {
+        fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+            self.0.read_at(offset, buf)
+        }
+
+        fn write_at(&mut self, offset: u64, buf: &[u8]) -> std::io::Result<()> {
+            self.0.write_at(offset, buf)
+        }
+
+        fn sync(&mut self) -> std::io::Result<()> {
+            self.1.fetch_add(1, Ordering::Relaxed);
+            self.0.sync()
+        }
+
+        fn len(&mut self) -> std::io::Result<u64> {
+            self.0.len()
+        }
+    }
+
+    let syncs = Arc::new(AtomicUsize::new(0));
+    let mut alloc = Alloc::init(
+        SyncCounting(Cursor::new(Vec::new()), syncs.clone()),
+        BLOCK_SIZE,
+    );
+    let mut nrs = Vec::new();
+    for i in 0..3u8 {
+        let nr = alloc.alloc_block(BlockType::User1, align_of::<u8>())?;
+        let block = alloc.block_mut(nr, align_of::<u8>())?;
+        block.data[0] = i;
+        block.set_dirty(true);
+        alloc.store_no_sync()?;
+        nrs.push(nr);
+    }
+    assert_eq!(syncs.load(Ordering::Relaxed), 0);
+    alloc.sync()?;
+    assert_eq!(syncs.load(Ordering::Relaxed), 1);
+    alloc.store()?;
+    assert_eq!(syncs.load(Ordering::Relaxed), 3);
+
+    let mut alloc = Alloc::load(Cursor::new(alloc.storage_bytes()?), BLOCK_SIZE)?;
+    for (i, nr) in nrs.into_iter().enumerate() {
+        assert_eq!(alloc.block(nr, align_of::<u8>())?.data[0], i as u8);
+    }
+
+    Ok(())
+}
+
+#[cfg(not(feature = "physical64"))]
+#[test]
+fn test_format_version() -> Result<(), Error> {
+    let path = Path::new("tmp/format_version.bin");
+    let mut fb = BasicFileBlocks::create(path, BLOCK_SIZE)?;
+    fb.alloc_from(BlockType::User1, b"x")?;
+    fb.store()?;
+    assert_eq!(fb.format_version(), 1);
+    drop(fb);
+
+    let fb = BasicFileBlocks::load(path, BLOCK_SIZE)?;
+    assert_eq!(fb.format_version(), 1);
+    drop(fb);
+
+    // version at 52.
+    for version in [0u16, 2, u16::MAX] {
+        let mut f = File::options()
+            .read(true)
+            .write(true)
+            .open(path)
+            .expect("file");
+        f.seek(SeekFrom::Start(52)).expect("seek");
+        f.write_all(&version.to_ne_bytes()).expect("write");
+        drop(f);
+
+        let r = BasicFileBlocks::load(path, BLOCK_SIZE);
+        assert_eq!(
+            r.expect_err("error").kind,
+            FBErrorKind::UnsupportedFormatVersion(version)
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_flush_on_drop() -> Result<(), Error> {
+    let path = Path::new("tmp/flush_on_drop.bin");
+    let mut fb = BasicFileBlocks::create(path, BLOCK_SIZE)?;
+    fb.flush_on_drop(true);
+    assert_eq!(fb.drop_policy(), DropPolicy::Store);
+    let nr = fb.alloc_from(BlockType::User1, b"saved")?;
+    drop(fb);
+
+    let mut fb = BasicFileBlocks::load(path, BLOCK_SIZE)?;
+    assert_eq!(&fb.get(nr)?.data[..5], b"saved");
+    fb.flush_on_drop(false);
+    assert_eq!(fb.drop_policy(), DropPolicy::Ignore);
+    let nr2 = fb.alloc_from(BlockType::User1, b"lost")?;
+    drop(fb);
+
+    let fb = BasicFileBlocks::load(path, BLOCK_SIZE)?;
+    assert_eq!(fb.block_type(nr2)?, BlockType::Free);
+
+    Ok(())
+}
+
+#[cfg(not(feature = "physical64"))]
+#[test]
+fn test_migrate_format() -> Result<(), Error> {
+    let path = Path::new("tmp/migrate_format.bin");
+    let bak = Path::new("tmp/migrate_format.bin.bak");
+    let _ = std::fs::remove_file(bak);
+
+    let mut fb = BasicFileBlocks::create(path, BLOCK_SIZE)?;
+    let nrs: Vec<_> = (0..10u8)
+        .map(|i| fb.alloc_from(BlockType::User1, &[i; 8]))
+        .collect::<Result<_, _>>()?;
+    fb.free(nrs[3])?;
+    fb.store()?;
+    drop(fb);
+
+    // simulate an old file without magic and version at 36 and 52.
+    let mut f = File::options()
+        .read(true)
+        .write(true)
+        .open(path)
+        .expect("file");
+    f.seek(SeekFrom::Start(36)).expect("seek");
+    f.write_all(&[0u8; 4]).expect("write");
+    f.seek(SeekFrom::Start(52)).expect("seek");
+    f.write_all(&[0u8; 2]).expect("write");
+    drop(f);
+    let fb = BasicFileBlocks::load(path, BLOCK_SIZE)?;
+    assert!(!fb.header().is_current_format());
+    drop(fb);
+
+    let mut fb = BasicFileBlocks::migrate_format(path, BLOCK_SIZE)?;
+    assert!(!Path::new("tmp/migrate_format.bin.migrate").exists());
+    assert!(fb.header().is_current_format());
+    assert_eq!(fb.format_version(), 1);
+    for (i, nr) in nrs.iter().enumerate() {
+        if i == 3 {
+            assert_eq!(fb.block_type(*nr)?, BlockType::Free);
+        } else {
+            assert_eq!(fb.get(*nr)?.data[..8], [i as u8; 8]);
+        }
+    }
+    drop(fb);
+
+    let old = BasicFileBlocks::load(bak, BLOCK_SIZE)?;
+    assert!(!old.header().is_current_format());
+    drop(old);
+
+    // no-op for the current format.
+    std::fs::remove_file(bak).expect("remove");
+    let fb = BasicFileBlocks::migrate_format(path, BLOCK_SIZE)?;
+    assert!(fb.header().is_current_format());
+    assert!(!bak.exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_is_cached() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    let nr = fb.alloc_from(BlockType::User1, b"x")?;
+    assert!(fb.is_cached(nr));
+    fb.store()?;
+
+    let mut fb = BasicFileBlocks::load_in_memory(fb.into_bytes()?, BLOCK_SIZE)?;
+    // checking doesn't load.
+    assert!(!fb.is_cached(nr));
+    assert_eq!(fb.stats().allocated, 1);
+    assert!(!fb.is_cached(nr));
+    fb.get(nr)?;
+    assert!(fb.is_cached(nr));
+    fb.discard(nr);
+    assert!(!fb.is_cached(nr));
+
+    Ok(())
+}
+
+#[test]
+fn test_cache_limit() -> Result<(), Error> {
+    let mut alloc = Alloc::init(Cursor::new(Vec::new()), BLOCK_SIZE);
+    let mut nrs = Vec::new();
+    for i in 0..20u8 {
+        let nr = alloc.alloc_block(BlockType::User1, align_of::<u8>())?;
+        let block = alloc.block_mut(nr, align_of::<u8>())?;
+        block.data[0] = i;
+        block.set_dirty(true);
+        nrs.push(nr);
+    }
+    alloc.set_cache_limit(Some(4));
+    // all dirty.
+    assert_eq!(alloc.iter_blocks().count(), 20);
+    alloc.store()?;
+
+    alloc.set_cache_limit(Some(4));
+    assert_eq!(alloc.iter_blocks().count(), 4);
+    for (i, nr) in nrs.iter().enumerate() {
+        assert_eq!(alloc.block(*nr, align_of::<u8>())?.data[0], i as u8);
+        assert!(alloc.iter_blocks().count() <= 4);
+    }
+    // the last ones are kept.
+    assert!(alloc.is_cached(nrs[19]));
+    assert!(alloc.is_cached(nrs[16]));
+    assert!(!alloc.is_cached(nrs[15]));
+
+    // dirty blocks stay.
+    for nr in &nrs[..6] {
+        alloc.block_mut(*nr, align_of::<u8>())?.set_dirty(true);
+    }
+    for nr in &nrs[6..] {
+        alloc.block(*nr, align_of::<u8>())?;
+        let clean = alloc.iter_blocks().filter(|v| !v.is_dirty()).count();
+        assert!(clean <= 1);
+    }
+    for nr in &nrs[..6] {
+        assert!(alloc.is_cached(*nr));
+    }
+
+    // a block used again moves to the end.
+    alloc.store()?;
+    for nr in &nrs[10..] {
+        alloc.block(nrs[0], align_of::<u8>())?;
+        alloc.block(*nr, align_of::<u8>())?;
+        assert!(alloc.iter_blocks().count() <= 4);
+    }
+    assert!(alloc.is_cached(nrs[0]));
+    assert!(!alloc.is_cached(nrs[1]));
+    assert!(!alloc.is_cached(nrs[10]));
+
+    Ok(())
+}
+
+#[test]
+fn test_release_clean() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    let nrs: Vec<_> = (0..10u8)
+        .map(|i| fb.alloc_from(BlockType::User1, &[i]))
+        .collect::<Result<_, _>>()?;
+    fb.store()?;
+    fb.get_mut(nrs[0])?.set_dirty(true);
+    fb.alloc_scratch();
+
+    fb.release_clean();
+    assert_eq!(fb.scratch_len(), 1);
+    assert!(fb.is_cached(nrs[0]));
+    for nr in &nrs[1..] {
+        assert!(!fb.is_cached(*nr));
+    }
+    assert_eq!(fb.get(nrs[5])?.data[0], 5);
+    fb.store()?;
+
+    Ok(())
+}
+
+#[test]
+fn test_check_block_size() -> Result<(), Error> {
+    let r = BasicFileBlocks::create(Path::new("tmp/check_block_size.bin"), 24);
+    assert_eq!(
+        r.expect_err("error").kind,
+        FBErrorKind::InvalidBlockSize(24)
+    );
+    let r = BasicFileBlocks::in_memory(24);
+    assert_eq!(
+        r.expect_err("error").kind,
+        FBErrorKind::InvalidBlockSize(24)
+    );
+    let r = Alloc::load(Cursor::new(vec![0u8; 1024]), 24);
+    assert_eq!(
+        r.expect_err("error").kind,
+        FBErrorKind::InvalidBlockSize(24)
+    );
+
+    Alloc::check_block_size(BLOCK_SIZE, true)?;
+    BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_append_stream_aligned() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    {
+        let mut w = fb.append_stream_aligned(BlockType::User1)?;
+        w.write_all(b"first").expect("write");
+    }
+    {
+        let mut w = fb.append_stream_aligned(BlockType::User1)?;
+        assert_eq!(w.idx(), 0);
+        w.write_all(b"second").expect("write");
+    }
+    fb.store()?;
+
+    let mut fb = BasicFileBlocks::load_in_memory(fb.into_bytes()?, BLOCK_SIZE)?;
+    assert_eq!(fb.iter_type(BlockType::User1).count(), 2);
+    assert_eq!(fb.stream_len(BlockType::User1), (BLOCK_SIZE + 6) as u64);
+
+    let mut buf = Vec::new();
+    fb.read_stream(BlockType::User1)?
+        .read_to_end(&mut buf)
+        .expect("read");
+    assert_eq!(&buf[..5], b"first");
+    assert!(buf[5..BLOCK_SIZE].iter().all(|v| *v == 0));
+    assert_eq!(&buf[BLOCK_SIZE..], b"second");
+
+    Ok(())
+}
+
+#[test]
+fn test_remaining_in_block() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    let mut w = fb.append_stream(BlockType::User1)?;
+    assert_eq!(w.block_size(), BLOCK_SIZE);
+    assert_eq!(w.remaining_in_block(), BLOCK_SIZE);
+
+    w.write_all(&[1u8; 100]).expect("write");
+    assert_eq!(w.remaining_in_block(), BLOCK_SIZE - 100);
+
+    w.write_all(&[2u8; 28]).expect("write");
+    assert_eq!(w.remaining_in_block(), 0);
+
+    w.write_all(&[3u8; 10]).expect("write");
+    assert_eq!(w.remaining_in_block(), BLOCK_SIZE - 10);
+
+    Ok(())
+}
+
+#[test]
+fn test_raw_block_type() -> Result<(), Error> {
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Only {
+        Data,
+    }
+
+    impl UserBlockType for Only {
+        fn block_type(self) -> BlockType {
+            BlockType::User1
+        }
+
+        fn user_type(block_type: BlockType) -> Option<Self> {
+            match block_type {
+                BlockType::User1 => Some(Only::Data),
+                _ => None,
+            }
+        }
+
+        fn align(self) -> usize {
+            8
+        }
+    }
+
+    let mut fb = FileBlocks::<Only>::in_memory(BLOCK_SIZE)?;
+    let nr = fb.alloc(Only::Data)?.block_nr();
+
+    assert!(fb.block_type(LogicalNr(0)).is_err());
+    assert_eq!(fb.raw_block_type(LogicalNr(0))?, BlockType::Header);
+    assert_eq!(fb.raw_block_type(LogicalNr(1))?, BlockType::Types);
+    assert_eq!(fb.raw_block_type(LogicalNr(2))?, BlockType::Physical);
+    assert_eq!(fb.block_type(nr)?, Only::Data);
+    assert_eq!(fb.raw_block_type(nr)?, BlockType::User1);
+    assert!(fb.raw_block_type(LogicalNr(100_000)).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_double_free() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    let nr = fb.alloc(BlockType::User1)?.block_nr();
+    fb.free(nr)?;
+
+    let r = fb.free(nr);
+    assert_eq!(
+        r.expect_err("double free").kind,
+        FBErrorKind::NotAllocated(nr)
+    );
+
+    let a = fb.alloc(BlockType::User1)?.block_nr();
+    let b = fb.alloc(BlockType::User1)?.block_nr();
+    assert_eq!(a, nr);
+    assert_ne!(a, b);
+
+    Ok(())
+}
+
+#[test]
+fn test_free_internal() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    fb.alloc(BlockType::User1)?.set_dirty(true);
+    fb.store()?;
+
+    for nr in 0..=3 {
+        let r = fb.free(LogicalNr(nr));
+        assert_eq!(
+            r.expect_err("internal").kind,
+            FBErrorKind::AccessDenied(LogicalNr(nr))
+        );
+    }
+    assert_eq!(fb.raw_block_type(LogicalNr(2))?, BlockType::Physical);
+    assert_eq!(fb.raw_block_type(LogicalNr(3))?, BlockType::Streams);
+
+    fb.store()?;
+    let fb = BasicFileBlocks::load_in_memory(fb.into_bytes()?, BLOCK_SIZE)?;
+    assert_eq!(fb.raw_block_type(LogicalNr(2))?, BlockType::Physical);
+
+    Ok(())
+}
+
+#[cfg(not(feature = "physical64"))]
+#[test]
+fn test_for_each_block_of_type() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    for i in 0..5u8 {
+        let ty = if i % 2 == 0 {
+            BlockType::User1
+        } else {
+            BlockType::User2
+        };
+        let block = fb.alloc(ty)?;
+        block.data[0] = i;
+        block.set_dirty(true);
+    }
+    fb.store()?;
+
+    let mut fb = BasicFileBlocks::load_in_memory(fb.into_bytes()?, BLOCK_SIZE)?;
+    let kept = fb.iter_type(BlockType::User1).next().expect("block");
+    fb.get(kept)?;
+
+    let mut seen = Vec::new();
+    fb.for_each_block_of_type(BlockType::User1, |nr, block| {
+        seen.push((nr, block.data[0]));
+    })?;
+    assert_eq!(
+        seen,
+        vec![(LogicalNr(4), 0), (LogicalNr(6), 2), (LogicalNr(8), 4)]
+    );
+
+    assert!(fb.is_cached(kept));
+    assert!(!fb.is_cached(LogicalNr(6)));
+    assert!(!fb.is_cached(LogicalNr(8)));
+
+    Ok(())
+}
+
+#[test]
+fn test_block_generation() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    let a = fb.alloc(BlockType::User1)?.block_nr();
+    let b = fb.alloc(BlockType::User1)?.block_nr();
+    fb.get_mut(a)?.set_dirty(true);
+    fb.get_mut(b)?.set_dirty(true);
+    fb.store()?;
+    let first = fb.generation();
+    assert_eq!(fb.block_generation(a)?, first);
+
+    fb.get_mut(b)?.set_dirty(true);
+    fb.store()?;
+    assert!(!fb.stored_since(a, first)?);
+    assert!(fb.stored_since(b, first)?);
+    assert_eq!(fb.block_generation(b)?, fb.generation());
+
+    fb.discard(a);
+    assert_eq!(fb.block_generation(a)?, 0);
+
+    fb.free(b)?;
+    assert_eq!(
+        fb.block_generation(b).expect_err("free").kind,
+        FBErrorKind::NotAllocated(b)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_reload() -> Result<(), Error> {
+    let path = Path::new("tmp/reload.bin");
+    let mut fb = BasicFileBlocks::create(path, BLOCK_SIZE)?;
+    let nr = fb.alloc_from(BlockType::User1, b"one")?;
+    fb.store()?;
+
+    let mut reader = BasicFileBlocks::load_read_only(path, BLOCK_SIZE)?;
+    assert_eq!(&reader.get(nr)?.data[..3], b"one");
+
+    fb.get_mut(nr)?.data[..3].copy_from_slice(b"two");
+    fb.get_mut(nr)?.set_dirty(true);
+    let nr2 = fb.alloc_from(BlockType::User2, b"new")?;
+    fb.store()?;
+
+    assert_eq!(&reader.get(nr)?.data[..3], b"one");
+    reader.reload()?;
+    assert_eq!(&reader.get(nr)?.data[..3], b"two");
+    assert_eq!(reader.raw_block_type(nr2)?, BlockType::User2);
+
+    reader.get_mut(nr)?.set_dirty(true);
+    assert_eq!(
+        reader.reload().expect_err("dirty").kind,
+        FBErrorKind::UnsavedChanges
+    );
+    reader.reload_force()?;
+    assert!(!reader.is_cached(nr));
+    assert_eq!(&reader.get(nr)?.data[..3], b"two");
+
+    Ok(())
+}
+
+#[test]
+fn test_block_write_at() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    let nr = fb.alloc(BlockType::User1)?.block_nr();
+    fb.store()?;
+
+    let mut block = fb.get_mut(nr)?;
+    assert!(!block.is_dirty());
+    block.write_at(10, b"abc")?;
+    assert!(block.is_dirty());
+    block.write_at(BLOCK_SIZE - 2, b"yz")?;
+
+    assert_eq!(
+        block.write_at(BLOCK_SIZE - 1, b"yz").expect_err("oob").kind,
+        FBErrorKind::OutOfBounds(BLOCK_SIZE - 1, 2)
+    );
+    assert_eq!(
+        block.write_at(usize::MAX, b"x").expect_err("oob").kind,
+        FBErrorKind::OutOfBounds(usize::MAX, 1)
+    );
+    fb.store()?;
+
+    let mut fb = BasicFileBlocks::load_in_memory(fb.into_bytes()?, BLOCK_SIZE)?;
+    let block = fb.get(nr)?;
+    let mut buf = [0u8; 3];
+    block.read_at(10, &mut buf)?;
+    assert_eq!(&buf, b"abc");
+    let mut buf = [0u8; 2];
+    block.read_at(BLOCK_SIZE - 2, &mut buf)?;
+    assert_eq!(&buf, b"yz");
+    let mut buf = [0u8; 4];
+    assert_eq!(
+        block
+            .read_at(BLOCK_SIZE - 2, &mut buf)
+            .expect_err("oob")
+            .kind,
+        FBErrorKind::OutOfBounds(BLOCK_SIZE - 2, 4)
+    );
+
+    Ok(())
+}
+
+#[cfg(not(feature = "physical64"))]
+#[test]
+fn test_truncate_file() -> Result<(), Error> {
+    let path = Path::new("tmp/truncate_file.bin");
+    let mut fb = BasicFileBlocks::create(path, BLOCK_SIZE)?;
+    let mut nrs = Vec::new();
+    for i in 0..20u8 {
+        nrs.push(fb.alloc_from(BlockType::User1, &[i])?);
+    }
+    fb.store()?;
+
+    for nr in &nrs[10..] {
+        fb.free(*nr)?;
+    }
+    assert_eq!(
+        fb.truncate_file().expect_err("dirty").kind,
+        FBErrorKind::UnsavedChanges
+    );
+    fb.store()?;
+    // the next stores write the maps to the free blocks at the start.
+    // the inactive state keeps the old maps at the end for one more store.
+    for _ in 0..2 {
+        fb.get_mut(nrs[0])?.set_dirty(true);
+        fb.store()?;
+    }
+
+    let before = fb.file_block_count()?;
+    let removed = fb.truncate_file()?;
+    assert!(removed > 0);
+    assert_eq!(fb.file_block_count()?, before - removed);
+    assert_eq!(fb.truncate_file()?, 0);
+    assert!(fb.can_rollback());
+
+    let mut more = Vec::new();
+    for i in 0..40u8 {
+        more.push(fb.alloc_from(BlockType::User2, &[100 + i])?);
+    }
+    fb.store()?;
+    drop(fb);
+
+    let mut fb = BasicFileBlocks::load(path, BLOCK_SIZE)?;
+    for (i, nr) in nrs[..10].iter().enumerate() {
+        assert_eq!(fb.get(*nr)?.data[0], i as u8);
+    }
+    for (i, nr) in more.iter().enumerate() {
+        assert_eq!(fb.get(*nr)?.data[0], 100 + i as u8);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_truncate_file_inactive() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    let mut nrs = Vec::new();
+    for i in 0..20u8 {
+        nrs.push(fb.alloc_from(BlockType::User1, &[i])?);
+    }
+    fb.store()?;
+    for nr in &nrs[10..] {
+        fb.free(*nr)?;
+    }
+    fb.store()?;
+    // the active state only uses blocks at the start, the inactive
+    // state still has its maps at the end.
+    fb.get_mut(nrs[0])?.set_dirty(true);
+    fb.store()?;
+    assert!(fb.can_rollback());
+
+    assert_eq!(fb.truncate_file()?, 0);
+    assert!(fb.can_rollback());
+
+    Ok(())
+}
+
+#[cfg(debug_assertions)]
+#[test]
+fn test_free_list_beyond_file_end() -> Result<(), Error> {
+    let mut alloc = Alloc::init(Cursor::new(Vec::new()), BLOCK_SIZE);
+    let nr = alloc.alloc_block(BlockType::User1, 1)?;
+    alloc.block_mut(nr, 1)?.set_dirty(true);
+    alloc.store()?;
+
+    // a block-nr mapped beyond the end of the file must not be handed
+    // out again when the file grows.
+    let pnr = PhysicalNr(20);
+    alloc.set_physical_nr_unchecked(nr, pnr)?;
+    let nr = alloc.alloc_block(BlockType::User1, 1)?;
+    alloc.block_mut(nr, 1)?.set_dirty(true);
+    alloc.store()?;
+    assert!(alloc.file_block_count()? < 20);
+
+    let mut nrs = Vec::new();
+    for _ in 0..20 {
+        let nr = alloc.alloc_block(BlockType::User2, 1)?;
+        alloc.block_mut(nr, 1)?.set_dirty(true);
+        nrs.push(nr);
+    }
+    alloc.store()?;
+    for nr in nrs {
+        assert_ne!(alloc.physical_nr(nr)?, pnr);
+    }
+    alloc.verify_no_double_mapping()?;
+
+    Ok(())
+}
+
+#[test]
+fn test_fragmentation_report() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    {
+        let mut w = fb.append_stream(BlockType::User1)?;
+        w.write_all(&[1u8; 3 * BLOCK_SIZE]).expect("write");
+    }
+    for _ in 0..3 {
+        {
+            let mut w = fb.append_stream(BlockType::User2)?;
+            w.write_all(&[2u8; BLOCK_SIZE]).expect("write");
+        }
+        {
+            let mut w = fb.append_stream(BlockType::User3)?;
+            w.write_all(&[3u8; BLOCK_SIZE]).expect("write");
+        }
+    }
+    assert_eq!(
+        fb.fragmentation_report(),
+        vec![
+            (BlockType::User1, 0),
+            (BlockType::User2, 0),
+            (BlockType::User3, 0)
+        ]
+    );
+
+    fb.store()?;
+    assert_eq!(
+        fb.fragmentation_report(),
+        vec![
+            (BlockType::User1, 0),
+            (BlockType::User2, 2),
+            (BlockType::User3, 2)
+        ]
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn test_mmap_storage() -> Result<(), Error> {
+    let path = Path::new("tmp/mmap_storage.bin");
+    let _ = std::fs::remove_file(path);
+    let mut nrs = Vec::new();
+    {
+        let mut fb = unsafe { BasicFileBlocks::load_mmap(path, BLOCK_SIZE)? };
+        for i in 0..10u8 {
+            nrs.push(fb.alloc_from(BlockType::User1, &[i])?);
+        }
+        fb.store()?;
+        // grows the file after the first map.
+        for i in 10..40u8 {
+            nrs.push(fb.alloc_from(BlockType::User1, &[i])?);
+        }
+        fb.store()?;
+        fb.retain(|_nr, _block| false);
+        for (i, nr) in nrs.iter().enumerate() {
+            assert_eq!(fb.get(*nr)?.data[0], i as u8);
+        }
+    }
+
+    let mut fb = unsafe { BasicFileBlocks::load_mmap(path, BLOCK_SIZE)? };
+    for (i, nr) in nrs.iter().enumerate() {
+        assert_eq!(fb.get(*nr)?.data[0], i as u8);
+    }
+    for nr in &nrs[5..] {
+        fb.free(*nr)?;
+    }
+    fb.store()?;
+    fb.get_mut(nrs[0])?.set_dirty(true);
+    fb.store()?;
+    fb.truncate_file()?;
+    fb.reload()?;
+    assert_eq!(fb.get(nrs[4])?.data[0], 4);
+
+    Ok(())
+}
+
+#[cfg(not(feature = "physical64"))]
+#[test]
+fn test_invalid_physical_zero() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    for _ in 0..30 {
+        fb.alloc(BlockType::User1)?.set_dirty(true);
+    }
+    fb.store()?;
+    let types_nr = fb.iter_types().nth(1).expect("types").block_nr();
+    let physical_pnr = fb.physical_nr(LogicalNr(2))?;
+    let mut data = fb.into_bytes()?;
+
+    // point the second type-map block at the header.
+    let pos = physical_pnr.as_usize() * BLOCK_SIZE + 8 + types_nr.as_usize() * 4;
+    data[pos..pos + 4].fill(0);
+
+    let r = BasicFileBlocks::load_in_memory(data, BLOCK_SIZE);
+    assert_eq!(
+        r.expect_err("zero").kind,
+        FBErrorKind::InvalidPhysicalZero(types_nr)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_chained_maps() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    let mut expect = Vec::new();
+    for i in 0..60u32 {
+        let ty = [BlockType::User1, BlockType::User2, BlockType::User3][i as usize % 3];
+        let nr = fb.alloc_from(ty, &i.to_ne_bytes())?;
+        expect.push((nr, ty, i));
+    }
+    fb.store()?;
+    for i in 60..150u32 {
+        let nr = fb.alloc_from(BlockType::User4, &i.to_ne_bytes())?;
+        expect.push((nr, BlockType::User4, i));
+    }
+    fb.store()?;
+    assert!(fb.iter_types().count() >= 4);
+    assert!(fb.iter_physical().count() >= 4);
+    let physical: Vec<_> = expect
+        .iter()
+        .map(|(nr, _, _)| fb.physical_nr(*nr))
+        .collect::<Result<_, _>>()?;
+
+    let mut fb = BasicFileBlocks::load_in_memory(fb.into_bytes()?, BLOCK_SIZE)?;
+    fb.verify()?;
+    assert!(fb.iter_types().count() >= 4);
+    for w in fb.iter_types().collect::<Vec<_>>().windows(2) {
+        assert_eq!(w[0].end_nr(), w[1].start_nr());
+    }
+    for ((nr, ty, i), pnr) in expect.iter().zip(physical) {
+        assert_eq!(fb.block_type(*nr)?, *ty);
+        assert_eq!(fb.physical_nr(*nr)?, pnr);
+        assert_eq!(fb.get(*nr)?.data[..4], i.to_ne_bytes());
+    }
+
+    let nr = fb.alloc_from(BlockType::User1, b"last")?;
+    fb.store()?;
+    let mut fb = BasicFileBlocks::load_in_memory(fb.into_bytes()?, BLOCK_SIZE)?;
+    assert_eq!(&fb.get(nr)?.data[..4], b"last");
+
+    Ok(())
+}
+
+#[test]
+fn test_reserve() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    fb.reserve(200)?;
+    let types = fb.iter_types().count();
+    let physical = fb.iter_physical().count();
+    assert!(types > 1);
+    assert!(fb.stats().free >= 200);
+
+    for i in 0..200u32 {
+        fb.alloc_from(BlockType::User1, &i.to_ne_bytes())?;
+    }
+    assert_eq!(fb.iter_types().count(), types);
+    assert_eq!(fb.iter_physical().count(), physical);
+
+    fb.reserve(100)?;
+    assert!(fb.iter_types().count() > types);
+    assert!(fb.stats().free >= 100);
+    fb.store()?;
+
+    assert_eq!(
+        fb.reserve(usize::MAX).expect_err("overflow").kind,
+        FBErrorKind::NoFreeBlocks
+    );
+
+    let fb = BasicFileBlocks::load_in_memory(fb.into_bytes()?, BLOCK_SIZE)?;
+    assert_eq!(fb.iter_type(BlockType::User1).count(), 200);
+
+    Ok(())
+}
+
+#[test]
+fn test_streams_iter() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    assert_eq!(fb.streams().iter().count(), 0);
+    {
+        let mut w = fb.append_stream(BlockType::User2)?;
+        w.write_all(&[1u8; BLOCK_SIZE + 5]).expect("write");
+    }
+    {
+        let mut w = fb.append_stream(BlockType::User1)?;
+        w.write_all(&[1u8; 17]).expect("write");
+    }
+    fb.store()?;
+
+    let fb = BasicFileBlocks::load_in_memory(fb.into_bytes()?, BLOCK_SIZE)?;
+    let streams: Vec<_> = fb.streams().iter().collect();
+    assert_eq!(streams, vec![(BlockType::User2, 5), (BlockType::User1, 17)]);
+
+    Ok(())
+}
+
+#[test]
+fn test_streams_chained() -> Result<(), Error> {
+    const SMALL: usize = 96;
+    let mut fb = BasicFileBlocks::in_memory(SMALL)?;
+    let capacity = fb.streams().capacity();
+    assert!(capacity < 16);
+
+    for i in 0..16u32 {
+        let block_type = BlockType::try_from(16 + i).expect("user-type");
+        let mut w = fb.append_stream(block_type)?;
+        w.write_all(&vec![1u8; i as usize + 1]).expect("write");
+    }
+    assert!(fb.streams().capacity() >= 16);
+    fb.store()?;
+
+    let mut fb = BasicFileBlocks::load_in_memory(fb.into_bytes()?, SMALL)?;
+    fb.verify()?;
+    assert_eq!(fb.streams().iter().count(), 16);
+    for i in 0..16u32 {
+        let block_type = BlockType::try_from(16 + i).expect("user-type");
+        assert_eq!(fb.streams().head_idx(block_type), i as usize + 1);
+    }
+
+    fb.clear_stream(BlockType::User1)?;
+    fb.store()?;
+    let fb = BasicFileBlocks::load_in_memory(fb.into_bytes()?, SMALL)?;
+    assert_eq!(fb.streams().iter().count(), 15);
+    assert_eq!(fb.streams().head_idx(BlockType::User16), 16);
+
+    Ok(())
+}
+
+#[test]
+fn test_streams_broken_link() -> Result<(), Error> {
+    let mut alloc = Alloc::init(Cursor::new(Vec::new()), BLOCK_SIZE);
+    {
+        let mut w = alloc.append_stream(BlockType::User1, 1)?;
+        w.write_all(&[1u8; 10]).expect("write");
+    }
+    let user_nr = alloc.alloc_block(BlockType::User2, 1)?;
+    alloc.block_mut(user_nr, 1)?.set_dirty(true);
+    alloc.store()?;
+
+    // the last entry of the streams block links to the next block.
+    let pnr = alloc.physical_nr(LogicalNr(3))?;
+    let link = pnr.as_usize() * BLOCK_SIZE + BLOCK_SIZE - 8;
+    let mut bytes = alloc.storage_bytes()?;
+    bytes[link..link + 4].copy_from_slice(&(BlockType::Streams as u32).to_ne_bytes());
+
+    // linked to itself.
+    bytes[link + 4..link + 8].copy_from_slice(&3u32.to_ne_bytes());
+    let r = Alloc::load(Cursor::new(bytes.clone()), BLOCK_SIZE);
+    assert_eq!(
+        r.expect_err("self-linked").kind,
+        FBErrorKind::InvalidBlockSequence(LogicalNr(3), LogicalNr(3))
+    );
+
+    // linked to a user block.
+    bytes[link + 4..link + 8].copy_from_slice(&user_nr.as_u32().to_ne_bytes());
+    let r = Alloc::load(Cursor::new(bytes), BLOCK_SIZE);
+    assert_eq!(
+        r.expect_err("user block").kind,
+        FBErrorKind::InvalidBlockType(user_nr, BlockType::User2)
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "bytemuck")]
+#[test]
+fn test_stream_records() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    {
+        let mut w = fb.append_stream(BlockType::User1)?;
+        for i in 0..30u32 {
+            w.write_all(bytemuck::bytes_of(&[i, i + 1, i + 2]))
+                .expect("write");
+        }
+    }
+    let records = fb
+        .read_stream(BlockType::User1)?
+        .records::<[u32; 3]>()
+        .collect::<Result<Vec<_>, _>>()
+        .expect("records");
+    assert_eq!(records.len(), 30);
+    assert_eq!(records[29], [29, 30, 31]);
+
+    {
+        let mut w = fb.append_stream(BlockType::User1)?;
+        w.write_all(&[1u8; 5]).expect("write");
+    }
+    let mut records = fb.read_stream(BlockType::User1)?.records::<[u32; 3]>();
+    assert_eq!(records.by_ref().take(30).filter(|v| v.is_ok()).count(), 30);
+    let err = records.next().expect("partial").expect_err("eof");
+    assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    assert!(records.next().is_none());
+
+    Ok(())
+}
+
+#[cfg(feature = "bytemuck")]
+#[test]
+fn test_write_record() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    {
+        let mut w = fb.append_stream(BlockType::User1)?;
+        for i in 0..30u32 {
+            w.write_record(&[i, i + 1, i + 2]).expect("write");
+        }
+    }
+    fb.store()?;
+
+    let mut fb = BasicFileBlocks::load_in_memory(fb.into_bytes()?, BLOCK_SIZE)?;
+    assert_eq!(fb.stream_len(BlockType::User1), 30 * 12);
+    let records = fb
+        .read_stream(BlockType::User1)?
+        .records::<[u32; 3]>()
+        .collect::<Result<Vec<_>, _>>()
+        .expect("records");
+    assert_eq!(records.len(), 30);
+    assert_eq!(records[10], [10, 11, 12]);
+
+    Ok(())
+}
+
+/// Timing of a store with 1000 dirty 4k blocks. The file storage writes
+/// each run of consecutive blocks with a single positioned write.
+/// Run with `cargo test test_store_timing -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn test_store_timing() -> Result<(), Error> {
+    const BLOCK_SIZE_4K: usize = 4096;
+
+    let path = Path::new("tmp/store_timing.bin");
+    let mut fb = BasicFileBlocks::create(path, BLOCK_SIZE_4K)?;
+    let mut nrs = Vec::new();
+    for i in 0..1000u32 {
+        nrs.push(fb.alloc_from(BlockType::User1, &i.to_le_bytes())?);
+    }
+    fb.store()?;
+
+    for _ in 0..5 {
+        for nr in &nrs {
+            fb.get_mut(*nr)?.set_dirty(true);
+        }
+        let start = std::time::Instant::now();
+        fb.store_no_sync()?;
+        println!("store 1000 blocks {:?}", start.elapsed());
+    }
+    fb.sync()?;
+
+    Ok(())
+}
@@ -0,0 +1,150 @@
+#![cfg(feature = "physical64")]
+
+use blockfile2::{Alloc, BasicFileBlocks, BlockStorage, BlockType, Error, LogicalNr, PhysicalNr};
+use std::collections::HashMap;
+use std::io;
+use std::mem::size_of;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+const BLOCK_SIZE: usize = 128;
+
+#[test]
+fn test_physical64() -> Result<(), Error> {
+    assert_eq!(size_of::<PhysicalNr>(), 8);
+
+    // simulated, the physical map stores the nr with its native bytes.
+    let pnr = PhysicalNr(u32::MAX as u64 + 5);
+    let bytes = pnr.0.to_ne_bytes();
+    assert_eq!(PhysicalNr(u64::from_ne_bytes(bytes)), pnr);
+    assert_eq!((pnr + 1).as_u64(), u32::MAX as u64 + 6);
+    assert!(pnr > u32::MAX);
+
+    let mut fb = BasicFileBlocks::create(Path::new("tmp/physical64.bin"), BLOCK_SIZE)?;
+    let block = fb.alloc(BlockType::User1)?;
+    block.data[0] = 17;
+    block.set_dirty(true);
+    let block_nr = block.block_nr();
+    fb.store()?;
+    drop(fb);
+
+    let mut fb = BasicFileBlocks::load(Path::new("tmp/physical64.bin"), BLOCK_SIZE)?;
+    assert_eq!(fb.get(block_nr)?.data[0], 17);
+    for p in fb.iter_physical() {
+        assert_eq!(p.len_physical(), (BLOCK_SIZE - 8) / 8);
+    }
+    // two physical blocks cover one types block.
+    assert_eq!(fb.iter_physical().count(), 2);
+
+    Ok(())
+}
+
+#[test]
+fn test_chained_maps_physical64() -> Result<(), Error> {
+    let mut fb = BasicFileBlocks::in_memory(BLOCK_SIZE)?;
+    let mut expect = Vec::new();
+    for i in 0..150u32 {
+        expect.push(fb.alloc_from(BlockType::User1, &i.to_ne_bytes())?);
+    }
+    fb.store()?;
+    assert!(fb.iter_physical().count() >= 2 * fb.iter_types().count());
+
+    let mut fb = BasicFileBlocks::load_in_memory(fb.into_bytes()?, BLOCK_SIZE)?;
+    fb.verify()?;
+    for (i, nr) in expect.iter().enumerate() {
+        assert_eq!(fb.block_type(*nr)?, BlockType::User1);
+        assert_eq!(fb.get(*nr)?.data[..4], (i as u32).to_ne_bytes());
+    }
+
+    Ok(())
+}
+
+/// Sparse in-memory storage. Blocks above u32::MAX are not counted in the
+/// length, otherwise the free-list would cover the whole hole below them.
+/// Reading a user block from there fails with PhysicalOutOfRange.
+#[derive(Debug, Default, Clone)]
+struct Sparse(Arc<Mutex<(HashMap<u64, u8>, u64)>>);
+
+impl BlockStorage for Sparse {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let data = self.0.lock().expect("lock");
+        for (i, v) in buf.iter_mut().enumerate() {
+            *v = data.0.get(&(offset + i as u64)).copied().unwrap_or(0);
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        let mut data = self.0.lock().expect("lock");
+        for (i, v) in buf.iter().enumerate() {
+            data.0.insert(offset + i as u64, *v);
+        }
+        if offset < HIGH * BLOCK_SIZE as u64 {
+            data.1 = data.1.max(offset + buf.len() as u64);
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn len(&mut self) -> io::Result<u64> {
+        Ok(self.0.lock().expect("lock").1)
+    }
+}
+
+const HIGH: u64 = u32::MAX as u64 + 1;
+
+impl Sparse {
+    fn copy_block(&self, from: PhysicalNr, to: PhysicalNr) {
+        let mut buf = [0u8; BLOCK_SIZE];
+        let mut s = self.clone();
+        s.read_at(from.as_u64() * BLOCK_SIZE as u64, &mut buf)
+            .expect("read");
+        s.write_at(to.as_u64() * BLOCK_SIZE as u64, &buf)
+            .expect("write");
+    }
+}
+
+#[cfg(debug_assertions)]
+#[test]
+fn test_physical64_store_load() -> Result<(), Error> {
+    let storage = Sparse::default();
+    let mut alloc = Alloc::init(storage.clone(), BLOCK_SIZE);
+    let block_nr = alloc.alloc_block(BlockType::User1, 1)?;
+    let block = alloc.block_mut(block_nr, 1)?;
+    block.data[0] = 17;
+    block.set_dirty(true);
+    alloc.store()?;
+
+    // move the user block and the root of the type-map above u32::MAX.
+    let user_pnr = PhysicalNr(HIGH + 5);
+    let types_pnr = PhysicalNr(HIGH + 6);
+    storage.copy_block(alloc.physical_nr(block_nr)?, user_pnr);
+    storage.copy_block(alloc.physical_nr(LogicalNr(1))?, types_pnr);
+    alloc.set_physical_nr_unchecked(block_nr, user_pnr)?;
+    alloc.set_physical_nr_unchecked(LogicalNr(1), types_pnr)?;
+    alloc.store()?;
+    let header = alloc.header();
+    assert!(header.low_types() == types_pnr || header.high_types() == types_pnr);
+
+    let mut alloc = Alloc::load(storage.clone(), BLOCK_SIZE)?;
+    alloc.verify()?;
+    assert_eq!(alloc.physical_nr(block_nr)?, user_pnr);
+    assert_eq!(alloc.physical_nr(LogicalNr(1))?, types_pnr);
+    // loaded from the high types root.
+    assert_eq!(alloc.block_type(block_nr)?, BlockType::User1);
+
+    // stores keep working with the big block-nrs.
+    let block_nr2 = alloc.alloc_block(BlockType::User1, 1)?;
+    alloc.block_mut(block_nr2, 1)?.set_dirty(true);
+    alloc.store()?;
+    alloc.verify()?;
+
+    let alloc = Alloc::load(storage, BLOCK_SIZE)?;
+    assert_eq!(alloc.physical_nr(block_nr)?, user_pnr);
+    assert_eq!(alloc.block_type(block_nr2)?, BlockType::User1);
+
+    Ok(())
+}